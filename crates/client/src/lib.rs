@@ -0,0 +1,367 @@
+//! Async client for Ample Geyser proxies and relaying gateways. Connects to a proxy the way the gateway does and hands
+//! out the notifications as typed [`futures::Stream`]s, for services that want the data without loading a Geyser plugin. Losing and
+//! regaining the connection and notifications a slow consumer missed are items of the streams, so every consumer sees
+//! where its stream has gaps.
+
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use transport::client::{ClientConnection, TransportClient};
+use transport::{ReplicaChannels, ReplicaReceivers, ReplicaSender, Subscription, TransportOpts};
+
+pub use solana_pubkey::Pubkey;
+pub use transport::error::TransportError;
+pub use transport::filters::{AccountFilter, TransactionFilter};
+pub use transport::{
+    UniformAccountInfo, UniformBlockInfo, UniformEntryInfo, UniformLifecycleInfo, UniformRollbackInfo,
+    UniformSlotInfo, UniformStartupInfo, UniformTransactionInfo,
+};
+
+const DEFAULT_MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Connection events buffered per stream, a stream that falls further behind misses the oldest
+const CONNECTION_EVENT_BUFFER: usize = 16;
+
+/// An item of the client's streams
+#[derive(Debug, Clone)]
+pub enum Update<T> {
+    Notification(T),
+    /// The consumer of the stream fell behind and this many notifications were dropped
+    Lagged(u64),
+    /// The connection to the proxy was lost, notifications are missed until [`Update::Reconnected`]
+    Disconnected(String),
+    Reconnected,
+}
+
+#[derive(Debug, Clone)]
+enum ConnectionEvent {
+    Disconnected(String),
+    Reconnected,
+}
+
+pub struct GeyserClientBuilder {
+    addr: SocketAddr,
+    server_name: String,
+    cert_path: PathBuf,
+    account_filter: Option<AccountFilter>,
+    transaction_filter: Option<TransactionFilter>,
+    entries: bool,
+    startup: bool,
+    rollbacks: bool,
+    min_reconnect_backoff: Duration,
+    max_reconnect_backoff: Duration,
+}
+
+impl GeyserClientBuilder {
+    /// Certificate the proxy presents, `certs/cert.pem` by default
+    pub fn cert_path(mut self, cert_path: impl Into<PathBuf>) -> Self {
+        self.cert_path = cert_path.into();
+        self
+    }
+
+    /// Subscribes to account updates, the account stream only yields the ones matching the filter
+    pub fn accounts(mut self, filter: AccountFilter) -> Self {
+        self.account_filter = Some(filter);
+        self
+    }
+
+    /// Subscribes to transactions, the transaction stream only yields the ones matching the filter
+    pub fn transactions(mut self, filter: TransactionFilter) -> Self {
+        self.transaction_filter = Some(filter);
+        self
+    }
+
+    pub fn entries(mut self) -> Self {
+        self.entries = true;
+        self
+    }
+
+    /// Subscribes to the account updates the validator sends while it starts up
+    pub fn startup(mut self) -> Self {
+        self.startup = true;
+        self
+    }
+
+    /// Subscribes to the rollbacks a relaying gateway detects, a proxy doesn't send any
+    pub fn rollbacks(mut self) -> Self {
+        self.rollbacks = true;
+        self
+    }
+
+    /// Delay before reconnecting after the connection was lost, doubled on every failed attempt up to `max`
+    pub fn reconnect_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_reconnect_backoff = min;
+        self.max_reconnect_backoff = max.max(min);
+        self
+    }
+
+    /// Fails if the first connection can't be established, later connection losses are reconnected
+    pub async fn connect(self) -> Result<GeyserClient, TransportError> {
+        let opts = TransportOpts {
+            cert_path: self.cert_path,
+            key_path: None,
+            fqdn: self.server_name,
+        };
+        let subscription = Subscription {
+            accounts: self.account_filter.is_some(),
+            transactions: self.transaction_filter.is_some(),
+            entries: self.entries,
+            startup: self.startup,
+            rollbacks: self.rollbacks,
+        };
+        let channels = ReplicaChannels::with_defaults();
+        let (receivers, connection) =
+            TransportClient::connect(self.addr, opts.clone(), channels.clone(), subscription, None).await?;
+        tracing::info!(addr = %self.addr, ?subscription, "connected to proxy");
+
+        let (events, connection_events) = broadcast::channel(CONNECTION_EVENT_BUFFER);
+        let (shutdown, stop) = watch::channel(false);
+        let supervisor = Supervisor {
+            addr: self.addr,
+            opts,
+            channels: channels.clone(),
+            subscription,
+            min_backoff: self.min_reconnect_backoff,
+            max_backoff: self.max_reconnect_backoff,
+        };
+        let supervisor = tokio::spawn(supervisor.run(connection, events, stop));
+
+        Ok(GeyserClient {
+            channels: Channels::new(&channels, receivers),
+            account_filter: Arc::new(self.account_filter.unwrap_or_default()),
+            transaction_filter: Arc::new(self.transaction_filter.unwrap_or_default()),
+            connection_events,
+            shutdown,
+            supervisor,
+        })
+    }
+}
+
+/// Every call of a stream method returns a stream of its own, each receiving every notification. Dropping the client
+/// closes the connection and ends its streams.
+pub struct GeyserClient {
+    channels: Channels,
+    account_filter: Arc<AccountFilter>,
+    transaction_filter: Arc<TransactionFilter>,
+    connection_events: Receiver<ConnectionEvent>,
+    shutdown: watch::Sender<bool>,
+    supervisor: JoinHandle<()>,
+}
+
+impl GeyserClient {
+    /// `server_name` has to match the proxy's certificate
+    pub fn builder(addr: SocketAddr, server_name: impl Into<String>) -> GeyserClientBuilder {
+        GeyserClientBuilder {
+            addr,
+            server_name: server_name.into(),
+            cert_path: PathBuf::from("certs/cert.pem"),
+            account_filter: None,
+            transaction_filter: None,
+            entries: false,
+            startup: false,
+            rollbacks: false,
+            min_reconnect_backoff: DEFAULT_MIN_RECONNECT_BACKOFF,
+            max_reconnect_backoff: DEFAULT_MAX_RECONNECT_BACKOFF,
+        }
+    }
+
+    /// Only yields connection events unless the builder subscribed to accounts
+    pub fn accounts(&self) -> BoxStream<'static, Update<UniformAccountInfo>> {
+        let filter = self.account_filter.clone();
+        self.updates(self.channels.account.subscribe(), move |account| filter.matches(account))
+    }
+
+    /// Only yields connection events unless the builder subscribed to transactions
+    pub fn transactions(&self) -> BoxStream<'static, Update<UniformTransactionInfo>> {
+        let filter = self.transaction_filter.clone();
+        self.updates(self.channels.transaction.subscribe(), move |txn| filter.matches(txn))
+    }
+
+    /// Only yields connection events unless the builder subscribed to entries
+    pub fn entries(&self) -> BoxStream<'static, Update<UniformEntryInfo>> {
+        self.updates(self.channels.entry.subscribe(), |_| true)
+    }
+
+    pub fn blocks(&self) -> BoxStream<'static, Update<UniformBlockInfo>> {
+        self.updates(self.channels.block.subscribe(), |_| true)
+    }
+
+    pub fn slots(&self) -> BoxStream<'static, Update<UniformSlotInfo>> {
+        self.updates(self.channels.slot.subscribe(), |_| true)
+    }
+
+    /// Only yields connection events unless the builder subscribed to startup updates. The account filter applies to
+    /// startup accounts as well
+    pub fn startup(&self) -> BoxStream<'static, Update<UniformStartupInfo>> {
+        let filter = self.account_filter.clone();
+        self.updates(self.channels.startup.subscribe(), move |info| match info {
+            UniformStartupInfo::Account(account) => filter.matches(account),
+            UniformStartupInfo::EndOfStartup => true,
+        })
+    }
+
+    pub fn lifecycle(&self) -> BoxStream<'static, Update<UniformLifecycleInfo>> {
+        self.updates(self.channels.lifecycle.subscribe(), |_| true)
+    }
+
+    /// Only yields connection events unless the builder subscribed to rollbacks
+    pub fn rollbacks(&self) -> BoxStream<'static, Update<UniformRollbackInfo>> {
+        self.updates(self.channels.rollback.subscribe(), |_| true)
+    }
+
+    /// Closes the connection, the streams end once they yielded what was received before
+    pub async fn close(self) {
+        let _ = self.shutdown.send(true);
+        let _ = self.supervisor.await;
+    }
+
+    fn updates<T, F>(&self, notifications: Receiver<T>, filter: F) -> BoxStream<'static, Update<T>>
+    where
+        T: Clone + Send + 'static,
+        F: Fn(&T) -> bool + Send + 'static,
+    {
+        let events = self.connection_events.resubscribe();
+        futures::stream::unfold((notifications, events, filter), |(mut notifications, mut events, filter)| async move {
+            loop {
+                let update = tokio::select! {
+                    // what was received before a disconnect is yielded before it
+                    biased;
+                    notification = notifications.recv() => match notification {
+                        Ok(notification) if filter(&notification) => Update::Notification(notification),
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(skipped)) => Update::Lagged(skipped),
+                        Err(RecvError::Closed) => return None,
+                    },
+                    event = events.recv() => match event {
+                        Ok(ConnectionEvent::Disconnected(reason)) => Update::Disconnected(reason),
+                        Ok(ConnectionEvent::Reconnected) => Update::Reconnected,
+                        Err(RecvError::Lagged(_)) => continue,
+                        // the client was closed or dropped
+                        Err(RecvError::Closed) => return None,
+                    },
+                };
+                return Some((update, (notifications, events, filter)));
+            }
+        })
+        .boxed()
+    }
+}
+
+/// A notification channel the client's streams subscribe to
+struct Channel<T> {
+    sender: Box<dyn ReplicaSender<T>>,
+    /// Subscribed while connecting and handed to the first stream, so it doesn't miss what arrived before it was created
+    first: Mutex<Option<Receiver<T>>>,
+}
+
+impl<T: Clone> Channel<T> {
+    fn new(sender: impl ReplicaSender<T>, first: Receiver<T>) -> Self {
+        Self {
+            sender: Box::new(sender),
+            first: Mutex::new(Some(first)),
+        }
+    }
+
+    fn subscribe(&self) -> Receiver<T> {
+        let first = self.first.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        first.unwrap_or_else(|| self.sender.subscribe())
+    }
+}
+
+struct Channels {
+    account: Channel<UniformAccountInfo>,
+    transaction: Channel<UniformTransactionInfo>,
+    entry: Channel<UniformEntryInfo>,
+    block: Channel<UniformBlockInfo>,
+    slot: Channel<UniformSlotInfo>,
+    startup: Channel<UniformStartupInfo>,
+    lifecycle: Channel<UniformLifecycleInfo>,
+    rollback: Channel<UniformRollbackInfo>,
+}
+
+impl Channels {
+    fn new(channels: &ReplicaChannels, receivers: ReplicaReceivers) -> Self {
+        Self {
+            account: Channel::new(channels.account.clone(), receivers.account),
+            transaction: Channel::new(channels.transaction.clone(), receivers.transaction),
+            entry: Channel::new(channels.entry.clone(), receivers.entry),
+            block: Channel::new(channels.block.clone(), receivers.block),
+            slot: Channel::new(channels.slot.clone(), receivers.slot),
+            startup: Channel::new(channels.startup.clone(), receivers.startup),
+            lifecycle: Channel::new(channels.lifecycle.clone(), receivers.lifecycle),
+            rollback: Channel::new(channels.rollback.clone(), receivers.rollback),
+        }
+    }
+}
+
+/// Keeps the client connected, reconnecting into the same channels so the streams continue
+struct Supervisor {
+    addr: SocketAddr,
+    opts: TransportOpts,
+    channels: ReplicaChannels,
+    subscription: Subscription,
+    min_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Supervisor {
+    /// Runs until the client is closed or dropped
+    async fn run(
+        self,
+        connection: ClientConnection,
+        events: Sender<ConnectionEvent>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let mut connection = Some(connection);
+        let mut backoff = self.min_backoff;
+        loop {
+            if let Some(current) = connection.take() {
+                let lost = tokio::select! {
+                    error = current.closed() => Some(error),
+                    _ = shutdown.wait_for(|stop| *stop) => None,
+                };
+                let Some(error) = lost else {
+                    current.close("client closed").await;
+                    return;
+                };
+                tracing::warn!(addr = %self.addr, "connection to proxy lost, reconnecting: {}", error);
+                let _ = events.send(ConnectionEvent::Disconnected(error.to_string()));
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = shutdown.wait_for(|stop| *stop) => return,
+            }
+            let connect = TransportClient::connect(
+                self.addr,
+                self.opts.clone(),
+                self.channels.clone(),
+                self.subscription,
+                None,
+            );
+            tokio::select! {
+                result = connect => match result {
+                    Ok((_, reconnected)) => {
+                        tracing::info!(addr = %self.addr, "reconnected to proxy");
+                        backoff = self.min_backoff;
+                        connection = Some(reconnected);
+                        let _ = events.send(ConnectionEvent::Reconnected);
+                    }
+                    Err(e) => {
+                        tracing::warn!(addr = %self.addr, ?backoff, "failed to reconnect to proxy: {}", e);
+                        backoff = (backoff * 2).min(self.max_backoff);
+                    }
+                },
+                _ = shutdown.wait_for(|stop| *stop) => return,
+            }
+        }
+    }
+}
@@ -0,0 +1,16 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    compile_protos().expect("failed to compile the gRPC protos");
+}
+
+#[cfg(feature = "grpc")]
+fn compile_protos() -> Result<(), Box<dyn std::error::Error>> {
+    // a vendored protoc, so building the gRPC API doesn't need protobuf installed
+    // SAFETY: build scripts are single threaded
+    unsafe { std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?) };
+    println!("cargo:rerun-if-changed=proto/gateway.proto");
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/gateway.proto"], &["proto"])?;
+    Ok(())
+}
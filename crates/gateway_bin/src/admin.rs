@@ -0,0 +1,131 @@
+//! Local admin interface to list, load, unload and reload plugins while the gateway keeps running.
+//!
+//! Requests are newline delimited JSON objects on a Unix socket, e.g. `{"method": "reload_plugin", "name": "my_plugin",
+//! "config": "/path/to/config.json"}`, each answered with a single line `{"ok": ...}` or `{"error": "..."}`. Requests
+//! are forwarded to the replicator thread, which owns the plugin workers. Plugins are loaded and unloaded on background
+//! threads, the replicator only adds and removes their workers between notifications.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AdminCommand {
+    /// Names of the loaded plugins
+    ListPlugins,
+    /// Loads the plugin of a geyser plugin config, optionally in a sandboxed child process
+    LoadPlugin {
+        config: String,
+        #[serde(default)]
+        sandboxed: bool,
+    },
+    /// Calls `on_unload` on the plugin and drops it, after it got the notifications already queued for it
+    UnloadPlugin { name: String },
+    /// Unloads the plugin, then loads it again from the given config in the same mode
+    ReloadPlugin { name: String, config: String },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminResponse {
+    Ok(serde_json::Value),
+    Error(String),
+}
+
+impl AdminResponse {
+    pub fn from_result<T: Serialize>(result: anyhow::Result<T>) -> Self {
+        match result.and_then(|value| Ok(serde_json::to_value(value)?)) {
+            Ok(value) => AdminResponse::Ok(value),
+            Err(e) => AdminResponse::Error(format!("{:#}", e)),
+        }
+    }
+}
+
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub reply: oneshot::Sender<AdminResponse>,
+}
+
+/// Listens on the admin socket, returns the receiving end of the requests for the replicator
+pub fn serve(socket_path: &Path) -> anyhow::Result<mpsc::UnboundedReceiver<AdminRequest>> {
+    // a socket left behind by a previous run would make binding fail
+    let _ = std::fs::remove_file(socket_path);
+    let listener = bind_private(socket_path)?;
+    listener.set_nonblocking(true)?;
+    let listener = UnixListener::from_std(listener)?;
+    tracing::info!(socket = %socket_path.display(), "admin interface listening");
+
+    let (requests, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let requests = requests.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, requests).await {
+                            tracing::warn!("admin connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => tracing::error!("failed to accept admin connection: {}", e),
+            }
+        }
+    });
+    Ok(received)
+}
+
+/// Only the gateway's user may load code into it. The socket is bound in a directory nobody else can enter and moved
+/// into place once restricted, so it's never reachable by others, not even between binding and restricting it.
+/// Sandboxed plugin sockets are bound the same way
+pub fn bind_private(socket_path: &Path) -> anyhow::Result<std::os::unix::net::UnixListener> {
+    let file_name = socket_path.file_name().context("admin socket path has no file name")?;
+    let parent = socket_path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let private_dir = parent.join(format!(".{}.{}", file_name.to_string_lossy(), std::process::id()));
+    let _ = std::fs::remove_dir_all(&private_dir);
+    std::fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+
+    let bound = (|| -> anyhow::Result<std::os::unix::net::UnixListener> {
+        let private_path = private_dir.join(file_name);
+        let listener = std::os::unix::net::UnixListener::bind(&private_path)?;
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&private_path, socket_path)?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_dir_all(&private_dir);
+    bound
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    requests: mpsc::UnboundedSender<AdminRequest>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<AdminCommand>(&line) {
+            Ok(command) => {
+                tracing::info!(?command, "admin request");
+                let (reply, response) = oneshot::channel();
+                match requests.send(AdminRequest { command, reply }) {
+                    Ok(()) => response.await.unwrap_or_else(|_| AdminResponse::Error("replicator stopped".to_string())),
+                    Err(_) => AdminResponse::Error("replicator stopped".to_string()),
+                }
+            }
+            Err(e) => AdminResponse::Error(format!("invalid request: {}", e)),
+        };
+
+        let mut serialized = serde_json::to_vec(&response)?;
+        serialized.push(b'\n');
+        writer.write_all(&serialized).await?;
+    }
+    Ok(())
+}
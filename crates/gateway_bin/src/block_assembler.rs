@@ -0,0 +1,313 @@
+//! Assembles complete blocks for the gRPC and WebSocket APIs. Transactions, entries and block metadata of a slot arrive
+//! on their own streams, they are collected by slot and emitted together once the counts announced by the block metadata
+//! are reached. A block still missing something, or whose validator doesn't announce counts, is emitted once its slot is
+//! rooted or falls out of the tracked slots, and dropped if it dies.
+
+use crate::compat::SharedTransaction;
+use crate::verification::SlotVerification;
+use agave_geyser_plugin_interface::geyser_plugin_interface::SlotStatus;
+use solana_clock::Slot;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use transport::{UniformBlockInfo, UniformEntryInfo, UniformSlotInfo};
+
+/// How many slots behind the newest one a block is kept, older blocks are emitted as they are
+const TRACKED_SLOTS: Slot = 1024;
+/// Assembled blocks buffered per consumer before it lags
+pub const ASSEMBLED_BLOCK_BUFFER: usize = 64;
+
+/// Everything the validator notified for a slot
+pub struct CompleteBlock {
+    pub slot: Slot,
+    /// Block metadata including the rewards, `None` if it never arrived
+    pub block: Option<Arc<UniformBlockInfo>>,
+    /// In index order if `index_verified`, in arrival order otherwise
+    pub transactions: Vec<Arc<SharedTransaction>>,
+    /// In index order
+    pub entries: Vec<Arc<UniformEntryInfo>>,
+    /// Set once as many transactions as the block metadata announced were collected. Without an announced count, set
+    /// if the slot was rooted before the block was emitted
+    pub transactions_complete: bool,
+    /// Set once as many entries as the block metadata announced were collected. Without an announced count, set if
+    /// the slot was rooted before the block was emitted
+    pub entries_complete: bool,
+    /// Whether every transaction carried its index in the block, so their order is the block's. V0_0_1 validators
+    /// don't report it
+    pub index_verified: bool,
+    /// Whether the transactions and entries are consistent with the counts of the block metadata and the entries,
+    /// a block that needs a backfill should be completed from another source
+    pub verification: SlotVerification,
+}
+
+impl CompleteBlock {
+    pub fn is_complete(&self) -> bool {
+        self.transactions_complete && self.entries_complete
+    }
+}
+
+/// Why a block is emitted
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    /// The counts announced by the block metadata were reached
+    Counted,
+    /// The slot was rooted, the validator notified everything it had for it
+    Rooted,
+    /// The slot fell out of the tracked slots
+    Pruned,
+}
+
+#[derive(Default)]
+struct PartialBlock {
+    block: Option<Arc<UniformBlockInfo>>,
+    transactions: Vec<Arc<SharedTransaction>>,
+    entries: Vec<Arc<UniformEntryInfo>>,
+    /// Set once emitted or dropped, late notifications of the slot are ignored
+    done: bool,
+}
+
+impl PartialBlock {
+    /// Only true if the block metadata announced both counts, otherwise completeness is unknown until the slot is
+    /// rooted or pruned
+    fn is_complete(&self) -> bool {
+        self.block.as_ref().is_some_and(|block| {
+            block.executed_transaction_count.is_some_and(|count| self.transactions.len() as u64 >= count)
+                && block.entry_count.is_some_and(|count| self.entries.len() as u64 >= count)
+        })
+    }
+
+    fn emit(&mut self, slot: Slot, reason: Emit) -> CompleteBlock {
+        self.done = true;
+        let mut transactions = std::mem::take(&mut self.transactions);
+        // a transaction without an index can't be placed, all of them keep their arrival order then
+        let index_verified = transactions.iter().all(|txn| txn.replica.index.is_some());
+        if index_verified {
+            transactions.sort_by_key(|txn| txn.replica.index);
+        }
+        let mut entries = std::mem::take(&mut self.entries);
+        entries.sort_by_key(|entry| entry.index);
+
+        let verification = SlotVerification::verify(self.block.as_deref(), &transactions, &entries);
+        let complete = |announced: Option<u64>, received: usize| match announced {
+            Some(count) => received as u64 >= count,
+            None => reason == Emit::Rooted,
+        };
+        let (transactions_complete, entries_complete) = match &self.block {
+            Some(block) => (
+                complete(block.executed_transaction_count, transactions.len()),
+                complete(block.entry_count, entries.len()),
+            ),
+            None => (false, false),
+        };
+        CompleteBlock {
+            slot,
+            block: self.block.take(),
+            transactions,
+            entries,
+            transactions_complete,
+            entries_complete,
+            index_verified,
+            verification,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BlockAssembler {
+    blocks: BTreeMap<Slot, PartialBlock>,
+}
+
+impl BlockAssembler {
+    /// Returns the block if the transaction completed it
+    pub fn transaction(&mut self, txn: Arc<SharedTransaction>) -> Option<CompleteBlock> {
+        let slot = txn.replica.slot;
+        let partial = self.blocks.entry(slot).or_default();
+        if partial.done {
+            return None;
+        }
+        partial.transactions.push(txn);
+        partial.is_complete().then(|| partial.emit(slot, Emit::Counted))
+    }
+
+    /// Returns the block if the entry completed it
+    pub fn entry(&mut self, entry: Arc<UniformEntryInfo>) -> Option<CompleteBlock> {
+        let slot = entry.slot;
+        let partial = self.blocks.entry(slot).or_default();
+        if partial.done {
+            return None;
+        }
+        partial.entries.push(entry);
+        partial.is_complete().then(|| partial.emit(slot, Emit::Counted))
+    }
+
+    /// Returns the block if its metadata completed it, along with blocks too old to wait for any longer
+    pub fn block(&mut self, block: Arc<UniformBlockInfo>) -> Vec<CompleteBlock> {
+        let slot = block.slot;
+        let mut emitted = self.prune(slot);
+
+        let partial = self.blocks.entry(slot).or_default();
+        if !partial.done {
+            partial.block = Some(block);
+            if partial.is_complete() {
+                emitted.push(partial.emit(slot, Emit::Counted));
+            }
+        }
+        emitted
+    }
+
+    /// Returns the block of a rooted slot that wasn't emitted yet, drops the block of a dead one
+    pub fn slot_status(&mut self, info: &UniformSlotInfo) -> Option<CompleteBlock> {
+        let partial = self.blocks.get_mut(&info.slot).filter(|partial| !partial.done)?;
+        match info.status {
+            SlotStatus::Rooted => Some(partial.emit(info.slot, Emit::Rooted)),
+            SlotStatus::Dead(_) => {
+                *partial = PartialBlock {
+                    done: true,
+                    ..Default::default()
+                };
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Forgets slots far behind the newest block, emitting what was collected for them unless they were done already
+    fn prune(&mut self, newest: Slot) -> Vec<CompleteBlock> {
+        let cutoff = newest.saturating_sub(TRACKED_SLOTS);
+        let mut emitted = Vec::new();
+        while let Some(mut entry) = self.blocks.first_entry() {
+            if *entry.key() >= cutoff {
+                break;
+            }
+            let slot = *entry.key();
+            if !entry.get().done {
+                emitted.push(entry.get_mut().emit(slot, Emit::Pruned));
+            }
+            entry.remove();
+        }
+        emitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verification::VerificationIssue;
+    use solana_transaction_status::RewardsAndNumPartitions;
+    use transport::UniformTransactionInfo;
+
+    fn block(slot: Slot, transactions: Option<u64>, entries: Option<u64>) -> Arc<UniformBlockInfo> {
+        Arc::new(UniformBlockInfo {
+            parent_slot: Some(slot - 1),
+            parent_blockhash: None,
+            slot,
+            blockhash: String::new(),
+            rewards: RewardsAndNumPartitions { rewards: Vec::new(), num_partitions: None },
+            block_time: None,
+            block_height: None,
+            executed_transaction_count: transactions,
+            entry_count: entries,
+        })
+    }
+
+    fn transaction(slot: Slot, index: Option<usize>) -> Arc<SharedTransaction> {
+        let replica = UniformTransactionInfo {
+            slot,
+            signature: Default::default(),
+            message_hash: Default::default(),
+            is_vote: false,
+            transaction: Default::default(),
+            transaction_status_meta: Default::default(),
+            index,
+            writable: None,
+        };
+        Arc::new(SharedTransaction::new(replica, None))
+    }
+
+    fn entry(slot: Slot, index: usize, starting_transaction_index: usize, transactions: u64) -> Arc<UniformEntryInfo> {
+        Arc::new(UniformEntryInfo {
+            slot,
+            index,
+            num_hashes: 1,
+            hash: Default::default(),
+            executed_transaction_count: transactions,
+            starting_transaction_index: Some(starting_transaction_index),
+        })
+    }
+
+    fn indexes(assembled: &CompleteBlock) -> Vec<Option<usize>> {
+        assembled.transactions.iter().map(|txn| txn.replica.index).collect()
+    }
+
+    #[test]
+    fn emits_the_block_once_the_announced_counts_are_reached() {
+        let mut assembler = BlockAssembler::default();
+        assert!(assembler.transaction(transaction(10, Some(1))).is_none());
+        assert!(assembler.block(block(10, Some(2), Some(1))).is_empty());
+        assert!(assembler.transaction(transaction(10, Some(0))).is_none());
+
+        let assembled = assembler.entry(entry(10, 0, 0, 2)).expect("every announced transaction and entry arrived");
+        assert_eq!(assembled.slot, 10);
+        assert_eq!(indexes(&assembled), [Some(0), Some(1)]);
+        assert!(assembled.is_complete());
+        assert!(assembled.index_verified);
+        assert!(!assembled.verification.needs_backfill());
+
+        // emitted once only
+        assert!(assembler.transaction(transaction(10, Some(2))).is_none());
+        assert!(assembler.slot_status(&UniformSlotInfo::from_replica(10, Some(9), SlotStatus::Rooted)).is_none());
+    }
+
+    #[test]
+    fn emits_an_incomplete_block_once_rooted() {
+        let mut assembler = BlockAssembler::default();
+        assembler.block(block(10, Some(2), Some(1)));
+        assembler.transaction(transaction(10, Some(0)));
+
+        let assembled = assembler.slot_status(&UniformSlotInfo::from_replica(10, Some(9), SlotStatus::Rooted))
+            .expect("a rooted slot is emitted");
+        assert!(!assembled.transactions_complete);
+        assert!(!assembled.entries_complete);
+        assert_eq!(assembled.verification.issues, [
+            VerificationIssue::MissingTransactions { expected: 2, received: 1 },
+            VerificationIssue::MissingEntries { expected: 1, received: 0 },
+        ]);
+    }
+
+    #[test]
+    fn rooting_completes_blocks_without_announced_counts() {
+        let mut assembler = BlockAssembler::default();
+        assert!(assembler.block(block(10, None, None)).is_empty());
+        assert!(assembler.transaction(transaction(10, None)).is_none());
+        assert!(assembler.transaction(transaction(10, None)).is_none());
+
+        let assembled = assembler.slot_status(&UniformSlotInfo::from_replica(10, Some(9), SlotStatus::Rooted))
+            .expect("a rooted slot is emitted");
+        assert!(assembled.is_complete());
+        // transactions without an index keep their arrival order
+        assert!(!assembled.index_verified);
+        assert_eq!(assembled.transactions.len(), 2);
+    }
+
+    #[test]
+    fn drops_the_block_of_a_dead_slot() {
+        let mut assembler = BlockAssembler::default();
+        assembler.transaction(transaction(10, Some(0)));
+
+        assert!(assembler.slot_status(&UniformSlotInfo::from_replica(10, Some(9), SlotStatus::Dead(String::new()))).is_none());
+        assert!(assembler.block(block(10, Some(1), Some(0))).is_empty());
+        assert!(assembler.slot_status(&UniformSlotInfo::from_replica(10, Some(9), SlotStatus::Rooted)).is_none());
+    }
+
+    #[test]
+    fn emits_blocks_that_fall_out_of_the_tracked_slots() {
+        let mut assembler = BlockAssembler::default();
+        assembler.transaction(transaction(10, Some(0)));
+
+        let emitted = assembler.block(block(11 + TRACKED_SLOTS, Some(1), Some(1)));
+        assert_eq!(emitted.len(), 1);
+        assert_eq!(emitted[0].slot, 10);
+        assert!(emitted[0].block.is_none());
+        assert!(!emitted[0].is_complete());
+        assert_eq!(emitted[0].verification.issues, [VerificationIssue::MissingBlockMetadata]);
+    }
+}
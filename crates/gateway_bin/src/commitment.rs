@@ -0,0 +1,287 @@
+//! Per-plugin commitment. Plugins with a `confirmed` or `finalized` commitment get the accounts, transactions, entries
+//! and block metadata of a slot only once the slot reached their commitment, so they never see data of slots that die
+//! or are skipped by fork choice and don't have to handle rollbacks themselves.
+
+use crate::plugin_settings::Commitment;
+use crate::plugin_worker::PluginEvent;
+use agave_geyser_plugin_interface::geyser_plugin_interface::SlotStatus;
+use solana_clock::Slot;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use transport::UniformSlotInfo;
+use transport::metrics::{StreamMetricHelper, TransportMetrics};
+
+/// How many slots behind the root the state of a slot is kept, to handle its late notifications
+const TRACKED_SLOTS: Slot = 512;
+
+#[derive(Default)]
+struct SlotState {
+    parent: Option<Slot>,
+    reached: Commitment,
+    dead: bool,
+    /// Not delivered to any gated plugin yet
+    unconfirmed: Vec<PluginEvent>,
+    /// Delivered to confirmed plugins, still waiting for the root
+    confirmed: Vec<PluginEvent>,
+}
+
+/// Events to deliver to the plugins with exactly this commitment
+pub struct Release {
+    pub commitment: Commitment,
+    pub events: Vec<PluginEvent>,
+}
+
+pub struct CommitmentGate {
+    slots: BTreeMap<Slot, SlotState>,
+    root: Option<Slot>,
+    metrics: Option<Arc<TransportMetrics>>,
+}
+
+impl CommitmentGate {
+    pub fn new(metrics: Option<Arc<TransportMetrics>>) -> Self {
+        Self {
+            slots: BTreeMap::new(),
+            root: None,
+            metrics,
+        }
+    }
+
+    /// Buffers slot data for the gated plugins. Returns the commitment the slot already reached, plugins up to it get
+    /// the event right away, or `None` if the slot is gone and the event was discarded.
+    pub fn buffer(&mut self, slot: Slot, event: &PluginEvent) -> Option<Commitment> {
+        if self.root.is_some_and(|root| slot < root.saturating_sub(TRACKED_SLOTS)) {
+            StreamMetricHelper::record_commitment_buffer(self.metrics.as_ref(), self.buffered_slots(), 1);
+            return None;
+        }
+
+        let state = self.slots.entry(slot).or_default();
+        if let PluginEvent::Block(block) = event {
+            if let Some(parent) = block.parent_slot {
+                state.parent.get_or_insert(parent);
+            }
+        }
+        if state.dead {
+            StreamMetricHelper::record_commitment_buffer(self.metrics.as_ref(), self.buffered_slots(), 1);
+            return None;
+        }
+        match state.reached {
+            Commitment::Processed => state.unconfirmed.push(event.clone()),
+            Commitment::Confirmed => state.confirmed.push(event.clone()),
+            Commitment::Finalized => {}
+        }
+        Some(state.reached)
+    }
+
+    /// Returns the buffered events the status releases, in slot order
+    pub fn slot_status(&mut self, info: &UniformSlotInfo) -> Vec<Release> {
+        if let Some(parent) = info.parent {
+            self.slots.entry(info.slot).or_default().parent.get_or_insert(parent);
+        }
+
+        let mut released = Vec::new();
+        let mut discarded = 0;
+        match &info.status {
+            SlotStatus::Confirmed => {
+                for slot in self.ancestry(info.slot) {
+                    let state = self.slots.get_mut(&slot).expect("ancestry only lists tracked slots");
+                    if state.reached == Commitment::Processed && !state.dead {
+                        Self::confirm(state, &mut released);
+                    }
+                }
+            }
+            SlotStatus::Rooted => {
+                let ancestry = self.ancestry(info.slot);
+                for &slot in &ancestry {
+                    let state = self.slots.get_mut(&slot).expect("ancestry only lists tracked slots");
+                    if state.reached == Commitment::Processed && !state.dead {
+                        Self::confirm(state, &mut released);
+                    }
+                    if state.reached == Commitment::Confirmed {
+                        state.reached = Commitment::Finalized;
+                        released.push(Release {
+                            commitment: Commitment::Finalized,
+                            events: std::mem::take(&mut state.confirmed),
+                        });
+                    }
+                }
+
+                // everything before the new root that isn't one of its ancestors was skipped by fork choice, slots
+                // older than a gap in the root's ancestry can't be told apart from its ancestors, they are left alone
+                // until pruned. Slots between the oldest known ancestor and its parent are no ancestors either.
+                let skipped_from = ancestry.first().map(|oldest| {
+                    self.slots[oldest].parent
+                        .filter(|parent| parent < oldest)
+                        .map_or(*oldest, |parent| parent + 1)
+                });
+                if let Some(skipped_from) = skipped_from {
+                    let ancestry: HashSet<Slot> = ancestry.into_iter().collect();
+                    for (slot, state) in self.slots.range_mut(skipped_from..info.slot) {
+                        if !ancestry.contains(slot) && state.reached != Commitment::Finalized && !state.dead {
+                            discarded += Self::discard(state);
+                        }
+                    }
+                }
+                self.root = Some(info.slot);
+                self.prune();
+            }
+            SlotStatus::Dead(_) => {
+                let state = self.slots.entry(info.slot).or_default();
+                if !state.dead {
+                    discarded += Self::discard(state);
+                }
+            }
+            _ => return released,
+        }
+
+        StreamMetricHelper::record_commitment_buffer(self.metrics.as_ref(), self.buffered_slots(), discarded);
+        released
+    }
+
+    fn confirm(state: &mut SlotState, released: &mut Vec<Release>) {
+        state.reached = Commitment::Confirmed;
+        released.push(Release {
+            commitment: Commitment::Confirmed,
+            events: state.unconfirmed.clone(),
+        });
+        // finalized plugins get everything of the slot once it is rooted
+        let mut events = std::mem::take(&mut state.unconfirmed);
+        events.append(&mut state.confirmed);
+        state.confirmed = events;
+    }
+
+    /// Returns how many events were discarded
+    fn discard(state: &mut SlotState) -> u64 {
+        state.dead = true;
+        let discarded = state.unconfirmed.len() + state.confirmed.len();
+        state.unconfirmed = Vec::new();
+        state.confirmed = Vec::new();
+        discarded as u64
+    }
+
+    /// The slot and its tracked ancestors after the current root, oldest first
+    fn ancestry(&self, slot: Slot) -> Vec<Slot> {
+        let mut ancestry = Vec::new();
+        let mut next = Some(slot);
+        while let Some(slot) = next {
+            if self.root.is_some_and(|root| slot <= root) {
+                break;
+            }
+            let Some(state) = self.slots.get(&slot) else {
+                break;
+            };
+            ancestry.push(slot);
+            next = state.parent.filter(|parent| *parent < slot);
+        }
+        ancestry.reverse();
+        ancestry
+    }
+
+    fn prune(&mut self) {
+        let Some(root) = self.root else {
+            return;
+        };
+        self.slots = self.slots.split_off(&root.saturating_sub(TRACKED_SLOTS));
+    }
+
+    fn buffered_slots(&self) -> u64 {
+        self.slots.values()
+            .filter(|state| !state.unconfirmed.is_empty() || !state.confirmed.is_empty())
+            .count() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_pubkey::Pubkey;
+    use transport::UniformAccountInfo;
+
+    fn account(slot: Slot) -> PluginEvent {
+        PluginEvent::Account {
+            replica: Arc::new(UniformAccountInfo {
+                slot,
+                pubkey: Pubkey::default(),
+                owner: Pubkey::default(),
+                lamports: 1,
+                data: Vec::new(),
+                transaction_ref: None,
+                executable: false,
+                rent_epoch: 0,
+                write_version: 0,
+            }),
+            txn: None,
+            is_startup: false,
+        }
+    }
+
+    fn status(slot: Slot, parent: Slot, status: SlotStatus) -> UniformSlotInfo {
+        UniformSlotInfo::from_replica(slot, Some(parent), status)
+    }
+
+    /// The slots of the released events, releases without events left out
+    fn released(releases: Vec<Release>) -> Vec<(Commitment, Vec<Slot>)> {
+        releases.into_iter()
+            .filter(|release| !release.events.is_empty())
+            .map(|release| (release.commitment, release.events.iter().filter_map(PluginEvent::data_slot).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn releases_confirmed_and_then_finalized() {
+        let mut gate = CommitmentGate::new(None);
+        assert_eq!(gate.buffer(10, &account(10)), Some(Commitment::Processed));
+
+        let confirmed = gate.slot_status(&status(10, 9, SlotStatus::Confirmed));
+        assert_eq!(released(confirmed), [(Commitment::Confirmed, vec![10])]);
+        assert_eq!(gate.buffer(10, &account(10)), Some(Commitment::Confirmed));
+
+        let rooted = gate.slot_status(&status(10, 9, SlotStatus::Rooted));
+        assert_eq!(released(rooted), [(Commitment::Finalized, vec![10, 10])]);
+    }
+
+    #[test]
+    fn confirming_a_slot_confirms_its_ancestors() {
+        let mut gate = CommitmentGate::new(None);
+        gate.slot_status(&status(10, 9, SlotStatus::Processed));
+        gate.slot_status(&status(11, 10, SlotStatus::Processed));
+        gate.buffer(10, &account(10));
+        gate.buffer(11, &account(11));
+
+        let confirmed = gate.slot_status(&status(11, 10, SlotStatus::Confirmed));
+        assert_eq!(released(confirmed), [(Commitment::Confirmed, vec![10]), (Commitment::Confirmed, vec![11])]);
+    }
+
+    #[test]
+    fn rooting_releases_the_root_and_discards_skipped_forks() {
+        let mut gate = CommitmentGate::new(None);
+        gate.slot_status(&status(10, 9, SlotStatus::Processed));
+        gate.slot_status(&status(11, 10, SlotStatus::Processed));
+        gate.slot_status(&status(12, 10, SlotStatus::Processed));
+        gate.buffer(11, &account(11));
+        gate.buffer(12, &account(12));
+
+        let rooted = gate.slot_status(&status(12, 10, SlotStatus::Rooted));
+        assert_eq!(released(rooted), [(Commitment::Confirmed, vec![12]), (Commitment::Finalized, vec![12])]);
+        assert_eq!(gate.buffer(11, &account(11)), None);
+        assert!(released(gate.slot_status(&status(11, 10, SlotStatus::Confirmed))).is_empty());
+    }
+
+    #[test]
+    fn dead_slots_are_discarded() {
+        let mut gate = CommitmentGate::new(None);
+        gate.buffer(10, &account(10));
+
+        assert!(released(gate.slot_status(&status(10, 9, SlotStatus::Dead(String::new())))).is_empty());
+        assert!(released(gate.slot_status(&status(10, 9, SlotStatus::Confirmed))).is_empty());
+        assert_eq!(gate.buffer(10, &account(10)), None);
+    }
+
+    #[test]
+    fn data_far_behind_the_root_is_discarded() {
+        let mut gate = CommitmentGate::new(None);
+        gate.slot_status(&status(1000, 999, SlotStatus::Rooted));
+
+        assert_eq!(gate.buffer(1000 - TRACKED_SLOTS - 1, &account(1000 - TRACKED_SLOTS - 1)), None);
+        assert_eq!(gate.buffer(1001, &account(1001)), Some(Commitment::Processed));
+    }
+}
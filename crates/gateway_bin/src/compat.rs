@@ -0,0 +1,292 @@
+//! Calls plugins with the interface version they were configured for, down-converting the `Uniform*Info` types into
+//! the older replica shapes for plugins built against older `agave-geyser-plugin-interface` releases. Fields the
+//! upstream validator didn't report are passed as 0 / empty where the plugin's interface requires them.
+
+use crate::plugin_settings::{
+    AccountInterfaceVersion, BlockInterfaceVersion, EntryInterfaceVersion, TransactionInterfaceVersion,
+};
+use agave_geyser_plugin_interface::geyser_plugin_interface::{
+    ReplicaAccountInfo, ReplicaAccountInfoV2, ReplicaAccountInfoV3, ReplicaAccountInfoVersions, ReplicaBlockInfo,
+    ReplicaBlockInfoV2, ReplicaBlockInfoV3, ReplicaBlockInfoV4, ReplicaBlockInfoVersions, ReplicaEntryInfo,
+    ReplicaEntryInfoV2, ReplicaEntryInfoVersions, ReplicaTransactionInfo, ReplicaTransactionInfoV2,
+    ReplicaTransactionInfoV3, ReplicaTransactionInfoVersions, Result as PluginResult,
+};
+use agave_reserved_account_keys::ReservedAccountKeys;
+use solana_geyser_plugin_manager::geyser_plugin_manager::LoadedGeyserPlugin;
+use solana_message::{AddressLoader, SanitizedMessage};
+use solana_message::v0::{LoadedAddresses, MessageAddressTableLookup};
+use solana_pubkey::Pubkey;
+use solana_transaction::sanitized::SanitizedTransaction;
+use solana_transaction_error::{TransactionError, TransactionResult};
+use std::collections::HashSet;
+use std::sync::{Arc, LazyLock, OnceLock};
+use transport::metrics::{StreamMetricHelper, TransportMetrics};
+use transport::{UniformAccountInfo, UniformBlockInfo, UniformEntryInfo, UniformTransactionInfo};
+
+#[derive(Clone)]
+struct LoadableLoadedAddresses(LoadedAddresses);
+
+impl AddressLoader for LoadableLoadedAddresses {
+    fn load_addresses(
+        self,
+        _lookups: &[MessageAddressTableLookup],
+    ) -> Result<LoadedAddresses, solana_transaction_error::AddressLoaderError> {
+        Ok(self.0)
+    }
+}
+
+/// Reserved account keys are never writable. Transactions the proxy didn't get the validator's writability for are
+/// reconstructed with every key reserved by the agave release the gateway was built against.
+static RESERVED_ACCOUNT_KEYS: LazyLock<HashSet<Pubkey>> =
+    LazyLock::new(|| ReservedAccountKeys::new_all_activated().active);
+
+/// A transaction shared by every plugin it is delivered to. It is only sanitized once and only if a plugin needs the
+/// `SanitizedTransaction`, either for an older transaction interface version or as the transaction of an account update.
+pub struct SharedTransaction {
+    pub replica: UniformTransactionInfo,
+    sanitized: OnceLock<Option<SanitizedTransaction>>,
+    metrics: Option<Arc<TransportMetrics>>,
+}
+
+impl SharedTransaction {
+    pub fn new(replica: UniformTransactionInfo, metrics: Option<Arc<TransportMetrics>>) -> Self {
+        Self {
+            replica,
+            sanitized: OnceLock::new(),
+            metrics,
+        }
+    }
+
+    /// `None` if the transaction fails to sanitize
+    pub fn sanitized(&self) -> Option<&SanitizedTransaction> {
+        self.sanitized.get_or_init(|| self.sanitize()).as_ref()
+    }
+
+    fn sanitize(&self) -> Option<SanitizedTransaction> {
+        let txn_info = &self.replica;
+        let sanitized = SanitizedTransaction::try_create(
+            txn_info.transaction.clone(),
+            txn_info.message_hash,
+            Some(txn_info.is_vote),
+            LoadableLoadedAddresses(txn_info.transaction_status_meta.loaded_addresses.clone()),
+            &RESERVED_ACCOUNT_KEYS,
+        )
+        .and_then(|sanitized| match &txn_info.writable {
+            Some(writable) => with_writability(sanitized, writable),
+            None => Ok(sanitized),
+        });
+
+        match sanitized {
+            Ok(sanitized) => Some(sanitized),
+            Err(e) => {
+                tracing::warn!(signature = %txn_info.signature, slot = txn_info.slot, "failed to reconstruct transaction: {}", e);
+                StreamMetricHelper::record_transaction_reconstruction_failure(self.metrics.as_ref());
+                None
+            }
+        }
+    }
+}
+
+/// Replaces the writability derived from the reserved account keys with the one the validator computed
+fn with_writability(sanitized: SanitizedTransaction, writable: &[bool]) -> TransactionResult<SanitizedTransaction> {
+    if writable.len() != sanitized.message().account_keys().len() {
+        return Err(TransactionError::SanitizeFailure);
+    }
+    let message = match sanitized.message().clone() {
+        SanitizedMessage::Legacy(mut message) => {
+            message.is_writable_account_cache = writable.to_vec();
+            SanitizedMessage::Legacy(message)
+        }
+        SanitizedMessage::V0(mut message) => {
+            message.is_writable_account_cache = writable.to_vec();
+            SanitizedMessage::V0(message)
+        }
+    };
+    SanitizedTransaction::try_new_from_fields(
+        message,
+        *sanitized.message_hash(),
+        sanitized.is_simple_vote_transaction(),
+        sanitized.signatures().to_vec(),
+    )
+}
+
+pub fn update_account(
+    plugin: &LoadedGeyserPlugin,
+    version: AccountInterfaceVersion,
+    replica: &UniformAccountInfo,
+    txn: Option<&SanitizedTransaction>,
+    is_startup: bool,
+) -> PluginResult<()> {
+    match version {
+        AccountInterfaceVersion::V0_0_3 => plugin.update_account(
+            ReplicaAccountInfoVersions::V0_0_3(&ReplicaAccountInfoV3 {
+                pubkey: replica.pubkey.as_ref(),
+                lamports: replica.lamports,
+                owner: replica.owner.as_ref(),
+                executable: replica.executable,
+                rent_epoch: replica.rent_epoch,
+                data: replica.data.as_ref(),
+                write_version: replica.write_version,
+                txn,
+            }),
+            replica.slot,
+            is_startup,
+        ),
+        AccountInterfaceVersion::V0_0_2 => plugin.update_account(
+            ReplicaAccountInfoVersions::V0_0_2(&ReplicaAccountInfoV2 {
+                pubkey: replica.pubkey.as_ref(),
+                lamports: replica.lamports,
+                owner: replica.owner.as_ref(),
+                executable: replica.executable,
+                rent_epoch: replica.rent_epoch,
+                data: replica.data.as_ref(),
+                write_version: replica.write_version,
+                txn_signature: replica.transaction_ref.as_ref(),
+            }),
+            replica.slot,
+            is_startup,
+        ),
+        AccountInterfaceVersion::V0_0_1 => plugin.update_account(
+            ReplicaAccountInfoVersions::V0_0_1(&ReplicaAccountInfo {
+                pubkey: replica.pubkey.as_ref(),
+                lamports: replica.lamports,
+                owner: replica.owner.as_ref(),
+                executable: replica.executable,
+                rent_epoch: replica.rent_epoch,
+                data: replica.data.as_ref(),
+                write_version: replica.write_version,
+            }),
+            replica.slot,
+            is_startup,
+        ),
+    }
+}
+
+/// The older versions need the sanitized transaction, transactions that fail to sanitize are skipped for those.
+pub fn notify_transaction(
+    plugin: &LoadedGeyserPlugin,
+    version: TransactionInterfaceVersion,
+    txn: &SharedTransaction,
+) -> PluginResult<()> {
+    let replica = &txn.replica;
+
+    match version {
+        TransactionInterfaceVersion::V0_0_3 => plugin.notify_transaction(
+            ReplicaTransactionInfoVersions::V0_0_3(&ReplicaTransactionInfoV3 {
+                signature: &replica.signature,
+                message_hash: &replica.message_hash,
+                is_vote: replica.is_vote,
+                transaction: &replica.transaction,
+                transaction_status_meta: &replica.transaction_status_meta,
+                index: replica.index.unwrap_or_default(),
+            }),
+            replica.slot,
+        ),
+        TransactionInterfaceVersion::V0_0_2 => match txn.sanitized() {
+            Some(transaction) => plugin.notify_transaction(
+                ReplicaTransactionInfoVersions::V0_0_2(&ReplicaTransactionInfoV2 {
+                    signature: &replica.signature,
+                    is_vote: replica.is_vote,
+                    transaction,
+                    transaction_status_meta: &replica.transaction_status_meta,
+                    index: replica.index.unwrap_or_default(),
+                }),
+                replica.slot,
+            ),
+            None => Ok(()),
+        },
+        TransactionInterfaceVersion::V0_0_1 => match txn.sanitized() {
+            Some(transaction) => plugin.notify_transaction(
+                ReplicaTransactionInfoVersions::V0_0_1(&ReplicaTransactionInfo {
+                    signature: &replica.signature,
+                    is_vote: replica.is_vote,
+                    transaction,
+                    transaction_status_meta: &replica.transaction_status_meta,
+                }),
+                replica.slot,
+            ),
+            None => Ok(()),
+        },
+    }
+}
+
+pub fn notify_block_metadata(
+    plugin: &LoadedGeyserPlugin,
+    version: BlockInterfaceVersion,
+    replica: &UniformBlockInfo,
+) -> PluginResult<()> {
+    match version {
+        BlockInterfaceVersion::V0_0_4 => plugin.notify_block_metadata(ReplicaBlockInfoVersions::V0_0_4(
+            &ReplicaBlockInfoV4 {
+                parent_slot: replica.parent_slot.unwrap_or_default(),
+                parent_blockhash: replica.parent_blockhash.as_deref().unwrap_or_default(),
+                slot: replica.slot,
+                blockhash: &replica.blockhash,
+                rewards: &replica.rewards,
+                block_time: replica.block_time,
+                block_height: replica.block_height,
+                executed_transaction_count: replica.executed_transaction_count.unwrap_or_default(),
+                entry_count: replica.entry_count.unwrap_or_default(),
+            },
+        )),
+        BlockInterfaceVersion::V0_0_3 => plugin.notify_block_metadata(ReplicaBlockInfoVersions::V0_0_3(
+            &ReplicaBlockInfoV3 {
+                parent_slot: replica.parent_slot.unwrap_or_default(),
+                parent_blockhash: replica.parent_blockhash.as_deref().unwrap_or_default(),
+                slot: replica.slot,
+                blockhash: &replica.blockhash,
+                rewards: &replica.rewards.rewards,
+                block_time: replica.block_time,
+                block_height: replica.block_height,
+                executed_transaction_count: replica.executed_transaction_count.unwrap_or_default(),
+                entry_count: replica.entry_count.unwrap_or_default(),
+            },
+        )),
+        BlockInterfaceVersion::V0_0_2 => plugin.notify_block_metadata(ReplicaBlockInfoVersions::V0_0_2(
+            &ReplicaBlockInfoV2 {
+                parent_slot: replica.parent_slot.unwrap_or_default(),
+                parent_blockhash: replica.parent_blockhash.as_deref().unwrap_or_default(),
+                slot: replica.slot,
+                blockhash: &replica.blockhash,
+                rewards: &replica.rewards.rewards,
+                block_time: replica.block_time,
+                block_height: replica.block_height,
+                executed_transaction_count: replica.executed_transaction_count.unwrap_or_default(),
+            },
+        )),
+        BlockInterfaceVersion::V0_0_1 => plugin.notify_block_metadata(ReplicaBlockInfoVersions::V0_0_1(
+            &ReplicaBlockInfo {
+                slot: replica.slot,
+                blockhash: &replica.blockhash,
+                rewards: &replica.rewards.rewards,
+                block_time: replica.block_time,
+                block_height: replica.block_height,
+            },
+        )),
+    }
+}
+
+pub fn notify_entry(
+    plugin: &LoadedGeyserPlugin,
+    version: EntryInterfaceVersion,
+    replica: &UniformEntryInfo,
+) -> PluginResult<()> {
+    let hash = replica.hash.to_bytes();
+    match version {
+        EntryInterfaceVersion::V0_0_2 => plugin.notify_entry(ReplicaEntryInfoVersions::V0_0_2(&ReplicaEntryInfoV2 {
+            slot: replica.slot,
+            index: replica.index,
+            num_hashes: replica.num_hashes,
+            hash: &hash,
+            executed_transaction_count: replica.executed_transaction_count,
+            starting_transaction_index: replica.starting_transaction_index.unwrap_or_default(),
+        })),
+        EntryInterfaceVersion::V0_0_1 => plugin.notify_entry(ReplicaEntryInfoVersions::V0_0_1(&ReplicaEntryInfo {
+            slot: replica.slot,
+            index: replica.index,
+            num_hashes: replica.num_hashes,
+            hash: &hash,
+            executed_transaction_count: replica.executed_transaction_count,
+        })),
+    }
+}
@@ -0,0 +1,207 @@
+//! Fork tracking. Slot statuses and block metadata link every slot to its parent, which builds the tree of forks after
+//! the current root. When a slot is rooted, every tracked slot that doesn't descend from it was on an abandoned fork and
+//! is rolled back, the same as a slot the validator marks dead.
+
+use agave_geyser_plugin_interface::geyser_plugin_interface::SlotStatus;
+use solana_clock::Slot;
+use solana_pubkey::Pubkey;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::Arc;
+use transport::metrics::{StreamMetricHelper, TransportMetrics};
+use transport::{UniformRollbackInfo, UniformSlotInfo};
+
+#[derive(Default)]
+struct SlotNode {
+    parent: Option<Slot>,
+    /// Accounts updated in the slot
+    pubkeys: HashSet<Pubkey>,
+    dead: bool,
+}
+
+pub struct ForkTracker {
+    slots: BTreeMap<Slot, SlotNode>,
+    root: Option<Slot>,
+    metrics: Option<Arc<TransportMetrics>>,
+}
+
+impl ForkTracker {
+    pub fn new(metrics: Option<Arc<TransportMetrics>>) -> Self {
+        Self {
+            slots: BTreeMap::new(),
+            root: None,
+            metrics,
+        }
+    }
+
+    pub fn account(&mut self, slot: Slot, pubkey: Pubkey) {
+        if let Some(node) = self.node(slot) {
+            node.pubkeys.insert(pubkey);
+        }
+    }
+
+    pub fn block(&mut self, slot: Slot, parent: Slot) {
+        if let Some(node) = self.node(slot) {
+            node.parent.get_or_insert(parent);
+        }
+    }
+
+    /// Returns the rollback the status causes, if any
+    pub fn slot_status(&mut self, info: &UniformSlotInfo) -> Option<UniformRollbackInfo> {
+        let parent_dead = info.parent.is_some_and(|parent| self.slots.get(&parent).is_some_and(|node| node.dead));
+        let node = self.node(info.slot)?;
+        if let Some(parent) = info.parent {
+            node.parent.get_or_insert(parent);
+        }
+        if node.dead {
+            return None;
+        }
+
+        match &info.status {
+            SlotStatus::Rooted => self.rooted(info.slot),
+            SlotStatus::Dead(_) => self.roll_back(None, BTreeSet::from([info.slot])),
+            // a slot built on a rolled back slot belongs to the abandoned fork as well
+            _ if parent_dead => self.roll_back(self.root, BTreeSet::from([info.slot])),
+            _ => None,
+        }
+    }
+
+    fn rooted(&mut self, root: Slot) -> Option<UniformRollbackInfo> {
+        let mut ancestry = HashSet::new();
+        let mut oldest = root;
+        let mut next = Some(root);
+        while let Some(slot) = next {
+            let Some(node) = self.slots.get(&slot) else {
+                break;
+            };
+            ancestry.insert(slot);
+            oldest = slot;
+            next = node.parent.filter(|parent| *parent < slot);
+        }
+
+        // slots older than a gap in the root's ancestry can't be told apart from its ancestors, they are left alone
+        let mut abandoned = BTreeSet::new();
+        for (slot, node) in self.slots.range(oldest..) {
+            if node.dead || ancestry.contains(slot) {
+                continue;
+            }
+            let on_abandoned_fork = match node.parent {
+                _ if *slot < root => true,
+                Some(parent) => abandoned.contains(&parent) || (parent < root && parent >= oldest && !ancestry.contains(&parent)),
+                None => false,
+            };
+            if on_abandoned_fork {
+                abandoned.insert(*slot);
+            }
+        }
+
+        let rollback = self.roll_back(Some(root), abandoned);
+        self.root = Some(root);
+        // everything before the root is settled, dead slots after it are kept to recognize their descendants
+        self.slots = self.slots.split_off(&root);
+        rollback
+    }
+
+    fn roll_back(&mut self, root: Option<Slot>, slots: BTreeSet<Slot>) -> Option<UniformRollbackInfo> {
+        if slots.is_empty() {
+            return None;
+        }
+
+        let mut pubkeys = Vec::new();
+        let mut accounts = 0;
+        for slot in &slots {
+            let node = self.slots.entry(*slot).or_default();
+            node.dead = true;
+            accounts += node.pubkeys.len() as u64;
+            pubkeys.extend(node.pubkeys.drain());
+        }
+        pubkeys.sort_unstable();
+        pubkeys.dedup();
+
+        StreamMetricHelper::record_rollback(self.metrics.as_ref(), slots.len() as u64, accounts);
+        tracing::info!(?root, ?slots, accounts = pubkeys.len(), "rolled back slots");
+        Some(UniformRollbackInfo {
+            root,
+            slots: slots.into_iter().collect(),
+            pubkeys,
+        })
+    }
+
+    /// `None` for slots at or before the root, they can't be rolled back anymore
+    fn node(&mut self, slot: Slot) -> Option<&mut SlotNode> {
+        if self.root.is_some_and(|root| slot <= root) {
+            return None;
+        }
+        Some(self.slots.entry(slot).or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(tracker: &mut ForkTracker, slot: Slot, parent: Slot, status: SlotStatus) -> Option<UniformRollbackInfo> {
+        tracker.slot_status(&UniformSlotInfo::from_replica(slot, Some(parent), status))
+    }
+
+    #[test]
+    fn rooting_rolls_back_abandoned_forks() {
+        let mut forks = ForkTracker::new(None);
+        let abandoned = Pubkey::new_from_array([1; 32]);
+        assert!(status(&mut forks, 10, 9, SlotStatus::Processed).is_none());
+        assert!(status(&mut forks, 11, 10, SlotStatus::Processed).is_none());
+        assert!(status(&mut forks, 12, 10, SlotStatus::Processed).is_none());
+        forks.account(11, abandoned);
+        forks.account(12, Pubkey::new_from_array([2; 32]));
+
+        let rollback = status(&mut forks, 12, 10, SlotStatus::Rooted).expect("slot 11 is on an abandoned fork");
+        assert_eq!(rollback.root, Some(12));
+        assert_eq!(rollback.slots, [11]);
+        assert_eq!(rollback.pubkeys, [abandoned]);
+    }
+
+    #[test]
+    fn rooting_the_only_fork_rolls_back_nothing() {
+        let mut forks = ForkTracker::new(None);
+        status(&mut forks, 10, 9, SlotStatus::Processed);
+        status(&mut forks, 11, 10, SlotStatus::Processed);
+        assert!(status(&mut forks, 11, 10, SlotStatus::Rooted).is_none());
+    }
+
+    #[test]
+    fn dead_slots_and_their_descendants_are_rolled_back() {
+        let mut forks = ForkTracker::new(None);
+        status(&mut forks, 10, 9, SlotStatus::Processed);
+
+        let dead = status(&mut forks, 11, 10, SlotStatus::Dead(String::new())).expect("the slot died");
+        assert_eq!(dead.root, None);
+        assert_eq!(dead.slots, [11]);
+        // rolled back once only
+        assert!(status(&mut forks, 11, 10, SlotStatus::Dead(String::new())).is_none());
+
+        let descendant = status(&mut forks, 12, 11, SlotStatus::Processed).expect("built on a dead slot");
+        assert_eq!(descendant.slots, [12]);
+    }
+
+    #[test]
+    fn descendants_of_a_dead_slot_after_the_root_are_rolled_back() {
+        let mut forks = ForkTracker::new(None);
+        status(&mut forks, 10, 9, SlotStatus::Processed);
+        status(&mut forks, 10, 9, SlotStatus::Rooted);
+        status(&mut forks, 11, 10, SlotStatus::Dead(String::new()));
+
+        let descendant = status(&mut forks, 12, 11, SlotStatus::Processed).expect("built on a dead slot");
+        assert_eq!(descendant.root, Some(10));
+        assert_eq!(descendant.slots, [12]);
+    }
+
+    #[test]
+    fn slots_up_to_the_root_are_pruned() {
+        let mut forks = ForkTracker::new(None);
+        status(&mut forks, 10, 9, SlotStatus::Processed);
+        status(&mut forks, 10, 9, SlotStatus::Rooted);
+
+        forks.account(8, Pubkey::new_from_array([1; 32]));
+        assert!(status(&mut forks, 8, 7, SlotStatus::Dead(String::new())).is_none());
+        assert!(forks.slots.range(..=10).all(|(slot, _)| *slot == 10));
+    }
+}
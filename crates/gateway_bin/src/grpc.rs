@@ -0,0 +1,450 @@
+//! Optional gRPC streaming API, built with the `grpc` cargo feature. Streams accounts, transactions, slot status, block
+//! metadata, entries and rollbacks straight from the replica channels, filtered per client on the gateway, without running a
+//! separate gRPC plugin behind it, and the blocks the gateway assembles. See `proto/gateway.proto`.
+
+mod proto {
+    tonic::include_proto!("ample.gateway.v1");
+}
+
+use crate::block_assembler::CompleteBlock;
+use crate::verification::VerificationIssue;
+use agave_geyser_plugin_interface::geyser_plugin_interface::SlotStatus;
+use proto::gateway_server::{Gateway, GatewayServer};
+use proto::update::Update as UpdateKind;
+use proto::{SubscribeRequest, Update};
+use solana_pubkey::Pubkey;
+use solana_transaction_status::{Reward, RewardType, TransactionStatusMeta, TransactionTokenBalance};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use transport::filters::{AccountFilter, TransactionFilter};
+use transport::{
+    ReplicaChannels, UniformAccountInfo, UniformBlockInfo, UniformEntryInfo, UniformRollbackInfo, UniformSlotInfo,
+    UniformTransactionInfo,
+};
+
+/// Updates buffered per client before the client's stream applies backpressure
+const CLIENT_BUFFER: usize = 4096;
+
+pub struct GrpcServer {
+    shutdown: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+}
+
+impl GrpcServer {
+    /// `assembled_blocks` is `None` if the gateway doesn't assemble blocks
+    pub async fn spawn(
+        addr: SocketAddr,
+        replica_channels: ReplicaChannels,
+        assembled_blocks: Option<Sender<Arc<CompleteBlock>>>,
+    ) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let (shutdown, stop) = watch::channel(false);
+        let service = GatewayService {
+            replica_channels,
+            assembled_blocks,
+            shutdown: stop.clone(),
+        };
+
+        let handle = tokio::spawn(async move {
+            let mut stop = stop;
+            let result = tonic::transport::Server::builder()
+                .add_service(GatewayServer::new(service))
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async move {
+                    let _ = stop.wait_for(|stop| *stop).await;
+                })
+                .await;
+            if let Err(e) = result {
+                tracing::error!("gRPC server failed: {}", e);
+            }
+        });
+        tracing::info!(%addr, "gRPC API listening");
+        Ok(Self { shutdown, handle })
+    }
+
+    /// Ends every client's stream and stops accepting new clients
+    pub async fn close(self) {
+        let _ = self.shutdown.send(true);
+        let _ = self.handle.await;
+    }
+}
+
+struct GatewayService {
+    replica_channels: ReplicaChannels,
+    assembled_blocks: Option<Sender<Arc<CompleteBlock>>>,
+    shutdown: watch::Receiver<bool>,
+}
+
+#[tonic::async_trait]
+impl Gateway for GatewayService {
+    type SubscribeStream = ReceiverStream<Result<Update, Status>>;
+
+    async fn subscribe(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let peer = request.remote_addr();
+        let filter = Filter::try_from(request.into_inner())?;
+        if filter.assembled_blocks && self.assembled_blocks.is_none() {
+            return Err(Status::failed_precondition("the gateway doesn't assemble blocks, it needs --assemble-blocks"));
+        }
+        let receivers = StreamReceivers::subscribe(&self.replica_channels, self.assembled_blocks.as_ref(), &filter);
+
+        let (updates, stream) = mpsc::channel(CLIENT_BUFFER);
+        let shutdown = self.shutdown.clone();
+        tracing::info!(?peer, "gRPC client subscribed");
+        tokio::spawn(async move {
+            stream_updates(filter, receivers, updates, shutdown).await;
+            tracing::info!(?peer, "gRPC client stream ended");
+        });
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+}
+
+struct Filter {
+    accounts: Option<AccountFilter>,
+    transactions: Option<TransactionFilter>,
+    slots: bool,
+    blocks: bool,
+    entries: bool,
+    rollbacks: bool,
+    assembled_blocks: bool,
+}
+
+impl TryFrom<SubscribeRequest> for Filter {
+    type Error = Status;
+
+    fn try_from(request: SubscribeRequest) -> Result<Self, Status> {
+        Ok(Self {
+            accounts: request.accounts.map(|accounts| -> Result<_, Status> {
+                Ok(AccountFilter {
+                    pubkeys: parse_pubkeys(&accounts.pubkeys)?,
+                    owners: parse_pubkeys(&accounts.owners)?,
+                })
+            }).transpose()?,
+            transactions: request.transactions.map(|transactions| -> Result<_, Status> {
+                Ok(TransactionFilter {
+                    include_votes: transactions.include_votes,
+                    include_failed: transactions.include_failed,
+                    accounts: parse_pubkeys(&transactions.account_include)?,
+                })
+            }).transpose()?,
+            slots: request.slots,
+            blocks: request.blocks,
+            entries: request.entries,
+            rollbacks: request.rollbacks,
+            assembled_blocks: request.assembled_blocks,
+        })
+    }
+}
+
+fn parse_pubkeys(keys: &[Vec<u8>]) -> Result<HashSet<Pubkey>, Status> {
+    keys.iter()
+        .map(|key| Pubkey::try_from(key.as_slice()).map_err(|_| Status::invalid_argument("pubkeys must be 32 bytes")))
+        .collect()
+}
+
+/// Only the streams a client asked for are subscribed, so the others can't make it lag
+struct StreamReceivers {
+    account: Option<Receiver<UniformAccountInfo>>,
+    transaction: Option<Receiver<UniformTransactionInfo>>,
+    slot: Option<Receiver<UniformSlotInfo>>,
+    block: Option<Receiver<UniformBlockInfo>>,
+    entry: Option<Receiver<UniformEntryInfo>>,
+    rollback: Option<Receiver<UniformRollbackInfo>>,
+    assembled_block: Option<Receiver<Arc<CompleteBlock>>>,
+}
+
+impl StreamReceivers {
+    fn subscribe(
+        replica_channels: &ReplicaChannels,
+        assembled_blocks: Option<&Sender<Arc<CompleteBlock>>>,
+        filter: &Filter,
+    ) -> Self {
+        Self {
+            account: filter.accounts.is_some().then(|| replica_channels.account.subscribe()),
+            transaction: filter.transactions.is_some().then(|| replica_channels.transaction.subscribe()),
+            slot: filter.slots.then(|| replica_channels.slot.subscribe()),
+            block: filter.blocks.then(|| replica_channels.block.subscribe()),
+            entry: filter.entries.then(|| replica_channels.entry.subscribe()),
+            rollback: filter.rollbacks.then(|| replica_channels.rollback.subscribe()),
+            assembled_block: assembled_blocks.filter(|_| filter.assembled_blocks).map(Sender::subscribe),
+        }
+    }
+}
+
+/// Pending forever for a stream the client didn't ask for
+async fn recv<T: Clone>(receiver: &mut Option<Receiver<T>>) -> Result<T, RecvError> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn stream_updates(
+    filter: Filter,
+    mut receivers: StreamReceivers,
+    updates: mpsc::Sender<Result<Update, Status>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let update = tokio::select! {
+            _ = shutdown.wait_for(|stop| *stop) => return,
+            account = recv(&mut receivers.account) => account.map(|account| {
+                filter.accounts.as_ref()
+                    .is_some_and(|accounts| accounts.matches(&account))
+                    .then(|| UpdateKind::Account(account.into()))
+            }),
+            txn = recv(&mut receivers.transaction) => txn.map(|txn| {
+                filter.transactions.as_ref()
+                    .is_some_and(|transactions| transactions.matches(&txn))
+                    .then(|| transaction_update(txn))
+                    .flatten()
+                    .map(UpdateKind::Transaction)
+            }),
+            slot = recv(&mut receivers.slot) => slot.map(|slot| Some(UpdateKind::Slot(slot.into()))),
+            block = recv(&mut receivers.block) => block.map(|block| Some(UpdateKind::Block(block.into()))),
+            entry = recv(&mut receivers.entry) => entry.map(|entry| Some(UpdateKind::Entry(entry.into()))),
+            rollback = recv(&mut receivers.rollback) => rollback.map(|rollback| Some(UpdateKind::Rollback(rollback.into()))),
+            assembled = recv(&mut receivers.assembled_block) => assembled.map(|assembled| {
+                match proto::AssembledBlock::try_from(assembled.as_ref()) {
+                    Ok(assembled) => Some(UpdateKind::AssembledBlock(assembled)),
+                    Err(e) => {
+                        tracing::error!(slot = assembled.slot, "failed to serialize assembled block, skipping it: {}", e);
+                        None
+                    }
+                }
+            }),
+        };
+
+        let update = match update {
+            Ok(Some(update)) => Ok(Update { update: Some(update) }),
+            Ok(None) => continue,
+            // a client that can't keep up would silently miss updates otherwise, it has to resubscribe
+            Err(RecvError::Lagged(skipped)) => Err(Status::data_loss(format!("client lagged, skipped {} updates", skipped))),
+            Err(RecvError::Closed) => return,
+        };
+        let lagged = update.is_err();
+        if updates.send(update).await.is_err() || lagged {
+            return;
+        }
+    }
+}
+
+impl From<UniformAccountInfo> for proto::Account {
+    fn from(account: UniformAccountInfo) -> Self {
+        Self {
+            slot: account.slot,
+            pubkey: account.pubkey.to_bytes().to_vec(),
+            owner: account.owner.to_bytes().to_vec(),
+            lamports: account.lamports,
+            data: account.data,
+            executable: account.executable,
+            rent_epoch: account.rent_epoch,
+            write_version: account.write_version,
+            txn_signature: account.transaction_ref.map(|signature| signature.as_ref().to_vec()),
+        }
+    }
+}
+
+/// `None` if the transaction can't be serialized, which is logged
+fn transaction_update(txn: UniformTransactionInfo) -> Option<proto::Transaction> {
+    let signature = txn.signature;
+    proto::Transaction::try_from(txn)
+        .inspect_err(|e| tracing::error!(%signature, "failed to serialize transaction, skipping it: {}", e))
+        .ok()
+}
+
+impl TryFrom<UniformTransactionInfo> for proto::Transaction {
+    type Error = bincode::Error;
+
+    fn try_from(txn: UniformTransactionInfo) -> Result<Self, Self::Error> {
+        Ok(Self {
+            slot: txn.slot,
+            signature: txn.signature.as_ref().to_vec(),
+            is_vote: txn.is_vote,
+            index: txn.index.map(|index| index as u64),
+            failed: txn.transaction_status_meta.status.is_err(),
+            transaction: bincode::serialize(&txn.transaction)?,
+            meta: Some(txn.transaction_status_meta.into()),
+        })
+    }
+}
+
+impl From<TransactionStatusMeta> for proto::TransactionStatusMeta {
+    fn from(meta: TransactionStatusMeta) -> Self {
+        let token_balances = |balances: Option<Vec<TransactionTokenBalance>>| -> Vec<proto::TokenBalance> {
+            balances.unwrap_or_default().into_iter()
+                .map(|balance| proto::TokenBalance {
+                    account_index: balance.account_index.into(),
+                    mint: balance.mint,
+                    owner: balance.owner,
+                    program_id: balance.program_id,
+                    amount: balance.ui_token_amount.amount,
+                    decimals: balance.ui_token_amount.decimals.into(),
+                    ui_amount_string: balance.ui_token_amount.ui_amount_string,
+                })
+                .collect()
+        };
+        let addresses = |addresses: Vec<Pubkey>| -> Vec<Vec<u8>> {
+            addresses.iter().map(|pubkey| pubkey.to_bytes().to_vec()).collect()
+        };
+
+        Self {
+            error: meta.status.err().map(|error| error.to_string()),
+            fee: meta.fee,
+            pre_balances: meta.pre_balances,
+            post_balances: meta.post_balances,
+            inner_instructions: meta.inner_instructions.unwrap_or_default().into_iter()
+                .map(|inner| proto::InnerInstructions {
+                    index: inner.index.into(),
+                    instructions: inner.instructions.into_iter()
+                        .map(|inner| proto::InnerInstruction {
+                            program_id_index: inner.instruction.program_id_index.into(),
+                            accounts: inner.instruction.accounts,
+                            data: inner.instruction.data,
+                            stack_height: inner.stack_height,
+                        })
+                        .collect(),
+                })
+                .collect(),
+            log_messages: meta.log_messages.unwrap_or_default(),
+            pre_token_balances: token_balances(meta.pre_token_balances),
+            post_token_balances: token_balances(meta.post_token_balances),
+            rewards: meta.rewards.unwrap_or_default().into_iter().map(Into::into).collect(),
+            loaded_writable_addresses: addresses(meta.loaded_addresses.writable),
+            loaded_readonly_addresses: addresses(meta.loaded_addresses.readonly),
+            return_data: meta.return_data.map(|return_data| proto::ReturnData {
+                program_id: return_data.program_id.to_bytes().to_vec(),
+                data: return_data.data,
+            }),
+            compute_units_consumed: meta.compute_units_consumed,
+            cost_units: meta.cost_units,
+        }
+    }
+}
+
+impl From<Reward> for proto::Reward {
+    fn from(reward: Reward) -> Self {
+        let reward_type = match reward.reward_type {
+            None => proto::RewardType::Unspecified,
+            Some(RewardType::Fee) => proto::RewardType::Fee,
+            Some(RewardType::Rent) => proto::RewardType::Rent,
+            Some(RewardType::Staking) => proto::RewardType::Staking,
+            Some(RewardType::Voting) => proto::RewardType::Voting,
+        };
+        Self {
+            pubkey: reward.pubkey,
+            lamports: reward.lamports,
+            post_balance: reward.post_balance,
+            reward_type: reward_type.into(),
+            commission: reward.commission.map(u32::from),
+        }
+    }
+}
+
+impl From<UniformSlotInfo> for proto::SlotUpdate {
+    fn from(info: UniformSlotInfo) -> Self {
+        let (status, dead_error) = match info.status {
+            SlotStatus::Processed => (proto::SlotStatus::Processed, None),
+            SlotStatus::Rooted => (proto::SlotStatus::Rooted, None),
+            SlotStatus::Confirmed => (proto::SlotStatus::Confirmed, None),
+            SlotStatus::FirstShredReceived => (proto::SlotStatus::FirstShredReceived, None),
+            SlotStatus::Completed => (proto::SlotStatus::Completed, None),
+            SlotStatus::CreatedBank => (proto::SlotStatus::CreatedBank, None),
+            SlotStatus::Dead(error) => (proto::SlotStatus::Dead, Some(error)),
+        };
+        Self {
+            slot: info.slot,
+            parent: info.parent,
+            status: status.into(),
+            dead_error,
+        }
+    }
+}
+
+impl From<UniformBlockInfo> for proto::Block {
+    fn from(block: UniformBlockInfo) -> Self {
+        Self {
+            slot: block.slot,
+            parent_slot: block.parent_slot,
+            blockhash: block.blockhash,
+            parent_blockhash: block.parent_blockhash,
+            block_time: block.block_time,
+            block_height: block.block_height,
+            executed_transaction_count: block.executed_transaction_count,
+            entry_count: block.entry_count,
+            rewards: block.rewards.rewards.into_iter().map(Into::into).collect(),
+            num_partitions: block.rewards.num_partitions,
+        }
+    }
+}
+
+impl From<UniformEntryInfo> for proto::Entry {
+    fn from(entry: UniformEntryInfo) -> Self {
+        Self {
+            slot: entry.slot,
+            index: entry.index as u64,
+            num_hashes: entry.num_hashes,
+            hash: entry.hash.to_bytes().to_vec(),
+            executed_transaction_count: entry.executed_transaction_count,
+            starting_transaction_index: entry.starting_transaction_index.map(|index| index as u64),
+        }
+    }
+}
+
+impl From<UniformRollbackInfo> for proto::Rollback {
+    fn from(rollback: UniformRollbackInfo) -> Self {
+        Self {
+            root: rollback.root,
+            slots: rollback.slots,
+            pubkeys: rollback.pubkeys.iter().map(|pubkey| pubkey.to_bytes().to_vec()).collect(),
+        }
+    }
+}
+
+impl TryFrom<&CompleteBlock> for proto::AssembledBlock {
+    type Error = bincode::Error;
+
+    fn try_from(assembled: &CompleteBlock) -> Result<Self, Self::Error> {
+        Ok(Self {
+            slot: assembled.slot,
+            block: assembled.block.as_deref().cloned().map(Into::into),
+            transactions: assembled.transactions.iter()
+                .map(|txn| txn.replica.clone().try_into())
+                .collect::<Result<_, _>>()?,
+            entries: assembled.entries.iter().map(|entry| entry.as_ref().clone().into()).collect(),
+            transactions_complete: assembled.transactions_complete,
+            entries_complete: assembled.entries_complete,
+            index_verified: assembled.index_verified,
+            issues: assembled.verification.issues.iter().map(Into::into).collect(),
+        })
+    }
+}
+
+impl From<&VerificationIssue> for proto::VerificationIssue {
+    fn from(issue: &VerificationIssue) -> Self {
+        let (index, expected, received) = match *issue {
+            VerificationIssue::MissingBlockMetadata => (None, None, None),
+            VerificationIssue::MissingTransactions { expected, received }
+            | VerificationIssue::MissingEntries { expected, received }
+            | VerificationIssue::EntryTransactionTotal { expected, received } => (None, Some(expected), Some(received)),
+            VerificationIssue::UnexpectedTransaction { index } | VerificationIssue::EntryGap { index } => {
+                (Some(index as u64), None, None)
+            }
+            VerificationIssue::EntryTransactionIndex { index, expected, received } => {
+                (Some(index as u64), Some(expected as u64), Some(received as u64))
+            }
+        };
+        Self {
+            kind: issue.kind().to_string(),
+            index,
+            expected,
+            received,
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Holds back account updates whose transaction hasn't arrived yet. Transactions and accounts travel on separate QUIC
+//! streams, so an account update can overtake the transaction it references. Held updates are released once their
+//! transaction is in the cache or their deadline passed, while updates of other accounts keep flowing.
+
+use crate::transaction_cache::TransactionCache;
+use solana_pubkey::Pubkey;
+use solana_signature::Signature;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use transport::UniformAccountInfo;
+
+/// Updates held at most, beyond that the oldest one is released without its transaction
+const MAX_HELD_ACCOUNTS: usize = 100_000;
+
+pub struct HeldAccount {
+    pub replica: UniformAccountInfo,
+    pub is_startup: bool,
+    deadline: Instant,
+}
+
+pub struct HeldAccounts {
+    timeout: Duration,
+    /// By arrival sequence number, which with a fixed timeout is also deadline order
+    held: BTreeMap<u64, HeldAccount>,
+    next_sequence: u64,
+    /// Held updates of each account in arrival order, later updates of an account are held behind earlier ones to
+    /// keep their order
+    pubkeys: HashMap<Pubkey, VecDeque<u64>>,
+    /// Held updates waiting for each transaction
+    awaited: HashMap<Signature, Vec<u64>>,
+}
+
+impl HeldAccounts {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            held: BTreeMap::new(),
+            next_sequence: 0,
+            pubkeys: HashMap::new(),
+            awaited: HashMap::new(),
+        }
+    }
+
+    /// Whether the update has to be held, because its transaction is missing or an earlier update of the same account
+    /// is held already
+    pub fn must_hold(&self, replica: &UniformAccountInfo, transaction_cache: &TransactionCache) -> bool {
+        self.pubkeys.contains_key(&replica.pubkey)
+            || replica.transaction_ref.as_ref().is_some_and(|signature| !transaction_cache.contains(signature))
+    }
+
+    /// Returns the oldest held update if it had to make room
+    pub fn hold(&mut self, replica: UniformAccountInfo, is_startup: bool) -> Option<HeldAccount> {
+        let mut evicted = None;
+        if self.held.len() >= MAX_HELD_ACCOUNTS {
+            // the oldest update is the first of its account, releasing it keeps the account's order
+            let oldest = self.held.first_key_value().map(|(&sequence, _)| sequence);
+            evicted = oldest.map(|sequence| self.remove(sequence));
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.pubkeys.entry(replica.pubkey).or_default().push_back(sequence);
+        if let Some(signature) = replica.transaction_ref {
+            self.awaited.entry(signature).or_default().push(sequence);
+        }
+        self.held.insert(sequence, HeldAccount {
+            replica,
+            is_startup,
+            deadline: Instant::now() + self.timeout,
+        });
+        evicted
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.held.first_key_value().map(|(_, held)| held.deadline)
+    }
+
+    /// Removes the updates that can be delivered now that the transaction arrived, together with the later updates of
+    /// the same accounts they held up
+    pub fn release_awaiting(&mut self, signature: &Signature, transaction_cache: &TransactionCache) -> Vec<HeldAccount> {
+        let mut released = Vec::new();
+        let Some(awaiting) = self.awaited.get(signature) else {
+            return released;
+        };
+        let pubkeys: Vec<Pubkey> = awaiting.iter().map(|sequence| self.held[sequence].replica.pubkey).collect();
+
+        let now = Instant::now();
+        for pubkey in pubkeys {
+            self.release_ready(pubkey, transaction_cache, now, &mut released);
+        }
+        released
+    }
+
+    /// Removes the updates whose deadline passed, together with the later updates of the same accounts they held up
+    pub fn release_expired(&mut self, transaction_cache: &TransactionCache, now: Instant) -> Vec<HeldAccount> {
+        let mut released = Vec::new();
+        while let Some(pubkey) = self.held.first_key_value()
+            .filter(|(_, held)| held.deadline <= now)
+            .map(|(_, held)| held.replica.pubkey)
+        {
+            self.release_ready(pubkey, transaction_cache, now, &mut released);
+        }
+        released
+    }
+
+    /// Removes every held update in the order they arrived
+    pub fn release_all(&mut self) -> Vec<HeldAccount> {
+        let sequences: Vec<u64> = self.held.keys().copied().collect();
+        sequences.into_iter().map(|sequence| self.remove(sequence)).collect()
+    }
+
+    /// Releases the account's held updates from the oldest on, until one still waits for its transaction
+    fn release_ready(
+        &mut self,
+        pubkey: Pubkey,
+        transaction_cache: &TransactionCache,
+        now: Instant,
+        released: &mut Vec<HeldAccount>,
+    ) {
+        while let Some(&sequence) = self.pubkeys.get(&pubkey).and_then(VecDeque::front) {
+            let held = &self.held[&sequence];
+            let ready = held.deadline <= now
+                || held.replica.transaction_ref.as_ref().is_none_or(|signature| transaction_cache.contains(signature));
+            if !ready {
+                break;
+            }
+            released.push(self.remove(sequence));
+        }
+    }
+
+    /// The update must be the oldest held one of its account
+    fn remove(&mut self, sequence: u64) -> HeldAccount {
+        let held = self.held.remove(&sequence).expect("removed updates are held");
+        if let Entry::Occupied(mut pubkey) = self.pubkeys.entry(held.replica.pubkey) {
+            pubkey.get_mut().pop_front();
+            if pubkey.get().is_empty() {
+                pubkey.remove();
+            }
+        }
+        if let Some(signature) = held.replica.transaction_ref {
+            if let Entry::Occupied(mut awaiting) = self.awaited.entry(signature) {
+                awaiting.get_mut().retain(|held| *held != sequence);
+                if awaiting.get().is_empty() {
+                    awaiting.remove();
+                }
+            }
+        }
+        held
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat::SharedTransaction;
+    use std::sync::Arc;
+    use transport::UniformTransactionInfo;
+
+    fn account(pubkey: u8, transaction: Option<u8>, write_version: u64) -> UniformAccountInfo {
+        UniformAccountInfo {
+            slot: 10,
+            pubkey: Pubkey::new_from_array([pubkey; 32]),
+            owner: Pubkey::default(),
+            lamports: 1,
+            data: Vec::new(),
+            transaction_ref: transaction.map(signature),
+            executable: false,
+            rent_epoch: 0,
+            write_version,
+        }
+    }
+
+    fn signature(byte: u8) -> Signature {
+        Signature::from([byte; 64])
+    }
+
+    fn cache(transactions: &[u8]) -> TransactionCache {
+        let mut cache = TransactionCache::new(16);
+        for byte in transactions {
+            cache.insert(Arc::new(SharedTransaction::new(UniformTransactionInfo {
+                slot: 10,
+                signature: signature(*byte),
+                message_hash: Default::default(),
+                is_vote: false,
+                transaction: Default::default(),
+                transaction_status_meta: Default::default(),
+                index: None,
+                writable: None,
+            }, None)));
+        }
+        cache
+    }
+
+    fn write_versions(released: &[HeldAccount]) -> Vec<u64> {
+        released.iter().map(|held| held.replica.write_version).collect()
+    }
+
+    #[test]
+    fn holds_updates_whose_transaction_is_missing() {
+        let held = HeldAccounts::new(Duration::from_secs(60));
+        let cache = cache(&[1]);
+        assert!(held.must_hold(&account(1, Some(2), 0), &cache));
+        assert!(!held.must_hold(&account(1, Some(1), 0), &cache));
+        assert!(!held.must_hold(&account(1, None, 0), &cache));
+    }
+
+    #[test]
+    fn holds_later_updates_of_an_account_behind_a_held_one() {
+        let mut held = HeldAccounts::new(Duration::from_secs(60));
+        held.hold(account(1, Some(2), 0), false);
+        let cache = cache(&[1]);
+        assert!(held.must_hold(&account(1, None, 1), &cache));
+        assert!(!held.must_hold(&account(2, None, 1), &cache));
+    }
+
+    #[test]
+    fn releases_updates_in_order_once_their_transaction_arrives() {
+        let mut held = HeldAccounts::new(Duration::from_secs(60));
+        held.hold(account(1, Some(1), 0), false);
+        held.hold(account(1, None, 1), false);
+        held.hold(account(1, Some(2), 2), false);
+        held.hold(account(2, Some(1), 3), false);
+
+        let released = held.release_awaiting(&signature(1), &cache(&[1]));
+        assert_eq!(write_versions(&released), [0, 1, 3]);
+        // still waiting for the second transaction
+        assert_eq!(write_versions(&held.release_all()), [2]);
+    }
+
+    #[test]
+    fn releases_updates_without_their_transaction_once_expired() {
+        let mut held = HeldAccounts::new(Duration::from_secs(60));
+        held.hold(account(1, Some(1), 0), false);
+        held.hold(account(1, Some(2), 1), false);
+        let deadline = held.next_deadline().expect("updates are held");
+
+        assert!(held.release_expired(&cache(&[]), deadline - Duration::from_secs(1)).is_empty());
+        assert_eq!(write_versions(&held.release_expired(&cache(&[]), deadline + Duration::from_secs(1))), [0, 1]);
+        assert!(held.next_deadline().is_none());
+    }
+
+    #[test]
+    fn releases_everything_in_arrival_order() {
+        let mut held = HeldAccounts::new(Duration::from_secs(60));
+        held.hold(account(2, Some(1), 0), false);
+        held.hold(account(1, Some(2), 1), true);
+        held.hold(account(2, None, 2), false);
+
+        let released = held.release_all();
+        assert_eq!(write_versions(&released), [0, 1, 2]);
+        assert!(released[1].is_startup);
+        assert!(held.release_awaiting(&signature(1), &cache(&[1])).is_empty());
+    }
+}
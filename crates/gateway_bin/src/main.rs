@@ -1,27 +1,58 @@
+mod admin;
+mod block_assembler;
+mod commitment;
+mod compat;
+mod forks;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod held_accounts;
+mod plugin_loader;
+mod plugin_settings;
+mod plugin_worker;
 mod replicator;
+mod sandbox;
+mod slot_order;
+mod snapshot;
 mod transaction_cache;
+mod upstreams;
+mod verification;
+#[cfg(feature = "websocket")]
+mod websocket;
 
 use clap::Parser;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use rustls::crypto::ring::default_provider;
+use tokio::signal::unix::{signal, SignalKind};
 use tracing_subscriber;
-use transport::{metrics::TransportMetrics, ReplicaChannels, TransportOpts};
+use anyhow::Context;
+use transport::server::{ServerShutdown, TransportServer, TransportServerConfig};
+use transport::{metrics::TransportMetrics, ReplicaChannels, Subscription, TransportOpts};
 use opentelemetry::metrics::MeterProvider;
-use solana_geyser_plugin_manager::geyser_plugin_manager::GeyserPluginManager;
 use tracing::log;
-use transport::metrics::{init_metrics, StreamMetricHelper};
+use transport::metrics::init_metrics;
+use crate::plugin_loader::PluginLoader;
 use crate::replicator::Replicator;
+use crate::upstreams::Upstreams;
+
+/// Time on top of the shutdown timeout for plugins to return from the notification they are in and from `on_unload`
+const UNLOAD_GRACE_PERIOD: Duration = Duration::from_secs(10);
 
 #[derive(Parser)]
 #[command(name = "ample-geyser-gateway")]
 #[command(about = "Gateway for Ample Geyser proxy", long_about = None)]
 struct Args {
-    /// Ample proxy server address to connect to
-    #[arg(long, value_name = "ADDR")]
-    upstream_proxy_addr: SocketAddr,
+    /// Ample proxy server address to connect to. Can be given multiple times to merge the notifications of redundant
+    /// proxies, delivering whichever copy arrives first
+    #[arg(long, value_name = "ADDR", required = true)]
+    upstream_proxy_addr: Vec<SocketAddr>,
+
+    /// How many recently delivered notifications per stream are remembered to drop the copies of other upstreams
+    #[arg(long, default_value_t = 1_000_000)]
+    upstream_dedup_capacity: usize,
 
     /// Fully qualified domain name
     #[arg(long, value_name = "FQDN")]
@@ -40,19 +71,131 @@ struct Args {
     geyser_plugin_config: Vec<String>,
     
     #[arg(long, default_value_t = 100_000)]
-    transaction_cache_size: usize
+    transaction_cache_size: usize,
+
+    /// How long an account update is held back waiting for the transaction it references, in milliseconds.
+    /// Transactions arrive on a separate stream and can be late, 0 delivers account updates right away without them
+    #[arg(long, default_value_t = 0)]
+    account_txn_wait_ms: u64,
+
+    /// Deliver block metadata and Processed/Confirmed/Rooted slot status only after the transactions and entries of
+    /// their slot, waiting at most this many milliseconds per slot. 0 delivers everything as it arrives
+    #[arg(long, default_value_t = 0)]
+    slot_order_budget_ms: u64,
+
+    /// Collect the transactions, entries and block metadata of every slot into complete blocks for the gRPC and
+    /// WebSocket APIs. Subscribes to transactions and entries upstream even if no plugin wants them
+    #[arg(long)]
+    assemble_blocks: bool,
+
+    /// How long the replicator keeps polling for notifications before parking its thread, in microseconds.
+    /// Spinning lowers delivery latency after quiet periods at the cost of a busy core, 0 parks right away
+    #[arg(long, default_value_t = 0)]
+    replicator_spin_us: u64,
+
+    /// Geyser plugin config of a plugin to run in its own supervised child process, so a crashing plugin doesn't
+    /// take down the gateway. Can be given multiple times
+    #[arg(long, value_name = "PATH")]
+    sandboxed_geyser_plugin_config: Vec<String>,
+
+    /// Delay before restarting a crashed sandboxed plugin, doubled on every consecutive crash
+    #[arg(long, default_value_t = 500)]
+    sandbox_restart_backoff_ms: u64,
+
+    /// Upper bound of the sandboxed plugin restart delay
+    #[arg(long, default_value_t = 30_000)]
+    sandbox_max_restart_backoff_ms: u64,
+
+    /// JSON file with gateway side settings per plugin name, e.g. the interface versions a plugin is called with
+    #[arg(long, value_name = "PATH")]
+    plugin_settings: Option<PathBuf>,
+
+    /// How long a shutdown waits for notifications already received to be delivered before unloading the plugins
+    #[arg(long, default_value_t = 30)]
+    shutdown_timeout_secs: u64,
+
+    /// Unix socket of the admin interface to list, load, unload and reload plugins at runtime, disabled if unset
+    #[arg(long, value_name = "PATH")]
+    admin_socket: Option<PathBuf>,
+
+    /// Snapshot archive (.tar.zst or .tar) or unpacked snapshot directory to bootstrap plugin account state from,
+    /// instead of relying on startup account notifications from the validator
+    #[arg(long, value_name = "PATH")]
+    snapshot_archive: Option<PathBuf>,
+
+    /// Directory the snapshot archive is unpacked into, a temporary directory is used and removed afterwards if unset
+    #[arg(long, value_name = "PATH")]
+    snapshot_unpack_dir: Option<PathBuf>,
+
+    /// Maximum number of live updates buffered while the snapshot is being loaded
+    #[arg(long, default_value_t = 10_000_000)]
+    snapshot_live_buffer_limit: usize,
+
+    /// Deliver only the newest version of every snapshot account and skip deleted ones, like the validator's startup
+    /// notifications. Remembers every account's pubkey while loading, tens of GB for a mainnet snapshot. Without it
+    /// every stored version is delivered, oldest first
+    #[arg(long)]
+    snapshot_newest_only: bool,
+
+    /// Address to re-serve the upstream feed on, so downstream gateways can connect to this gateway the same way as to
+    /// a proxy. Subscribes to every stream upstream, disabled if unset
+    #[arg(long, value_name = "ADDR", requires_all = ["relay_cert_path", "relay_key_path"])]
+    relay_bind_addr: Option<SocketAddr>,
+
+    /// Certificate the relay presents to downstream gateways
+    #[arg(long, value_name = "PATH")]
+    relay_cert_path: Option<PathBuf>,
+
+    /// Private key of the relay certificate
+    #[arg(long, value_name = "PATH")]
+    relay_key_path: Option<PathBuf>,
+
+    /// Compress the relayed streams with lz4
+    #[arg(long, conflicts_with = "relay_zstd_compression")]
+    relay_lz4_compression: bool,
+
+    /// Compress the relayed streams with zstd
+    #[arg(long)]
+    relay_zstd_compression: bool,
+
+    /// Address of the gRPC streaming API, disabled if unset. Subscribes to every stream upstream
+    #[cfg(feature = "grpc")]
+    #[arg(long, value_name = "ADDR")]
+    grpc_bind_addr: Option<SocketAddr>,
+
+    /// Address of the WebSocket JSON API, disabled if unset. Subscribes to every stream upstream
+    #[cfg(feature = "websocket")]
+    #[arg(long, value_name = "ADDR")]
+    websocket_bind_addr: Option<SocketAddr>,
+
+    /// Notifications sent to a WebSocket client per second, further ones are dropped. 0 is unlimited
+    #[cfg(feature = "websocket")]
+    #[arg(long, default_value_t = 1_000)]
+    websocket_max_notifications_per_sec: u32,
+
+    /// Subscriptions a WebSocket client can hold at once
+    #[cfg(feature = "websocket")]
+    #[arg(long, default_value_t = 32)]
+    websocket_max_subscriptions: usize,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
+    if let Some((socket_path, plugin_config, is_reload)) = sandbox::child_args() {
+        // a Ctrl+C or SIGTERM sent to the whole process group is left to the gateway, which stops its children itself
+        let _sigint = signal(SignalKind::interrupt())?;
+        let _sigterm = signal(SignalKind::terminate())?;
+        return tokio::task::spawn_blocking(move || sandbox::run_child(socket_path, plugin_config, is_reload)).await?;
+    }
+
     let args = Args::parse();
 
     let _ = default_provider().install_default();
 
     tracing::info!(
-        upstream_proxy_addr = %args.upstream_proxy_addr,
+        upstream_proxy_addr = ?args.upstream_proxy_addr,
         fqdn = %args.fqdn,
         cert_path = ?args.cert_path,
         "starting ample geyser gateway"
@@ -68,55 +211,265 @@ async fn main() -> anyhow::Result<()> {
         (None, None)
     };
 
+    let mut transaction_cache = transaction_cache::TransactionCache::new(args.transaction_cache_size);
+    let settings_by_name = match &args.plugin_settings {
+        Some(path) => plugin_settings::load_plugin_settings(path)?,
+        None => Default::default(),
+    };
+    let loader = PluginLoader::new(
+        settings_by_name,
+        Duration::from_millis(args.sandbox_restart_backoff_ms),
+        Duration::from_millis(args.sandbox_max_restart_backoff_ms),
+        metrics.clone(),
+    );
+
+    // requests arriving before the replicator runs wait in the channel
+    let admin_requests = match &args.admin_socket {
+        Some(path) => Some(admin::serve(path)?),
+        None => None,
+    };
+
+    let configs = args.geyser_plugin_config.iter().map(|path| (path, false))
+        .chain(args.sandboxed_geyser_plugin_config.iter().map(|path| (path, true)));
+    let mut workers = Vec::new();
+    for (path, sandboxed) in configs {
+        if let Err(e) = loader.load(&mut workers, path, sandboxed) {
+            // the plugins loaded so far are running on their workers already
+            loader.shutdown(workers, Instant::now());
+            return Err(e.context(format!("failed to load geyser plugin {}", path)));
+        }
+    }
+
+    for name in loader.configured_names() {
+        if !workers.iter().any(|worker| worker.name() == name) {
+            log::warn!("plugin settings for {} don't match any loaded plugin", name);
+        }
+    }
+
+    // plugins are loaded before connecting, so streams no plugin wants are never opened upstream. A relay or the gRPC
+    // and WebSocket APIs can't know what their clients want, so they take everything
+    let serves_clients = args.relay_bind_addr.is_some();
+    #[cfg(feature = "grpc")]
+    let serves_clients = serves_clients || args.grpc_bind_addr.is_some();
+    #[cfg(feature = "websocket")]
+    let serves_clients = serves_clients || args.websocket_bind_addr.is_some();
+    let subscription = if serves_clients {
+        // rollbacks are detected by this gateway's own replicator and published from there
+        Subscription { rollbacks: false, ..Subscription::default() }
+    } else {
+        replicator::upstream_subscription(&workers, args.snapshot_archive.is_some(), args.assemble_blocks)
+    };
+    tracing::info!(?subscription, "subscribing to upstream streams");
+
     let replica_channels = ReplicaChannels::with_defaults();
+    let rollbacks = replica_channels.rollback.clone();
+    let assembled_blocks = args.assemble_blocks
+        .then(|| tokio::sync::broadcast::channel(block_assembler::ASSEMBLED_BLOCK_BUFFER).0);
+
+    // bound before connecting, so the lifecycle events upstream replays are replayed to downstream gateways as well
+    let relay = match args.relay_bind_addr {
+        Some(addr) => match start_relay(addr, &args, replica_channels.clone(), metrics.clone()) {
+            Ok(relay) => Some(relay),
+            Err(e) => {
+                loader.shutdown(workers, Instant::now());
+                return Err(e.context("failed to start the relay"));
+            }
+        },
+        None => None,
+    };
 
-    let replica_receivers = transport::client::TransportClient::connect(
-        args.upstream_proxy_addr,
+    #[cfg(feature = "grpc")]
+    let grpc = match args.grpc_bind_addr {
+        Some(addr) => match grpc::GrpcServer::spawn(addr, replica_channels.clone(), assembled_blocks.clone()).await {
+            Ok(grpc) => Some(grpc),
+            Err(e) => {
+                if let Some(relay) = relay {
+                    relay.close("gateway failed to start").await;
+                }
+                loader.shutdown(workers, Instant::now());
+                return Err(e.context("failed to start the gRPC API"));
+            }
+        },
+        None => None,
+    };
+
+    #[cfg(feature = "websocket")]
+    let websocket = match args.websocket_bind_addr {
+        Some(addr) => {
+            let limits = websocket::WebSocketLimits {
+                max_notifications_per_sec: args.websocket_max_notifications_per_sec,
+                max_subscriptions: args.websocket_max_subscriptions,
+            };
+            let spawned = websocket::WebSocketServer::spawn(
+                addr,
+                replica_channels.clone(),
+                assembled_blocks.clone(),
+                limits,
+                metrics.clone(),
+            ).await;
+            match spawned {
+                Ok(websocket) => Some(websocket),
+                Err(e) => {
+                    if let Some(relay) = relay {
+                        relay.close("gateway failed to start").await;
+                    }
+                    #[cfg(feature = "grpc")]
+                    if let Some(grpc) = grpc {
+                        grpc.close().await;
+                    }
+                    loader.shutdown(workers, Instant::now());
+                    return Err(e.context("failed to start the WebSocket API"));
+                }
+            }
+        }
+        None => None,
+    };
+
+    let connected = Upstreams::connect(
+        &args.upstream_proxy_addr,
         TransportOpts {
             cert_path: args.cert_path,
             key_path: None,
             fqdn: args.fqdn,
         },
-        replica_channels.clone(),
+        replica_channels,
+        subscription,
+        args.upstream_dedup_capacity,
         metrics.clone(),
-    ).await?;
-
-    let replicator = Replicator::new(replica_receivers);
-    let transaction_cache = transaction_cache::TransactionCache::new(args.transaction_cache_size);
-    let mut manager = GeyserPluginManager::default();
-
-    for path in args.geyser_plugin_config {
-        log::info!("loading geyser plugin: {}", path);
-        let name = manager.load_plugin(path.clone())?;
-        StreamMetricHelper::record_geyser_plugin_loaded(
-            metrics.as_ref(),
-            &path,
-            &name
-        )
-    }
+    ).await;
+    let (replica_receivers, upstreams) = match connected {
+        Ok(connected) => connected,
+        Err(e) => {
+            // nothing was delivered yet, the plugins only need their `on_unload`
+            loader.shutdown(workers, Instant::now());
+            return Err(e);
+        }
+    };
+
+    let mut replicator = Replicator::new(
+        replica_receivers,
+        workers,
+        subscription,
+        admin_requests,
+        Duration::from_millis(args.account_txn_wait_ms),
+        Duration::from_millis(args.slot_order_budget_ms),
+        assembled_blocks,
+        Duration::from_micros(args.replicator_spin_us),
+        rollbacks,
+        metrics.clone(),
+    );
 
     let shutdown = Arc::new(AtomicBool::new(false));
     let shutdown_clone = shutdown.clone();
 
-    let replicator_handle = tokio::task::spawn_blocking(move || {
-        replicator.run(manager, transaction_cache, shutdown_clone);
+    let snapshot_archive = args.snapshot_archive;
+    let snapshot_unpack_dir = args.snapshot_unpack_dir;
+    let snapshot_live_buffer_limit = args.snapshot_live_buffer_limit;
+    let snapshot_newest_only = args.snapshot_newest_only;
+
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout_secs);
+    let mut replicator_handle = tokio::task::spawn_blocking(move || {
+        if let Some(snapshot_archive) = snapshot_archive {
+            if let Err(e) = replicator.bootstrap_from_snapshot(
+                &mut transaction_cache,
+                &snapshot_archive,
+                snapshot_unpack_dir.as_deref(),
+                snapshot_live_buffer_limit,
+                snapshot_newest_only,
+                &shutdown_clone,
+            ) {
+                replicator.stop(&loader, Instant::now() + shutdown_timeout);
+                return Err(e.context(format!("failed to bootstrap from snapshot {}", snapshot_archive.display())));
+            }
+        }
+        replicator.run(loader, transaction_cache, shutdown_clone, shutdown_timeout);
+        Ok(())
     });
 
+    let mut sigterm = signal(SignalKind::terminate())?;
     tokio::select! {
         _ = tokio::signal::ctrl_c() => {
             tracing::info!("received Ctrl+C, initiating shutdown");
         }
-        result = replicator_handle => {
-            match result {
-                Ok(()) => tracing::info!("replicator finished"),
-                Err(e) => tracing::error!("replicator task panicked: {}", e),
+        _ = sigterm.recv() => {
+            tracing::info!("received SIGTERM, initiating shutdown");
+        }
+        result = &mut replicator_handle => {
+            // a replicator that failed has already unloaded the plugins, the gateway exits with its error
+            let result = match result {
+                Ok(Ok(())) => {
+                    tracing::info!("replicator finished");
+                    Ok(())
+                }
+                Ok(Err(e)) => Err(e),
+                Err(e) => Err(anyhow::anyhow!("replicator task panicked: {}", e)),
+            };
+            upstreams.close("gateway stopped").await;
+            if let Some(relay) = relay {
+                relay.close("gateway stopped").await;
+            }
+            #[cfg(feature = "grpc")]
+            if let Some(grpc) = grpc {
+                grpc.close().await;
+            }
+            #[cfg(feature = "websocket")]
+            if let Some(websocket) = websocket {
+                websocket.close().await;
             }
-            return Ok(());
+            return result;
         }
     }
 
+    // stop receiving first, what was already received is still delivered
+    upstreams.close("gateway shutting down").await;
+    if let Some(relay) = relay {
+        relay.close("gateway shutting down").await;
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = grpc {
+        grpc.close().await;
+    }
+    #[cfg(feature = "websocket")]
+    if let Some(websocket) = websocket {
+        websocket.close().await;
+    }
     shutdown.store(true, Ordering::Relaxed);
-    tracing::info!("shutdown signal sent, waiting for replicator to finish");
+    tracing::info!(timeout = ?shutdown_timeout, "upstreams closed, waiting for plugins to catch up");
+
+    match tokio::time::timeout(shutdown_timeout + UNLOAD_GRACE_PERIOD, replicator_handle).await {
+        Ok(Ok(Ok(()))) => {
+            tracing::info!("shutdown complete");
+            Ok(())
+        }
+        Ok(Ok(Err(e))) => Err(e),
+        Ok(Err(e)) => Err(anyhow::anyhow!("replicator task panicked: {}", e)),
+        Err(_) => {
+            // the runtime would wait for the stuck replicator forever when dropped
+            tracing::error!("plugins didn't unload in time, exiting anyway");
+            std::process::exit(1);
+        }
+    }
+}
 
-    Ok(())
+/// Re-serves everything received from upstream to downstream gateways
+fn start_relay(
+    addr: SocketAddr,
+    args: &Args,
+    replica_channels: ReplicaChannels,
+    metrics: Option<Arc<TransportMetrics>>,
+) -> anyhow::Result<ServerShutdown> {
+    let opts = TransportOpts {
+        cert_path: args.relay_cert_path.clone().context("--relay-cert-path is required")?,
+        key_path: args.relay_key_path.clone(),
+        fqdn: String::new(),
+    };
+    let config = TransportServerConfig {
+        use_lz4_compression: args.relay_lz4_compression,
+        use_zstd_compression: args.relay_zstd_compression,
+    };
+    let server = TransportServer::bind(addr, opts, config, replica_channels, metrics)?;
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(server.serve());
+    tracing::info!(%addr, "relaying the upstream feed to downstream gateways");
+    Ok(shutdown)
 }
@@ -0,0 +1,175 @@
+//! Loads plugins onto their workers and unloads them again, at startup as well as at runtime through the admin API.
+
+use crate::plugin_settings::PluginSettings;
+use crate::plugin_worker::PluginWorker;
+use crate::sandbox::{self, SandboxConfig, SandboxSupervisor};
+use agave_geyser_plugin_interface::geyser_plugin_interface::GeyserPlugin;
+use anyhow::{bail, Context};
+use solana_geyser_plugin_manager::geyser_plugin_manager::{GeyserPluginManager, LoadedGeyserPlugin};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::log;
+use transport::metrics::{StreamMetricHelper, TransportMetrics};
+
+/// Symbol every geyser plugin library exports to create its plugin
+type PluginConstructor = unsafe fn() -> *mut dyn GeyserPlugin;
+
+/// Keeps the code of an in-process plugin loaded, it has to be dropped after the plugin
+pub enum PluginLibrary {
+    /// The manager that loaded the plugin, holding nothing else
+    Manager(GeyserPluginManager),
+    /// Loaded by the gateway itself, for a reloaded plugin
+    Library(libloading::Library),
+}
+
+/// Loads the plugin of a geyser plugin config and calls its `on_load`. A [`GeyserPluginManager`] only tells a plugin
+/// it's reloaded when it reloads a plugin it loaded itself, but every plugin of the gateway has a manager of its own, so
+/// a reloaded plugin is loaded by the gateway the same way the manager would.
+pub fn load_plugin(config: &str, is_reload: bool) -> anyhow::Result<(LoadedGeyserPlugin, PluginLibrary)> {
+    if !is_reload {
+        let mut manager = GeyserPluginManager::default();
+        manager.load_plugin(config.to_string())?;
+        let plugin = manager.plugins.pop().context("plugin manager didn't load the plugin")?;
+        return Ok((plugin, PluginLibrary::Manager(manager)));
+    }
+
+    let config_path = Path::new(config);
+    let contents = std::fs::read_to_string(config_path)
+        .with_context(|| format!("failed to read geyser plugin config {}", config))?;
+    let parsed: serde_json::Value = json5::from_str(&contents)
+        .with_context(|| format!("failed to parse geyser plugin config {}", config))?;
+    let libpath = parsed["libpath"].as_str().context("geyser plugin config has no libpath")?;
+    // relative to the config, like the validator resolves it
+    let mut libpath = PathBuf::from(libpath);
+    if libpath.is_relative() {
+        libpath = config_path.parent().unwrap_or(Path::new("")).join(libpath);
+    }
+    let name = parsed["name"].as_str().map(str::to_string);
+
+    // SAFETY: the library is a geyser plugin, loaded the same way the validator loads it
+    let library = unsafe { libloading::Library::new(&libpath) }
+        .with_context(|| format!("failed to load geyser plugin library {}", libpath.display()))?;
+    // SAFETY: `_create_plugin` hands over ownership of a boxed plugin, which is dropped before its library
+    let plugin = unsafe {
+        let constructor: libloading::Symbol<PluginConstructor> = library.get(b"_create_plugin")?;
+        Box::from_raw(constructor())
+    };
+    let mut plugin = LoadedGeyserPlugin::new(plugin, name);
+    plugin.setup_logger(log::logger(), log::max_level())?;
+    plugin.on_load(config, true)?;
+    Ok((plugin, PluginLibrary::Library(library)))
+}
+
+/// Every in-process plugin's library is loaded for it alone, it moves onto the plugin's worker and is unloaded along
+/// with the plugin. A plugin reloaded from a rebuilt library then runs the new code.
+pub struct PluginLoader {
+    settings: HashMap<String, PluginSettings>,
+    restart_backoff: Duration,
+    max_restart_backoff: Duration,
+    metrics: Option<Arc<TransportMetrics>>,
+}
+
+impl PluginLoader {
+    pub fn new(
+        settings: HashMap<String, PluginSettings>,
+        restart_backoff: Duration,
+        max_restart_backoff: Duration,
+        metrics: Option<Arc<TransportMetrics>>,
+    ) -> Self {
+        Self {
+            settings,
+            restart_backoff,
+            max_restart_backoff,
+            metrics,
+        }
+    }
+
+    /// Names from the `--plugin-settings` file
+    pub fn configured_names(&self) -> impl Iterator<Item = &str> {
+        self.settings.keys().map(String::as_str)
+    }
+
+    /// Loads the plugin of a geyser plugin config and adds it to the workers, see [`spawn`](Self::spawn) and
+    /// [`add`](Self::add). Returns its name.
+    pub fn load(&self, workers: &mut Vec<PluginWorker>, config: &str, sandboxed: bool) -> anyhow::Result<String> {
+        let worker = self.spawn(config, sandboxed, false)?;
+        self.add(workers, worker, config)
+    }
+
+    /// Loads the plugin of a geyser plugin config onto a new worker, either in-process or in a sandboxed child process.
+    /// Blocks until the plugin's `on_load` returned, for a sandboxed plugin up to its startup timeout. `is_reload` is
+    /// passed on to `on_load`.
+    pub fn spawn(&self, config: &str, sandboxed: bool, is_reload: bool) -> anyhow::Result<PluginWorker> {
+        if sandboxed {
+            self.spawn_sandboxed(config, is_reload)
+        } else {
+            self.spawn_in_process(config, is_reload)
+        }
+    }
+
+    /// Like the validator, a plugin whose name is already taken by a loaded plugin is rejected and unloaded in the
+    /// background. Returns its name.
+    pub fn add(&self, workers: &mut Vec<PluginWorker>, worker: PluginWorker, config: &str) -> anyhow::Result<String> {
+        let name = worker.name().to_string();
+        if workers.iter().any(|loaded| loaded.name() == name) {
+            // every plugin has its own manager, so the clash can't be detected before the plugin's `on_load` ran
+            Self::unload_in_background(worker);
+            bail!("a plugin named {} is already loaded, didn't load {}", name, config);
+        }
+
+        StreamMetricHelper::record_geyser_plugin_loaded(self.metrics.as_ref(), config, &name);
+        workers.push(worker);
+        Ok(name)
+    }
+
+    /// Lets the worker deliver what it has queued, then calls `on_unload` on the plugin. A sandboxed plugin's child
+    /// process calls `on_unload` itself once the gateway closes its connection.
+    pub fn unload(worker: PluginWorker) {
+        worker.join();
+    }
+
+    /// Unloads every plugin. Notifications the plugins haven't caught up with by the deadline are dropped, so every
+    /// plugin still gets its `on_unload`.
+    pub fn shutdown(&self, workers: Vec<PluginWorker>, deadline: Instant) {
+        for worker in workers {
+            worker.join_until(deadline);
+        }
+    }
+
+    fn unload_in_background(worker: PluginWorker) {
+        let name = worker.name().to_string();
+        let spawned = std::thread::Builder::new()
+            .name(format!("ample-unload-{name}"))
+            .spawn(move || Self::unload(worker));
+        if let Err(e) = spawned {
+            // the dropped worker still delivers its queue and unloads the plugin on its own thread
+            tracing::error!(plugin = %name, "failed to spawn unload thread: {}", e);
+        }
+    }
+
+    fn settings_for(&self, name: &str) -> PluginSettings {
+        self.settings.get(name).cloned().unwrap_or_default()
+    }
+
+    fn spawn_in_process(&self, config: &str, is_reload: bool) -> anyhow::Result<PluginWorker> {
+        log::info!("loading geyser plugin: {}", config);
+        let (plugin, library) = load_plugin(config, is_reload)?;
+        let settings = self.settings_for(plugin.name());
+        Ok(PluginWorker::spawn(plugin, library, settings, self.metrics.clone())?)
+    }
+
+    fn spawn_sandboxed(&self, config: &str, is_reload: bool) -> anyhow::Result<PluginWorker> {
+        log::info!("loading sandboxed geyser plugin: {}", config);
+        let config = SandboxConfig {
+            plugin_config: config.to_string(),
+            restart_backoff: self.restart_backoff,
+            max_restart_backoff: self.max_restart_backoff,
+        };
+        let launched = sandbox::launch(&config, is_reload)?;
+        let settings = self.settings_for(launched.plugin_name());
+        let supervisor = SandboxSupervisor::new(config, launched, settings.interface, self.metrics.clone())?;
+        Ok(PluginWorker::spawn_sandboxed(supervisor, settings, self.metrics.clone())?)
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+/// Gateway side settings of a loaded plugin, keyed by plugin name in the `--plugin-settings` file.
+/// These live outside the plugin's own config file since many plugins reject unknown config fields.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginSettings {
+    /// Interface versions the plugin is called with, for plugins built against older
+    /// `agave-geyser-plugin-interface` releases
+    pub interface: InterfaceVersions,
+    /// Number of notifications queued for the plugin's worker thread
+    pub queue_size: usize,
+    /// What happens to new notifications while the plugin's queue is full
+    pub overflow_policy: OverflowPolicy,
+    /// Slot data is only delivered once its slot reached this commitment
+    pub commitment: Commitment,
+}
+
+impl Default for PluginSettings {
+    fn default() -> Self {
+        Self {
+            interface: InterfaceVersions::default(),
+            queue_size: 65_536,
+            overflow_policy: OverflowPolicy::default(),
+            commitment: Commitment::default(),
+        }
+    }
+}
+
+/// Startup accounts and the end of startup are never dropped, whatever the policy
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wait for the plugin to catch up, holding up the other plugins and eventually the upstream streams
+    #[default]
+    Block,
+    /// Drop the notification that doesn't fit anymore
+    DropNewest,
+    /// Drop the oldest queued notification to make room
+    DropOldest,
+}
+
+/// Accounts, transactions, entries and block metadata of a slot are buffered until the slot reaches the commitment and
+/// are discarded if the slot dies or is skipped. Slot status and startup accounts are always delivered right away.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Commitment {
+    #[default]
+    Processed,
+    /// Released once the slot is optimistically confirmed
+    Confirmed,
+    /// Released once the slot is rooted
+    Finalized,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InterfaceVersions {
+    pub account: AccountInterfaceVersion,
+    pub transaction: TransactionInterfaceVersion,
+    pub entry: EntryInterfaceVersion,
+    pub block: BlockInterfaceVersion,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub enum AccountInterfaceVersion {
+    V0_0_1,
+    V0_0_2,
+    #[default]
+    V0_0_3,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub enum TransactionInterfaceVersion {
+    V0_0_1,
+    V0_0_2,
+    #[default]
+    V0_0_3,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub enum EntryInterfaceVersion {
+    V0_0_1,
+    #[default]
+    V0_0_2,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub enum BlockInterfaceVersion {
+    V0_0_1,
+    V0_0_2,
+    V0_0_3,
+    #[default]
+    V0_0_4,
+}
+
+pub fn load_plugin_settings(path: &Path) -> anyhow::Result<HashMap<String, PluginSettings>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&contents)?)
+}
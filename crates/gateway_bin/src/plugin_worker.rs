@@ -0,0 +1,426 @@
+//! Runs every loaded plugin on its own thread behind a bounded queue, so one slow plugin doesn't hold up the others.
+//! Each plugin sees its notifications in the order the replicator dispatched them.
+
+use crate::compat;
+use crate::compat::SharedTransaction;
+use crate::plugin_loader::PluginLibrary;
+use crate::plugin_settings::{Commitment, InterfaceVersions, OverflowPolicy, PluginSettings};
+use crate::sandbox::SandboxSupervisor;
+use serde::{Deserialize, Serialize};
+use solana_clock::Slot;
+use solana_geyser_plugin_manager::geyser_plugin_manager::LoadedGeyserPlugin;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use transport::metrics::{StreamMetricHelper, TransportMetrics};
+use transport::{UniformAccountInfo, UniformBlockInfo, UniformEntryInfo, UniformSlotInfo};
+
+/// How often the queue depth and delivery lag of a plugin are reported
+const LAG_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A notification dispatched to the plugin workers, shared between all of them
+#[derive(Clone)]
+pub enum PluginEvent {
+    Account {
+        replica: Arc<UniformAccountInfo>,
+        /// The transaction that caused the update, if it was still in the transaction cache
+        txn: Option<Arc<SharedTransaction>>,
+        is_startup: bool,
+    },
+    Transaction(Arc<SharedTransaction>),
+    Block(Arc<UniformBlockInfo>),
+    Entry(Arc<UniformEntryInfo>),
+    Slot(Arc<UniformSlotInfo>),
+    EndOfStartup,
+}
+
+impl PluginEvent {
+    /// The slot of accounts, transactions, entries and block metadata, the data gated by a plugin's commitment
+    pub fn data_slot(&self) -> Option<Slot> {
+        match self {
+            PluginEvent::Account { replica, is_startup: false, .. } => Some(replica.slot),
+            PluginEvent::Transaction(txn) => Some(txn.replica.slot),
+            PluginEvent::Entry(replica) => Some(replica.slot),
+            PluginEvent::Block(replica) => Some(replica.slot),
+            _ => None,
+        }
+    }
+
+    /// Startup state is never dropped by an overflow policy, a plugin missing part of it would never recover
+    fn droppable(&self) -> bool {
+        !matches!(self, PluginEvent::Account { is_startup: true, .. } | PluginEvent::EndOfStartup)
+    }
+}
+
+/// Which notifications a plugin wants, gating delivery the same way the validator does.
+/// Block metadata and slot status notifications are always delivered.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct NotificationFlags {
+    pub accounts: bool,
+    pub startup_accounts: bool,
+    pub transactions: bool,
+    pub entries: bool,
+}
+
+impl NotificationFlags {
+    pub fn of(plugin: &LoadedGeyserPlugin) -> Self {
+        Self {
+            accounts: plugin.account_data_notifications_enabled(),
+            startup_accounts: plugin.account_data_snapshot_notifications_enabled(),
+            transactions: plugin.transaction_notifications_enabled(),
+            entries: plugin.entry_notifications_enabled(),
+        }
+    }
+
+    fn wants(&self, event: &PluginEvent) -> bool {
+        match event {
+            PluginEvent::Account { is_startup: true, .. } => self.startup_accounts,
+            PluginEvent::Account { is_startup: false, .. } => self.accounts,
+            PluginEvent::Transaction(_) => self.transactions,
+            PluginEvent::Entry(_) => self.entries,
+            PluginEvent::Block(_) | PluginEvent::Slot(_) | PluginEvent::EndOfStartup => true,
+        }
+    }
+}
+
+struct QueuedEvent {
+    event: PluginEvent,
+    queued_at: Instant,
+}
+
+struct QueueState {
+    events: VecDeque<QueuedEvent>,
+    /// Set once either side is gone, events pushed afterwards are discarded
+    closed: bool,
+}
+
+struct EventQueue {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+}
+
+impl EventQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                events: VecDeque::with_capacity(capacity.min(4096)),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Returns how many events were dropped to make room or because the queue was full
+    fn push(&self, event: PluginEvent, policy: OverflowPolicy) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let mut dropped = 0;
+
+        while !state.closed && state.events.len() >= self.capacity {
+            if event.droppable() {
+                match policy {
+                    OverflowPolicy::Block => {}
+                    OverflowPolicy::DropNewest => return 1,
+                    OverflowPolicy::DropOldest => {
+                        if let Some(oldest) = state.events.iter().position(|queued| queued.event.droppable()) {
+                            state.events.remove(oldest);
+                            dropped += 1;
+                            continue;
+                        }
+                    }
+                }
+            }
+            state = self.not_full.wait(state).unwrap();
+        }
+        if state.closed {
+            return dropped;
+        }
+
+        state.events.push_back(QueuedEvent {
+            event,
+            queued_at: Instant::now(),
+        });
+        drop(state);
+        self.not_empty.notify_one();
+        dropped
+    }
+
+    /// Blocks until an event is available, returns `None` once the queue is closed and drained
+    fn pop(&self) -> Option<(QueuedEvent, usize)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(queued) = state.events.pop_front() {
+                let depth = state.events.len();
+                drop(state);
+                self.not_full.notify_one();
+                return Some((queued, depth));
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    /// Drops everything still queued, returns how many events that were
+    fn discard(&self) -> usize {
+        let discarded = std::mem::take(&mut self.state.lock().unwrap().events).len();
+        self.not_full.notify_all();
+        discarded
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+}
+
+/// Closes the queue when the worker thread exits, including when a plugin panics, so the replicator never blocks on
+/// a queue nobody drains anymore
+struct CloseOnExit(Arc<EventQueue>);
+
+impl Drop for CloseOnExit {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Where a worker delivers its plugin's notifications
+enum Target {
+    InProcess {
+        plugin: LoadedGeyserPlugin,
+        interface: InterfaceVersions,
+        /// Holds only the plugin's library, so unloading the plugin unloads its code and a reload picks up a rebuild
+        library: PluginLibrary,
+    },
+    Sandboxed(SandboxSupervisor),
+}
+
+impl Target {
+    /// Returns whether the notification reached the plugin. `flush` is set when nothing else is queued right now.
+    fn deliver(&mut self, event: &PluginEvent, flush: bool) -> bool {
+        match self {
+            Target::InProcess { plugin, interface, .. } => {
+                deliver(plugin, interface, event);
+                true
+            }
+            Target::Sandboxed(supervisor) => supervisor.deliver(event, flush),
+        }
+    }
+
+    /// Calls `on_unload` on an in-process plugin and unloads its library. A sandboxed plugin's child process calls
+    /// `on_unload` itself once its connection is closed.
+    fn finish(self) {
+        match self {
+            Target::InProcess { mut plugin, library, .. } => {
+                plugin.on_unload();
+                // the plugin has to be dropped before its library is unloaded
+                drop(plugin);
+                drop(library);
+            }
+            Target::Sandboxed(supervisor) => supervisor.stop(),
+        }
+    }
+}
+
+pub struct PluginWorker {
+    name: String,
+    queue: Arc<EventQueue>,
+    flags: NotificationFlags,
+    overflow_policy: OverflowPolicy,
+    commitment: Commitment,
+    sandboxed: bool,
+    handle: Option<JoinHandle<()>>,
+    metrics: Option<Arc<TransportMetrics>>,
+}
+
+impl PluginWorker {
+    /// Runs an in-process plugin, `library` holds only the plugin's code
+    pub fn spawn(
+        plugin: LoadedGeyserPlugin,
+        library: PluginLibrary,
+        settings: PluginSettings,
+        metrics: Option<Arc<TransportMetrics>>,
+    ) -> std::io::Result<Self> {
+        let name = plugin.name().to_string();
+        let flags = NotificationFlags::of(&plugin);
+        let target = Target::InProcess { plugin, interface: settings.interface, library };
+        Self::start(name, flags, &settings, target, metrics)
+    }
+
+    /// Runs a plugin that was loaded in a supervised child process, see [`SandboxSupervisor`]
+    pub fn spawn_sandboxed(
+        supervisor: SandboxSupervisor,
+        settings: PluginSettings,
+        metrics: Option<Arc<TransportMetrics>>,
+    ) -> std::io::Result<Self> {
+        let name = supervisor.name().to_string();
+        let flags = supervisor.flags();
+        Self::start(name, flags, &settings, Target::Sandboxed(supervisor), metrics)
+    }
+
+    fn start(
+        name: String,
+        flags: NotificationFlags,
+        settings: &PluginSettings,
+        target: Target,
+        metrics: Option<Arc<TransportMetrics>>,
+    ) -> std::io::Result<Self> {
+        let queue = Arc::new(EventQueue::new(settings.queue_size));
+        let sandboxed = matches!(target, Target::Sandboxed(_));
+
+        let worker_name = name.clone();
+        let worker_queue = queue.clone();
+        let worker_metrics = metrics.clone();
+        let handle = std::thread::Builder::new()
+            .name(format!("ample-plugin-{name}"))
+            .spawn(move || Self::work(worker_name, target, worker_queue, worker_metrics))?;
+
+        Ok(Self {
+            name,
+            queue,
+            flags,
+            overflow_policy: settings.overflow_policy,
+            commitment: settings.commitment,
+            sandboxed,
+            handle: Some(handle),
+            metrics,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_sandboxed(&self) -> bool {
+        self.sandboxed
+    }
+
+    pub fn commitment(&self) -> Commitment {
+        self.commitment
+    }
+
+    pub fn flags(&self) -> NotificationFlags {
+        self.flags
+    }
+
+    /// Queues the event if the plugin wants it, applying the plugin's overflow policy when its queue is full
+    pub fn send(&self, event: &PluginEvent) {
+        if !self.flags.wants(event) {
+            return;
+        }
+        let dropped = self.queue.push(event.clone(), self.overflow_policy);
+        if dropped > 0 {
+            StreamMetricHelper::record_plugin_events_dropped(self.metrics.as_ref(), &self.name, dropped);
+        }
+    }
+
+    /// Lets the worker deliver everything still queued, then unloads the plugin
+    pub fn join(mut self) {
+        self.queue.close();
+        let Some(handle) = self.handle.take() else {
+            return;
+        };
+        if handle.join().is_err() {
+            tracing::error!("worker of plugin {} panicked", self.name);
+        }
+        tracing::info!(plugin = %self.name, "unloaded plugin");
+    }
+
+    /// Like [`join`](Self::join), but what is still queued once the deadline passed is dropped instead of delivered
+    pub fn join_until(self, deadline: Instant) {
+        self.queue.close();
+        if let Some(handle) = &self.handle {
+            while !handle.is_finished() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        let discarded = self.queue.discard();
+        if discarded > 0 {
+            tracing::warn!(plugin = %self.name, discarded, "plugin didn't catch up in time, dropped its queued notifications");
+            StreamMetricHelper::record_plugin_events_dropped(self.metrics.as_ref(), &self.name, discarded as u64);
+        }
+        self.join()
+    }
+
+    fn work(
+        name: String,
+        mut target: Target,
+        queue: Arc<EventQueue>,
+        metrics: Option<Arc<TransportMetrics>>,
+    ) {
+        let _close_on_exit = CloseOnExit(queue.clone());
+        let mut max_lag = Duration::ZERO;
+        let mut last_report = Instant::now();
+
+        while let Some((queued, depth)) = queue.pop() {
+            max_lag = max_lag.max(queued.queued_at.elapsed());
+            if !target.deliver(&queued.event, depth == 0) {
+                StreamMetricHelper::record_plugin_events_dropped(metrics.as_ref(), &name, 1);
+            }
+
+            if last_report.elapsed() >= LAG_REPORT_INTERVAL {
+                StreamMetricHelper::record_plugin_lag(metrics.as_ref(), &name, depth as u64, max_lag);
+                max_lag = Duration::ZERO;
+                last_report = Instant::now();
+            }
+        }
+
+        target.finish()
+    }
+}
+
+impl Drop for PluginWorker {
+    fn drop(&mut self) {
+        // a worker dropped without being joined still delivers what it has queued, but in the background
+        self.queue.close();
+    }
+}
+
+/// Calls the plugin for a single notification, shared by in-process workers and sandboxed plugin hosts
+pub fn deliver(plugin: &LoadedGeyserPlugin, interface: &InterfaceVersions, event: &PluginEvent) {
+    let (result, notification) = match event {
+        PluginEvent::Account { replica, txn, is_startup } => (
+            compat::update_account(
+                plugin,
+                interface.account,
+                replica,
+                txn.as_deref().and_then(SharedTransaction::sanitized),
+                *is_startup,
+            ),
+            "account",
+        ),
+        PluginEvent::Transaction(txn) => (
+            compat::notify_transaction(plugin, interface.transaction, txn),
+            "transaction",
+        ),
+        PluginEvent::Block(replica) => (
+            compat::notify_block_metadata(plugin, interface.block, replica),
+            "block",
+        ),
+        PluginEvent::Entry(replica) => (
+            compat::notify_entry(plugin, interface.entry, replica),
+            "entry",
+        ),
+        PluginEvent::Slot(replica) => (
+            plugin.update_slot_status(replica.slot, replica.parent, &replica.status),
+            "slot",
+        ),
+        PluginEvent::EndOfStartup => (plugin.notify_end_of_startup(), "end of startup"),
+    };
+
+    if let Err(err) = result {
+        tracing::error!(
+            "Error processing {} replica in plugin {}: {:?}",
+            notification,
+            plugin.name(),
+            err
+        );
+    }
+}
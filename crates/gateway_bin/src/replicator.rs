@@ -1,224 +1,966 @@
+use crate::snapshot::{NewestAccounts, Snapshot};
 use crate::transaction_cache::TransactionCache;
-use agave_geyser_plugin_interface::geyser_plugin_interface::{
-    ReplicaAccountInfoV3, ReplicaAccountInfoVersions, ReplicaBlockInfoV4, ReplicaBlockInfoVersions,
-    ReplicaEntryInfoV2, ReplicaEntryInfoVersions, ReplicaTransactionInfoV3,
-    ReplicaTransactionInfoVersions,
-};
-use solana_geyser_plugin_manager::geyser_plugin_manager::GeyserPluginManager;
-use solana_message::AddressLoader;
-use solana_message::v0::{LoadedAddresses, MessageAddressTableLookup};
-use solana_transaction::sanitized::SanitizedTransaction;
-use std::collections::HashSet;
+use crate::verification::VerificationIssue;
+use crate::admin::{AdminCommand, AdminRequest, AdminResponse};
+use crate::block_assembler::{BlockAssembler, CompleteBlock};
+use crate::commitment::CommitmentGate;
+use crate::compat::SharedTransaction;
+use crate::forks::ForkTracker;
+use crate::held_accounts::{HeldAccount, HeldAccounts};
+use crate::slot_order::SlotOrder;
+use crate::plugin_loader::PluginLoader;
+use crate::plugin_settings::Commitment;
+use crate::plugin_worker::{NotificationFlags, PluginEvent, PluginWorker};
+use agave_geyser_plugin_interface::geyser_plugin_interface::SlotStatus;
+use anyhow::anyhow;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use tokio::sync::broadcast::error::TryRecvError;
+use std::time::{Duration, Instant};
+use solana_clock::Slot;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use transport::metrics::{StreamMetricHelper, TransportMetrics};
 use transport::{
-    ReplicaReceivers, UniformAccountInfo, UniformBlockInfo, UniformEntryInfo, UniformSlotInfo,
-    UniformTransactionInfo,
+    ReplicaReceivers, Subscription, UniformAccountInfo, UniformBlockInfo, UniformEntryInfo, UniformRollbackInfo,
+    UniformSlotInfo, UniformLifecycleInfo, UniformStartupInfo, UniformTransactionInfo,
 };
 
+/// How long the parked replicator sleeps at most before re-checking the shutdown flag
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the busy/idle ratio is reported
+const UTILIZATION_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the startup stream may stay quiet after the validator finished startup before plugins are told startup is
+/// over without its terminator
+const STARTUP_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Evaluates to whether the receiver had something to handle
 macro_rules! try_recv_and_handle {
     ($receiver:expr, $channel_name:literal, $replica:ident => $ok_handler:expr) => {
         match $receiver.try_recv() {
-            Ok($replica) => $ok_handler,
-            Err(TryRecvError::Empty) => {}
+            Ok($replica) => {
+                $ok_handler;
+                true
+            }
+            Err(TryRecvError::Empty) => false,
             Err(TryRecvError::Lagged(n)) => {
                 tracing::warn!(channel = $channel_name, skipped = n, "receiver lagged, messages dropped");
+                true
             }
             Err(TryRecvError::Closed) => {
                 tracing::error!(channel = $channel_name, "channel closed unexpectedly");
+                false
             }
         }
     };
 }
 
+/// A notification received while the replicator was parked
+enum Notification {
+    Transaction(UniformTransactionInfo),
+    Account(UniformAccountInfo),
+    Block(UniformBlockInfo),
+    Entry(UniformEntryInfo),
+    Slot(UniformSlotInfo),
+    Startup(UniformStartupInfo),
+    Lifecycle(UniformLifecycleInfo),
+}
+
+enum Wakeup {
+    Notification(Notification),
+    Admin(AdminRequest),
+    Loaded(AdminLoad),
+    /// Timed out or lagged, nothing to deliver
+    Idle,
+    Closed(&'static str),
+}
+
+/// A plugin loaded through the admin interface off the replicator thread, waiting to be added to the workers
+struct AdminLoad {
+    worker: anyhow::Result<PluginWorker>,
+    config: String,
+    sandboxed: bool,
+    /// Name of the plugin it replaces, for a reload
+    reloaded: Option<String>,
+    reply: oneshot::Sender<AdminResponse>,
+}
+
+/// Time spent delivering notifications versus spinning or parked waiting for them, reported periodically
+struct Utilization {
+    busy: Duration,
+    idle: Duration,
+    since: Instant,
+}
+
+impl Utilization {
+    fn new() -> Self {
+        Self {
+            busy: Duration::ZERO,
+            idle: Duration::ZERO,
+            since: Instant::now(),
+        }
+    }
+
+    fn report_if_due(&mut self, metrics: Option<&Arc<TransportMetrics>>) {
+        if self.since.elapsed() < UTILIZATION_REPORT_INTERVAL {
+            return;
+        }
+        StreamMetricHelper::record_replicator_utilization(metrics, self.busy, self.idle);
+        *self = Self::new();
+    }
+}
+
 pub struct Replicator {
     replica_receivers: ReplicaReceivers,
+    /// One worker per loaded plugin, each delivering to its plugin on its own thread
+    workers: Vec<PluginWorker>,
+    /// Account updates waiting for their transaction, `None` delivers them right away without it
+    held_accounts: Option<HeldAccounts>,
+    /// Holds back block metadata and slot status until their slot's data was delivered, `None` delivers right away
+    slot_order: Option<SlotOrder>,
+    /// Buffers slot data for plugins with a `confirmed` or `finalized` commitment
+    commitment_gate: CommitmentGate,
+    /// Slot tree after the root, rolls back slots on abandoned forks
+    forks: ForkTracker,
+    /// Rollbacks are published for the relay and the built-in APIs, plugins only learn about the rolled back slots
+    rollbacks: Sender<UniformRollbackInfo>,
+    /// Collects the transactions, entries and metadata of each slot into complete blocks, `None` if disabled
+    block_assembler: Option<BlockAssembler>,
+    /// Assembled blocks are published here for the gRPC and WebSocket APIs
+    assembled_blocks: Option<Sender<Arc<CompleteBlock>>>,
+    /// Upstream streams opened at connect time, plugins loaded later only get notifications from these
+    subscription: Subscription,
+    admin: Option<UnboundedReceiver<AdminRequest>>,
+    /// Plugins are loaded and unloaded on background threads, which can take until a sandboxed plugin's startup
+    /// timeout or until a plugin caught up with its queue. Loaded plugins come back here to be added
+    admin_loaded: UnboundedSender<AdminLoad>,
+    admin_loads: UnboundedReceiver<AdminLoad>,
+    /// Set once account state was bootstrapped from a local snapshot, startup accounts from upstream are redundant then
+    ignore_upstream_startup: bool,
+    /// Plugins get `notify_end_of_startup` exactly once, no matter which source signals it first
+    end_of_startup_notified: bool,
+    /// Set once startup accounts arrived, the end of startup is then signalled by the startup stream's terminator
+    receiving_startup_accounts: bool,
+    /// The validator finished startup while startup accounts were still streaming in, the startup stream gets until
+    /// this deadline, pushed out by every startup account, to deliver its terminator
+    end_of_startup_pending: Option<Instant>,
+    /// How long to keep polling after the last notification before parking the thread, trading CPU for latency
+    spin: Duration,
+    metrics: Option<Arc<TransportMetrics>>,
 }
 
-#[derive(Clone)]
-struct LoadableLoadedAddresses(LoadedAddresses);
+/// The upstream streams needed by the loaded plugins, streams no plugin wants are not opened at all.
+/// `snapshot_bootstrap` is set when startup accounts come from a local snapshot instead of upstream.
+/// `assemble_blocks` needs transactions and entries whether a plugin wants them or not.
+pub fn upstream_subscription(workers: &[PluginWorker], snapshot_bootstrap: bool, assemble_blocks: bool) -> Subscription {
+    let flags: Vec<_> = workers.iter().map(PluginWorker::flags).collect();
+    let accounts = flags.iter().any(|f| f.accounts);
 
-impl AddressLoader for LoadableLoadedAddresses {
-    fn load_addresses(
-        self,
-        _lookups: &[MessageAddressTableLookup],
-    ) -> Result<LoadedAddresses, solana_transaction_error::AddressLoaderError> {
-        Ok(self.0)
+    Subscription {
+        accounts,
+        // account updates reference their transaction, which is looked up in the transaction cache
+        transactions: assemble_blocks || accounts || flags.iter().any(|f| f.transactions),
+        entries: assemble_blocks || flags.iter().any(|f| f.entries),
+        startup: !snapshot_bootstrap && flags.iter().any(|f| f.startup_accounts),
+        // forks are tracked by the replicator itself
+        rollbacks: false,
+    }
+}
+
+/// Live updates received while bootstrapping from a snapshot, they are replayed once the snapshot has been loaded.
+struct LiveBuffer {
+    accounts: Vec<UniformAccountInfo>,
+    transactions: Vec<UniformTransactionInfo>,
+    entries: Vec<UniformEntryInfo>,
+    blocks: Vec<UniformBlockInfo>,
+    slots: Vec<UniformSlotInfo>,
+    remaining: usize,
+    dropped: u64,
+}
+
+impl LiveBuffer {
+    fn new(limit: usize) -> Self {
+        Self {
+            accounts: Vec::new(),
+            transactions: Vec::new(),
+            entries: Vec::new(),
+            blocks: Vec::new(),
+            slots: Vec::new(),
+            remaining: limit,
+            dropped: 0,
+        }
+    }
+}
+
+fn drain_live<T: Clone>(
+    receiver: &mut Receiver<T>,
+    channel_name: &'static str,
+    buffered: &mut Vec<T>,
+    remaining: &mut usize,
+    dropped: &mut u64,
+) {
+    loop {
+        match receiver.try_recv() {
+            Ok(replica) if *remaining > 0 => {
+                buffered.push(replica);
+                *remaining -= 1;
+            }
+            Ok(_) => *dropped += 1,
+            Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => break,
+            Err(TryRecvError::Lagged(n)) => {
+                tracing::warn!(channel = channel_name, skipped = n, "receiver lagged while loading snapshot, messages dropped");
+                *dropped += n;
+            }
+        }
     }
 }
 
 impl Replicator {
-    pub fn new(replica_receivers: ReplicaReceivers) -> Self {
-        Self { replica_receivers }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        mut replica_receivers: ReplicaReceivers,
+        workers: Vec<PluginWorker>,
+        subscription: Subscription,
+        admin: Option<UnboundedReceiver<AdminRequest>>,
+        account_txn_wait: Duration,
+        slot_order_budget: Duration,
+        assembled_blocks: Option<Sender<Arc<CompleteBlock>>>,
+        spin: Duration,
+        rollbacks: Sender<UniformRollbackInfo>,
+        metrics: Option<Arc<TransportMetrics>>,
+    ) -> Self {
+        let (admin_loaded, admin_loads) = tokio::sync::mpsc::unbounded_channel();
+        // rollbacks are published by the replicator itself and never read back, its own receiver would count as a
+        // consumer of the rollback stream
+        replica_receivers.rollback = tokio::sync::broadcast::channel(1).1;
+        Self {
+            replica_receivers,
+            workers,
+            held_accounts: (!account_txn_wait.is_zero()).then(|| HeldAccounts::new(account_txn_wait)),
+            slot_order: (!slot_order_budget.is_zero())
+                .then(|| SlotOrder::new(slot_order_budget, &subscription, metrics.clone())),
+            commitment_gate: CommitmentGate::new(metrics.clone()),
+            forks: ForkTracker::new(metrics.clone()),
+            rollbacks,
+            block_assembler: assembled_blocks.is_some().then(BlockAssembler::default),
+            assembled_blocks,
+            subscription,
+            admin,
+            admin_loaded,
+            admin_loads,
+            ignore_upstream_startup: false,
+            end_of_startup_notified: false,
+            receiving_startup_accounts: false,
+            end_of_startup_pending: None,
+            spin,
+            metrics,
+        }
+    }
+
+    /// Slot data reaches each plugin once its slot reached the plugin's commitment, everything else right away.
+    /// Slot status goes out after the data it released, so plugins see a slot's data before its new status.
+    fn dispatch(&mut self, event: PluginEvent) {
+        if let PluginEvent::Slot(info) = &event {
+            for release in self.commitment_gate.slot_status(info) {
+                self.dispatch_to(release.commitment, &release.events);
+            }
+        }
+
+        let Some(slot) = event.data_slot() else {
+            for worker in &self.workers {
+                worker.send(&event);
+            }
+            return;
+        };
+        let gated = self.workers.iter().any(|worker| worker.commitment() != Commitment::Processed);
+        // without gated plugins nothing needs to be kept for later
+        let reached = if gated { self.commitment_gate.buffer(slot, &event) } else { Some(Commitment::Processed) };
+        for worker in &self.workers {
+            if reached.is_some_and(|reached| worker.commitment() <= reached) {
+                worker.send(&event);
+            }
+        }
+    }
+
+    fn dispatch_to(&self, commitment: Commitment, events: &[PluginEvent]) {
+        for worker in self.workers.iter().filter(|worker| worker.commitment() == commitment) {
+            for event in events {
+                worker.send(event);
+            }
+        }
+    }
+
+    fn dispatch_all(&mut self, events: Vec<PluginEvent>) {
+        for event in events {
+            self.dispatch(event);
+        }
     }
 
     fn notify_account_replica(
-        &self,
-        manager: &GeyserPluginManager,
+        &mut self,
         replica: UniformAccountInfo,
+        is_startup: bool,
         transaction_cache: &mut TransactionCache,
     ) {
-        let txn = if let Some(signature) = &replica.transaction_ref {
-            transaction_cache
-                .take(signature)
-                .map(|txn_info| {
-                    SanitizedTransaction::try_create(
-                        txn_info.transaction,
-                        txn_info.message_hash,
-                        Some(txn_info.is_vote),
-                        LoadableLoadedAddresses(txn_info.transaction_status_meta.loaded_addresses),
-                        &HashSet::new(),
-                    )
-                    .ok()
-                })
-                .flatten()
-        } else {
-            None
-        };
-        for plugin in manager.plugins.iter() {
-            match plugin.update_account(
-                ReplicaAccountInfoVersions::V0_0_3(&ReplicaAccountInfoV3 {
-                    pubkey: replica.pubkey.as_ref(),
-                    lamports: replica.lamports,
-                    owner: replica.owner.as_ref(),
-                    executable: replica.executable,
-                    rent_epoch: replica.rent_epoch,
-                    data: replica.data.as_ref(),
-                    write_version: replica.write_version,
-                    txn: txn.as_ref()
-                }),
-                replica.slot,
-                false,
-            ) {
-                Ok(_) => {}
-                Err(err) => {
-                    tracing::error!(
-                        "Error processing account replica in plugin {}: {:?}",
-                        plugin.name(),
-                        err
-                    );
-                }
+        // the accounts of a slot are only needed for the rollbacks published to the relay and the APIs
+        if !is_startup && self.rollbacks.receiver_count() > 0 {
+            self.forks.account(replica.slot, replica.pubkey);
+        }
+        if let Some(slot_order) = &mut self.slot_order {
+            slot_order.account(replica.slot);
+        }
+        if let Some(held) = &mut self.held_accounts {
+            if held.must_hold(&replica, transaction_cache) {
+                let evicted = held.hold(replica, is_startup);
+                self.dispatch_held(evicted.into_iter().collect(), transaction_cache);
+                return;
             }
         }
+        self.dispatch_account(replica, is_startup, transaction_cache);
+    }
+
+    /// Returns whether the update referenced a transaction that isn't in the cache
+    fn dispatch_account(
+        &mut self,
+        replica: UniformAccountInfo,
+        is_startup: bool,
+        transaction_cache: &mut TransactionCache,
+    ) -> bool {
+        let txn = replica.transaction_ref.as_ref()
+            .and_then(|signature| transaction_cache.get(signature));
+        let missing_txn = replica.transaction_ref.is_some() && txn.is_none();
+        self.dispatch(PluginEvent::Account {
+            replica: Arc::new(replica),
+            txn,
+            is_startup,
+        });
+        missing_txn
     }
 
     fn notify_transaction_replica(
-        &self,
-        manager: &GeyserPluginManager,
+        &mut self,
         replica: UniformTransactionInfo,
         transaction_cache: &mut TransactionCache,
     ) {
-        for plugin in manager.plugins.iter() {
-            match plugin.notify_transaction(
-                ReplicaTransactionInfoVersions::V0_0_3(&ReplicaTransactionInfoV3 {
-                    signature: &replica.signature,
-                    message_hash: &replica.message_hash,
-                    is_vote: replica.is_vote,
-                    transaction: &replica.transaction,
-                    transaction_status_meta: &replica.transaction_status_meta,
-                    index: replica.index,
-                }),
-                replica.slot,
-            ) {
-                Ok(_) => {}
-                Err(err) => {
-                    tracing::error!(
-                        "Error processing transaction replica in plugin {}: {:?}",
-                        plugin.name(),
-                        err
-                    );
-                }
+        let signature = replica.signature;
+        let slot = replica.slot;
+        let txn = Arc::new(SharedTransaction::new(replica, self.metrics.clone()));
+        self.dispatch(PluginEvent::Transaction(txn.clone()));
+        if let Some(assembled) = self.block_assembler.as_mut().and_then(|assembler| assembler.transaction(txn.clone())) {
+            self.emit_block(assembled);
+        }
+        transaction_cache.insert(txn);
+
+        if let Some(slot_order) = &mut self.slot_order {
+            let released = slot_order.transaction(slot);
+            self.dispatch_all(released);
+        }
+
+        if let Some(held) = &mut self.held_accounts {
+            let released = held.release_awaiting(&signature, transaction_cache);
+            self.dispatch_held(released, transaction_cache);
+        }
+    }
+
+    /// Delivers released account updates, those whose transaction still isn't there are counted as fallbacks
+    fn dispatch_held(&mut self, released: Vec<HeldAccount>, transaction_cache: &mut TransactionCache) {
+        let mut fallbacks = 0;
+        for account in released {
+            if self.dispatch_account(account.replica, account.is_startup, transaction_cache) {
+                fallbacks += 1;
+            }
+        }
+        if fallbacks > 0 {
+            StreamMetricHelper::record_account_txn_fallbacks(self.metrics.as_ref(), fallbacks);
+        }
+    }
+
+    fn release_expired_accounts(&mut self, transaction_cache: &mut TransactionCache) {
+        let now = Instant::now();
+        let expired = self.held_accounts.as_ref()
+            .and_then(HeldAccounts::next_deadline)
+            .is_some_and(|deadline| deadline <= now);
+        if let Some(held) = self.held_accounts.as_mut().filter(|_| expired) {
+            let released = held.release_expired(transaction_cache, now);
+            self.dispatch_held(released, transaction_cache);
+        }
+    }
+
+    /// Falls back to the validator's end of startup once the startup stream stayed quiet for too long
+    fn release_pending_end_of_startup(&mut self) {
+        if self.end_of_startup_pending.is_some_and(|deadline| deadline <= Instant::now()) {
+            tracing::warn!("startup stream didn't end after the validator finished startup, ending startup anyway");
+            self.notify_end_of_startup();
+        }
+    }
+
+    /// How long the replicator may park before a held account update or slot or the pending end of startup expires
+    fn park_timeout(&self) -> Duration {
+        let held_account = self.held_accounts.as_ref().and_then(HeldAccounts::next_deadline);
+        let held_slot = self.slot_order.as_ref().and_then(SlotOrder::next_deadline);
+        held_account.into_iter()
+            .chain(held_slot)
+            .chain(self.end_of_startup_pending)
+            .min()
+            .map_or(SHUTDOWN_POLL_INTERVAL, |deadline| {
+                deadline.saturating_duration_since(Instant::now()).min(SHUTDOWN_POLL_INTERVAL)
+            })
+    }
+
+    fn notify_block_replica(&mut self, replica: UniformBlockInfo) {
+        if let Some(parent) = replica.parent_slot {
+            self.forks.block(replica.slot, parent);
+        }
+        let replica = Arc::new(replica);
+        if let Some(assembler) = &mut self.block_assembler {
+            for assembled in assembler.block(replica.clone()) {
+                self.emit_block(assembled);
+            }
+        }
+        match &mut self.slot_order {
+            Some(slot_order) => {
+                let released = slot_order.block(replica);
+                self.dispatch_all(released);
+            }
+            None => self.dispatch(PluginEvent::Block(replica)),
+        }
+    }
+
+    fn notify_entry_replica(&mut self, replica: UniformEntryInfo) {
+        let slot = replica.slot;
+        let replica = Arc::new(replica);
+        if let Some(assembled) = self.block_assembler.as_mut().and_then(|assembler| assembler.entry(replica.clone())) {
+            self.emit_block(assembled);
+        }
+        self.dispatch(PluginEvent::Entry(replica));
+        if let Some(slot_order) = &mut self.slot_order {
+            let released = slot_order.entry(slot);
+            self.dispatch_all(released);
+        }
+    }
+
+    fn notify_slot_replica(&mut self, replica: UniformSlotInfo) {
+        let rollback = self.forks.slot_status(&replica);
+        let dead = matches!(replica.status, SlotStatus::Dead(_)).then_some(replica.slot);
+        if let Some(assembled) = self.block_assembler.as_mut().and_then(|assembler| assembler.slot_status(&replica)) {
+            self.emit_block(assembled);
+        }
+        let replica = Arc::new(replica);
+        match &mut self.slot_order {
+            Some(slot_order) => {
+                let released = slot_order.slot_status(replica);
+                self.dispatch_all(released);
             }
+            None => self.dispatch(PluginEvent::Slot(replica)),
+        }
+        if let Some(rollback) = rollback {
+            self.notify_rollback(rollback, dead);
+        }
+    }
+
+    /// Reports the verification of an assembled block and publishes it for the gRPC and WebSocket APIs
+    fn emit_block(&self, assembled: CompleteBlock) {
+        StreamMetricHelper::record_block_assembled(self.metrics.as_ref(), assembled.is_complete());
+        if assembled.verification.needs_backfill() {
+            let issues: Vec<_> = assembled.verification.issues.iter().map(VerificationIssue::kind).collect();
+            StreamMetricHelper::record_block_verification_failed(self.metrics.as_ref(), &issues);
+            tracing::warn!(
+                slot = assembled.slot,
+                transactions = assembled.transactions.len(),
+                entries = assembled.entries.len(),
+                issues = ?assembled.verification.issues,
+                "slot failed verification, needs a backfill"
+            );
+        } else {
+            tracing::debug!(
+                slot = assembled.slot,
+                transactions = assembled.transactions.len(),
+                entries = assembled.entries.len(),
+                transactions_complete = assembled.transactions_complete,
+                entries_complete = assembled.entries_complete,
+                index_verified = assembled.index_verified,
+                "assembled and verified block"
+            );
         }
-        transaction_cache.insert(replica);
-    }
-
-    fn notify_block_replica(&self, manager: &GeyserPluginManager, replica: UniformBlockInfo) {
-        for plugin in manager.plugins.iter() {
-            match plugin.notify_block_metadata(ReplicaBlockInfoVersions::V0_0_4(
-                &ReplicaBlockInfoV4 {
-                    parent_slot: replica.parent_slot,
-                    parent_blockhash: &replica.parent_blockhash,
-                    slot: replica.slot,
-                    blockhash: &replica.blockhash,
-                    rewards: &replica.rewards,
-                    block_time: replica.block_time,
-                    block_height: replica.block_height,
-                    executed_transaction_count: replica.executed_transaction_count,
-                    entry_count: replica.entry_count,
-                },
-            )) {
-                Ok(_) => {}
-                Err(err) => {
-                    tracing::error!(
-                        "Error processing block replica in plugin {}: {:?}",
-                        plugin.name(),
-                        err
-                    );
+        if let Some(assembled_blocks) = &self.assembled_blocks {
+            // nobody may be subscribed
+            let _ = assembled_blocks.send(Arc::new(assembled));
+        }
+    }
+
+    /// Publishes the rollback with the accounts to restore. The Geyser interface has no rollback notification, plugins
+    /// learn about rolled back slots the same way as about dead slots. The validator already told them about the
+    /// `dead` slot it marked dead itself
+    fn notify_rollback(&mut self, rollback: UniformRollbackInfo, dead: Option<Slot>) {
+        let _ = self.rollbacks.send(rollback.clone());
+        let reason = match rollback.root {
+            Some(root) => format!("rolled back, on a fork abandoned by root {}", root),
+            None => "rolled back, built on a dead slot".to_string(),
+        };
+        for slot in rollback.slots.into_iter().filter(|slot| Some(*slot) != dead) {
+            let status = SlotStatus::Dead(reason.clone());
+            self.dispatch(PluginEvent::Slot(Arc::new(UniformSlotInfo::from_replica(slot, None, status))));
+        }
+    }
+
+    fn release_expired_slots(&mut self) {
+        let Some(slot_order) = &mut self.slot_order else {
+            return;
+        };
+        let now = Instant::now();
+        if slot_order.next_deadline().is_some_and(|deadline| deadline <= now) {
+            let released = slot_order.release_expired(now);
+            self.dispatch_all(released);
+        }
+    }
+
+    fn notify_startup_replica(
+        &mut self,
+        replica: UniformStartupInfo,
+        transaction_cache: &mut TransactionCache,
+    ) {
+        match replica {
+            _ if self.ignore_upstream_startup => {}
+            UniformStartupInfo::Account(account) => {
+                self.receiving_startup_accounts = true;
+                if let Some(deadline) = &mut self.end_of_startup_pending {
+                    *deadline = Instant::now() + STARTUP_DRAIN_TIMEOUT;
                 }
+                self.notify_account_replica(account, true, transaction_cache)
+            }
+            UniformStartupInfo::EndOfStartup => {
+                tracing::info!("upstream finished streaming startup accounts");
+                self.notify_end_of_startup();
             }
         }
     }
 
-    fn notify_entry_replica(&self, manager: &GeyserPluginManager, replica: UniformEntryInfo) {
-        for plugin in manager.plugins.iter() {
-            match plugin.notify_entry(ReplicaEntryInfoVersions::V0_0_2(&ReplicaEntryInfoV2 {
-                slot: replica.slot,
-                index: replica.index,
-                num_hashes: replica.num_hashes,
-                hash: &replica.hash.to_bytes(),
-                executed_transaction_count: replica.executed_transaction_count,
-                starting_transaction_index: replica.starting_transaction_index,
-            })) {
-                Ok(_) => {}
-                Err(err) => {
-                    tracing::error!(
-                        "Error processing entry replica in plugin {}: {:?}",
-                        plugin.name(),
-                        err
-                    );
+    fn notify_lifecycle_replica(&mut self, replica: UniformLifecycleInfo) {
+        match replica {
+            UniformLifecycleInfo::EndOfStartup { startup_accounts_forwarded } => {
+                // while startup accounts are streaming in, wait for the startup stream itself to end so none of the
+                // accounts still in flight on it are delivered after the end of startup, but not forever in case its
+                // terminator never arrives
+                if startup_accounts_forwarded && self.receiving_startup_accounts {
+                    if !self.end_of_startup_notified {
+                        self.end_of_startup_pending = Some(Instant::now() + STARTUP_DRAIN_TIMEOUT);
+                    }
+                    return;
+                }
+                if self.receiving_startup_accounts {
+                    tracing::warn!("upstream dropped startup accounts, plugins don't have the full account state");
                 }
+                tracing::info!("upstream validator finished startup");
+                self.notify_end_of_startup();
             }
         }
     }
 
-    fn notify_slot_replica(&self, manager: &GeyserPluginManager, replica: UniformSlotInfo) {
-        for plugin in manager.plugins.iter() {
-            match plugin.update_slot_status(replica.slot, replica.parent, &replica.status) {
-                Ok(_) => {}
-                Err(err) => {
-                    tracing::error!(
-                        "Error processing slot replica in plugin {}: {:?}",
-                        plugin.name(),
-                        err
-                    );
+    fn notify_end_of_startup(&mut self) {
+        self.end_of_startup_pending = None;
+        if std::mem::replace(&mut self.end_of_startup_notified, true) {
+            return;
+        }
+        self.dispatch(PluginEvent::EndOfStartup);
+    }
+
+    /// Streams the accounts of a local snapshot into the plugins as startup updates, then replays the live updates
+    /// that arrived in the meantime and are newer than the snapshot slot. Afterwards `run` continues with the live feed.
+    /// With `newest_only` plugins get only the newest version of every account, otherwise every stored version oldest
+    /// first, see [`NewestAccounts`] for what the former costs.
+    #[allow(clippy::too_many_arguments)]
+    pub fn bootstrap_from_snapshot(
+        &mut self,
+        transaction_cache: &mut TransactionCache,
+        snapshot_path: &Path,
+        unpack_dir: Option<&Path>,
+        live_buffer_limit: usize,
+        newest_only: bool,
+        shutdown: &AtomicBool,
+    ) -> anyhow::Result<()> {
+        self.ignore_upstream_startup = true;
+        let mut live = LiveBuffer::new(live_buffer_limit);
+
+        let snapshot = match Snapshot::open(snapshot_path, unpack_dir, shutdown, || self.buffer_live(&mut live)) {
+            Ok(snapshot) => snapshot,
+            Err(_) if shutdown.load(Ordering::Relaxed) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        tracing::info!(
+            slot = snapshot.slot,
+            storages = snapshot.storages().len(),
+            "loading accounts from snapshot"
+        );
+
+        let mut loaded: u64 = 0;
+        if newest_only {
+            let mut newest = NewestAccounts::default();
+            // newest storage first, so older versions of an account are recognized as superseded
+            for storage in snapshot.storages().iter().rev() {
+                if shutdown.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                for account in newest.filter(storage.read_accounts()?) {
+                    self.notify_account_replica(account, true, transaction_cache);
+                    loaded += 1;
                 }
+                self.buffer_live(&mut live);
             }
+        } else {
+            // oldest storage first, so the newest version of an account is the last one delivered
+            for storage in snapshot.storages() {
+                if shutdown.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                for account in storage.accounts()? {
+                    self.notify_account_replica(account?, true, transaction_cache);
+                    loaded += 1;
+                }
+                self.buffer_live(&mut live);
+            }
+        }
+        tracing::info!(slot = snapshot.slot, accounts = loaded, "finished loading accounts from snapshot");
+
+        self.notify_end_of_startup();
+        self.replay_live(transaction_cache, live, snapshot.slot);
+
+        Ok(())
+    }
+
+    fn buffer_live(&mut self, live: &mut LiveBuffer) {
+        let receivers = &mut self.replica_receivers;
+        drain_live(&mut receivers.transaction, "transaction", &mut live.transactions, &mut live.remaining, &mut live.dropped);
+        drain_live(&mut receivers.account, "account", &mut live.accounts, &mut live.remaining, &mut live.dropped);
+        drain_live(&mut receivers.block, "block", &mut live.blocks, &mut live.remaining, &mut live.dropped);
+        drain_live(&mut receivers.entry, "entry", &mut live.entries, &mut live.remaining, &mut live.dropped);
+        drain_live(&mut receivers.slot, "slot", &mut live.slots, &mut live.remaining, &mut live.dropped);
+        // the snapshot replaces the upstream startup stream
+        while let Ok(_) | Err(TryRecvError::Lagged(_)) = self.replica_receivers.startup.try_recv() {}
+    }
+
+    fn replay_live(
+        &mut self,
+        transaction_cache: &mut TransactionCache,
+        live: LiveBuffer,
+        snapshot_slot: Slot,
+    ) {
+        if live.dropped > 0 {
+            tracing::warn!(dropped = live.dropped, "live updates were dropped while loading the snapshot, plugin state may have gaps");
+        }
+
+        // the live feed has to pick up right after the snapshot, otherwise the slots in between are missing
+        let earliest = live.transactions.iter().map(|r| r.slot)
+            .chain(live.accounts.iter().map(|r| r.slot))
+            .chain(live.blocks.iter().map(|r| r.slot))
+            .chain(live.entries.iter().map(|r| r.slot))
+            .chain(live.slots.iter().map(|r| r.slot))
+            .min();
+        if let Some(earliest) = earliest.filter(|&earliest| earliest > snapshot_slot + 1) {
+            let missing = earliest - snapshot_slot - 1;
+            tracing::warn!(
+                snapshot_slot,
+                earliest_live_slot = earliest,
+                missing,
+                "live feed starts after the snapshot slot, updates of the slots in between are missing, use a newer snapshot",
+            );
+            StreamMetricHelper::record_snapshot_live_gap(self.metrics.as_ref(), missing);
+        }
+
+        // transactions first, so the account updates referencing them find them in the cache
+        for replica in live.transactions.into_iter().filter(|r| r.slot > snapshot_slot) {
+            self.notify_transaction_replica(replica, transaction_cache);
+        }
+        for replica in live.accounts.into_iter().filter(|r| r.slot > snapshot_slot) {
+            self.notify_account_replica(replica, false, transaction_cache);
+        }
+        for replica in live.blocks.into_iter().filter(|r| r.slot > snapshot_slot) {
+            self.notify_block_replica(replica);
+        }
+        for replica in live.entries.into_iter().filter(|r| r.slot > snapshot_slot) {
+            self.notify_entry_replica(replica);
+        }
+        for replica in live.slots.into_iter().filter(|r| r.slot > snapshot_slot) {
+            self.notify_slot_replica(replica);
         }
     }
 
+    /// Delivers notifications until shutdown. Pending notifications are drained without blocking, one per stream
+    /// and round, in the priority order transaction > account > block > entry > slot. Once everything is drained the
+    /// replicator spins for the configured duration and then parks until the next notification arrives.
     pub fn run(
         mut self,
-        manager: GeyserPluginManager,
+        loader: PluginLoader,
         mut transaction_cache: TransactionCache,
         shutdown: Arc<AtomicBool>,
+        shutdown_timeout: Duration,
     ) {
+        let runtime = tokio::runtime::Handle::current();
+        let loader = Arc::new(loader);
+        let mut utilization = Utilization::new();
+        let mut last_activity = Instant::now();
+
         while !shutdown.load(Ordering::Relaxed) {
-            self.replicate(&manager, &mut transaction_cache);
+            utilization.report_if_due(self.metrics.as_ref());
+            self.release_expired_accounts(&mut transaction_cache);
+            self.release_expired_slots();
+            self.release_pending_end_of_startup();
+            while let Some(request) = self.admin.as_mut().and_then(|admin| admin.try_recv().ok()) {
+                self.handle_admin(&loader, request);
+            }
+            while let Ok(load) = self.admin_loads.try_recv() {
+                self.add_loaded_plugin(&loader, load);
+            }
+
+            let started = Instant::now();
+            if self.replicate(&mut transaction_cache) {
+                last_activity = Instant::now();
+                utilization.busy += last_activity - started;
+                continue;
+            }
+
+            if last_activity.elapsed() < self.spin {
+                std::hint::spin_loop();
+                utilization.idle += started.elapsed();
+                continue;
+            }
+
+            let park_timeout = self.park_timeout();
+            let wakeup = runtime.block_on(Self::wait_for_notification(
+                &mut self.replica_receivers,
+                &mut self.admin,
+                &mut self.admin_loads,
+                park_timeout,
+            ));
+            let woken = Instant::now();
+            utilization.idle += woken - started;
+
+            match wakeup {
+                Wakeup::Notification(notification) => {
+                    self.notify(&mut transaction_cache, notification);
+                    last_activity = Instant::now();
+                    utilization.busy += last_activity - woken;
+                }
+                Wakeup::Admin(request) => self.handle_admin(&loader, request),
+                Wakeup::Loaded(load) => self.add_loaded_plugin(&loader, load),
+                Wakeup::Idle => {}
+                Wakeup::Closed(channel) => {
+                    tracing::error!(channel, "upstream channel closed, stopping replicator");
+                    break;
+                }
+            }
+        }
+
+        // the upstream connection is closed by now, deliver what was received before it was
+        let deadline = Instant::now() + shutdown_timeout;
+        let mut drained: u64 = 0;
+        while Instant::now() < deadline && self.replicate(&mut transaction_cache) {
+            drained += 1;
+        }
+        if let Some(held) = &mut self.held_accounts {
+            let released = held.release_all();
+            self.dispatch_held(released, &mut transaction_cache);
         }
-        tracing::info!("replicator received shutdown signal, stopping");
+        if let Some(slot_order) = &mut self.slot_order {
+            let released = slot_order.release_all();
+            self.dispatch_all(released);
+        }
+        tracing::info!(rounds = drained, "drained in-flight notifications");
+        self.stop(&loader, deadline);
     }
 
-    fn replicate(
+    /// Waits for the plugin workers to deliver what they have queued until the deadline, then unloads the plugins.
+    /// Admin loads and unloads still in progress finish on their own threads.
+    pub fn stop(self, loader: &PluginLoader, deadline: Instant) {
+        tracing::info!("replicator stopping, waiting for plugin workers to finish");
+        loader.shutdown(self.workers, deadline);
+    }
+
+    async fn wait_for_notification(
+        receivers: &mut ReplicaReceivers,
+        admin: &mut Option<UnboundedReceiver<AdminRequest>>,
+        admin_loads: &mut UnboundedReceiver<AdminLoad>,
+        timeout: Duration,
+    ) -> Wakeup {
+        let received = tokio::select! {
+            biased;
+            Some(request) = next_admin_request(admin) => return Wakeup::Admin(request),
+            Some(load) = admin_loads.recv() => return Wakeup::Loaded(load),
+            r = receivers.transaction.recv() => r.map(Notification::Transaction).map_err(|e| ("transaction", e)),
+            r = receivers.account.recv() => r.map(Notification::Account).map_err(|e| ("account", e)),
+            r = receivers.block.recv() => r.map(Notification::Block).map_err(|e| ("block", e)),
+            r = receivers.entry.recv() => r.map(Notification::Entry).map_err(|e| ("entry", e)),
+            r = receivers.slot.recv() => r.map(Notification::Slot).map_err(|e| ("slot", e)),
+            r = receivers.startup.recv() => r.map(Notification::Startup).map_err(|e| ("startup", e)),
+            r = receivers.lifecycle.recv() => r.map(Notification::Lifecycle).map_err(|e| ("lifecycle", e)),
+            _ = tokio::time::sleep(timeout) => return Wakeup::Idle,
+        };
+
+        match received {
+            Ok(notification) => Wakeup::Notification(notification),
+            Err((channel, RecvError::Lagged(n))) => {
+                tracing::warn!(channel, skipped = n, "receiver lagged, messages dropped");
+                Wakeup::Idle
+            }
+            Err((channel, RecvError::Closed)) => Wakeup::Closed(channel),
+        }
+    }
+
+    /// Lists right away, loads and unloads run on a background thread each and reply from there, or from
+    /// [`add_loaded_plugin`](Self::add_loaded_plugin) once a loaded plugin came back
+    fn handle_admin(&mut self, loader: &Arc<PluginLoader>, request: AdminRequest) {
+        let AdminRequest { command, reply } = request;
+        let task: Box<dyn FnOnce() + Send> = match command {
+            AdminCommand::ListPlugins => {
+                // the admin connection may be gone already
+                let _ = reply.send(AdminResponse::from_result(Ok(self.plugin_names())));
+                return;
+            }
+            AdminCommand::LoadPlugin { config, sandboxed } => {
+                let (loader, loaded) = (loader.clone(), self.admin_loaded.clone());
+                Box::new(move || {
+                    let worker = loader.spawn(&config, sandboxed, false);
+                    let _ = loaded.send(AdminLoad { worker, config, sandboxed, reloaded: None, reply });
+                })
+            }
+            AdminCommand::UnloadPlugin { name } => {
+                let Some(worker) = self.take_worker(&name) else {
+                    let error = anyhow!("the plugin you requested to unload is not loaded");
+                    let _ = reply.send(AdminResponse::from_result::<()>(Err(error)));
+                    return;
+                };
+                Box::new(move || {
+                    PluginLoader::unload(worker);
+                    let _ = reply.send(AdminResponse::from_result(Ok(())));
+                })
+            }
+            // same as the validator's plugin reload, the old plugin is unloaded first in case the new one needs
+            // exclusive access to a resource such as a port, then the new one is loaded with `is_reload` set. If
+            // loading fails the plugin stays unloaded.
+            AdminCommand::ReloadPlugin { name, config } => {
+                let Some(worker) = self.take_worker(&name) else {
+                    let error = anyhow!("the plugin you requested to reload is not loaded");
+                    let _ = reply.send(AdminResponse::from_result::<()>(Err(error)));
+                    return;
+                };
+                let sandboxed = worker.is_sandboxed();
+                let (loader, loaded) = (loader.clone(), self.admin_loaded.clone());
+                Box::new(move || {
+                    PluginLoader::unload(worker);
+                    let worker = loader.spawn(&config, sandboxed, true);
+                    let _ = loaded.send(AdminLoad { worker, config, sandboxed, reloaded: Some(name), reply });
+                })
+            }
+        };
+
+        if let Err(e) = std::thread::Builder::new().name("ample-admin".to_string()).spawn(task) {
+            // the request's reply is dropped along with the task, the admin connection reports the failure
+            tracing::error!("failed to spawn admin thread: {}", e);
+        }
+    }
+
+    fn plugin_names(&self) -> Vec<String> {
+        self.workers.iter().map(|worker| worker.name().to_string()).collect()
+    }
+
+    /// Removes the plugin's worker, so it gets no further notifications
+    fn take_worker(&mut self, name: &str) -> Option<PluginWorker> {
+        let index = self.workers.iter().position(|worker| worker.name() == name)?;
+        Some(self.workers.remove(index))
+    }
+
+    fn add_loaded_plugin(&mut self, loader: &PluginLoader, load: AdminLoad) {
+        let AdminLoad { worker, config, sandboxed, reloaded, reply } = load;
+        let added = worker.and_then(|worker| self.add_plugin(loader, worker, &config, sandboxed));
+        let response = match reloaded {
+            Some(name) => AdminResponse::from_result(added.map(|reloaded| {
+                if reloaded != name {
+                    tracing::warn!(plugin = %name, "reloaded plugin now calls itself {}", reloaded);
+                }
+            })),
+            None => AdminResponse::from_result(added),
+        };
+        // the admin connection may be gone already
+        let _ = reply.send(response);
+    }
+
+    fn add_plugin(
+        &mut self,
+        loader: &PluginLoader,
+        worker: PluginWorker,
+        config: &str,
+        sandboxed: bool,
+    ) -> anyhow::Result<String> {
+        let name = loader.add(&mut self.workers, worker, config)?;
+        let worker = self.workers.last().expect("the loaded plugin's worker was just added");
+
+        self.warn_unsubscribed(&name, worker.flags());
+        // plugins loaded late never see the startup accounts, but shouldn't wait for the end of startup forever
+        if self.end_of_startup_notified {
+            worker.send(&PluginEvent::EndOfStartup);
+        }
+        tracing::info!(plugin = %name, config, sandboxed, "loaded plugin");
+        Ok(name)
+    }
+
+    /// Streams weren't opened upstream if no plugin wanted them at connect time
+    fn warn_unsubscribed(&self, name: &str, flags: NotificationFlags) {
+        let missing: Vec<_> = [
+            (flags.accounts && !self.subscription.accounts, "accounts"),
+            (flags.transactions && !self.subscription.transactions, "transactions"),
+            (flags.entries && !self.subscription.entries, "entries"),
+        ]
+        .into_iter()
+        .filter_map(|(missing, stream)| missing.then_some(stream))
+        .collect();
+
+        if !missing.is_empty() {
+            tracing::warn!(
+                plugin = %name,
+                ?missing,
+                "plugin wants streams that aren't subscribed upstream, restart the gateway to receive them"
+            );
+        }
+    }
+
+    fn notify(
         &mut self,
-        manager: &GeyserPluginManager,
         transaction_cache: &mut TransactionCache,
+        notification: Notification,
     ) {
-        try_recv_and_handle!(self.replica_receivers.transaction, "transaction", transaction_replica => self.notify_transaction_replica(manager, transaction_replica, transaction_cache));
-        try_recv_and_handle!(self.replica_receivers.account, "account", account_replica => self.notify_account_replica(manager, account_replica, transaction_cache));
-        try_recv_and_handle!(self.replica_receivers.block, "block", block_replica => self.notify_block_replica(manager, block_replica));
-        try_recv_and_handle!(self.replica_receivers.entry, "entry", entry_replica => self.notify_entry_replica(manager, entry_replica));
-        try_recv_and_handle!(self.replica_receivers.slot, "slot", slot_replica => self.notify_slot_replica(manager, slot_replica));
+        match notification {
+            Notification::Transaction(replica) => self.notify_transaction_replica(replica, transaction_cache),
+            Notification::Account(replica) => self.notify_account_replica(replica, false, transaction_cache),
+            Notification::Block(replica) => self.notify_block_replica(replica),
+            Notification::Entry(replica) => self.notify_entry_replica(replica),
+            Notification::Slot(replica) => self.notify_slot_replica(replica),
+            Notification::Startup(replica) => self.notify_startup_replica(replica, transaction_cache),
+            Notification::Lifecycle(replica) => self.notify_lifecycle_replica(replica),
+        }
+    }
+
+    /// Handles at most one pending notification per stream, returns whether anything was pending
+    fn replicate(
+        &mut self,
+        transaction_cache: &mut TransactionCache,
+    ) -> bool {
+        let mut handled = false;
+        handled |= try_recv_and_handle!(self.replica_receivers.transaction, "transaction", transaction_replica => self.notify_transaction_replica(transaction_replica, transaction_cache));
+        handled |= try_recv_and_handle!(self.replica_receivers.account, "account", account_replica => self.notify_account_replica(account_replica, false, transaction_cache));
+        handled |= try_recv_and_handle!(self.replica_receivers.block, "block", block_replica => self.notify_block_replica(block_replica));
+        handled |= try_recv_and_handle!(self.replica_receivers.entry, "entry", entry_replica => self.notify_entry_replica(entry_replica));
+        handled |= try_recv_and_handle!(self.replica_receivers.slot, "slot", slot_replica => self.notify_slot_replica(slot_replica));
+        handled |= try_recv_and_handle!(self.replica_receivers.startup, "startup", startup_replica => self.notify_startup_replica(startup_replica, transaction_cache));
+        handled |= try_recv_and_handle!(self.replica_receivers.lifecycle, "lifecycle", lifecycle_replica => self.notify_lifecycle_replica(lifecycle_replica));
+        handled
+    }
+}
+
+/// Pending forever without an admin interface
+async fn next_admin_request(admin: &mut Option<UnboundedReceiver<AdminRequest>>) -> Option<AdminRequest> {
+    match admin {
+        Some(admin) => admin.recv().await,
+        None => std::future::pending().await,
     }
 }
@@ -0,0 +1,442 @@
+//! Runs a plugin in a supervised child process, so a plugin crashing takes down only its own process instead of the
+//! whole gateway with every other plugin and the upstream connection.
+//!
+//! The child is the gateway binary itself started in a hidden host mode. It loads the plugin, connects back to the
+//! gateway over a Unix socket and receives notifications as bincode frames, the same `u32` length prefix framing the
+//! transport uses. When the child dies it is restarted with an exponential backoff.
+//!
+//! The socket is bound the same private way as the admin socket, in a fresh temporary directory, and only the child
+//! the gateway spawned is accepted on it, checked by the connecting process id.
+
+use crate::compat::SharedTransaction;
+use crate::plugin_settings::InterfaceVersions;
+use crate::plugin_worker::{deliver, NotificationFlags, PluginEvent};
+use crate::admin::bind_private;
+use crate::plugin_loader::load_plugin;
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use transport::metrics::{StreamMetricHelper, TransportMetrics};
+use transport::{UniformAccountInfo, UniformBlockInfo, UniformEntryInfo, UniformSlotInfo, UniformTransactionInfo};
+
+/// First argument the gateway binary is started with to host a sandboxed plugin
+const CHILD_ARG: &str = "__sandbox-child";
+/// Last argument of a child hosting a reloaded plugin
+const RELOAD_ARG: &str = "reload";
+/// How long a child gets to load its plugin and connect back
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long a stopped child gets to deliver what it has received and to return from `on_unload` before it's killed
+const STOP_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct SandboxConfig {
+    pub plugin_config: String,
+    pub restart_backoff: Duration,
+    pub max_restart_backoff: Duration,
+}
+
+/// Sent by the child once its plugin is loaded
+#[derive(Serialize, Deserialize)]
+struct Ready {
+    name: String,
+    flags: NotificationFlags,
+}
+
+/// Serialized by the gateway without cloning the shared notifications, read back by the child as [`SandboxEvent`]
+#[derive(Serialize)]
+enum SandboxEventRef<'a> {
+    Account {
+        replica: &'a UniformAccountInfo,
+        txn: Option<&'a UniformTransactionInfo>,
+        is_startup: bool,
+    },
+    Transaction(&'a UniformTransactionInfo),
+    Block(&'a UniformBlockInfo),
+    Entry(&'a UniformEntryInfo),
+    Slot(&'a UniformSlotInfo),
+    EndOfStartup,
+}
+
+#[derive(Deserialize)]
+enum SandboxEvent {
+    Account {
+        replica: UniformAccountInfo,
+        txn: Option<UniformTransactionInfo>,
+        is_startup: bool,
+    },
+    Transaction(UniformTransactionInfo),
+    Block(UniformBlockInfo),
+    Entry(UniformEntryInfo),
+    Slot(UniformSlotInfo),
+    EndOfStartup,
+}
+
+impl<'a> From<&'a PluginEvent> for SandboxEventRef<'a> {
+    fn from(event: &'a PluginEvent) -> Self {
+        match event {
+            PluginEvent::Account { replica, txn, is_startup } => SandboxEventRef::Account {
+                replica,
+                txn: txn.as_ref().map(|txn| &txn.replica),
+                is_startup: *is_startup,
+            },
+            PluginEvent::Transaction(txn) => SandboxEventRef::Transaction(&txn.replica),
+            PluginEvent::Block(replica) => SandboxEventRef::Block(replica),
+            PluginEvent::Entry(replica) => SandboxEventRef::Entry(replica),
+            PluginEvent::Slot(replica) => SandboxEventRef::Slot(replica),
+            PluginEvent::EndOfStartup => SandboxEventRef::EndOfStartup,
+        }
+    }
+}
+
+impl From<SandboxEvent> for PluginEvent {
+    fn from(event: SandboxEvent) -> Self {
+        match event {
+            SandboxEvent::Account { replica, txn, is_startup } => PluginEvent::Account {
+                replica: Arc::new(replica),
+                txn: txn.map(|txn| Arc::new(SharedTransaction::new(txn, None))),
+                is_startup,
+            },
+            SandboxEvent::Transaction(txn) => PluginEvent::Transaction(Arc::new(SharedTransaction::new(txn, None))),
+            SandboxEvent::Block(replica) => PluginEvent::Block(Arc::new(replica)),
+            SandboxEvent::Entry(replica) => PluginEvent::Entry(Arc::new(replica)),
+            SandboxEvent::Slot(replica) => PluginEvent::Slot(Arc::new(replica)),
+            SandboxEvent::EndOfStartup => PluginEvent::EndOfStartup,
+        }
+    }
+}
+
+fn write_frame<T: Serialize>(writer: &mut impl Write, value: &T) -> anyhow::Result<()> {
+    let serialized = bincode::serialize(value)?;
+    writer.write_all(&(serialized.len() as u32).to_le_bytes())?;
+    writer.write_all(&serialized)?;
+    Ok(())
+}
+
+/// Returns `None` once the other side closed the connection
+fn read_frame<T: DeserializeOwned>(reader: &mut impl Read) -> anyhow::Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(Some(bincode::deserialize(&buf)?))
+}
+
+/// Returns the socket path, the plugin config and whether the plugin is reloaded when this process was started to host
+/// a sandboxed plugin
+pub fn child_args() -> Option<(PathBuf, String, bool)> {
+    let mut args = std::env::args().skip(1);
+    if args.next()? != CHILD_ARG {
+        return None;
+    }
+    let (socket_path, plugin_config) = (PathBuf::from(args.next()?), args.next()?);
+    Some((socket_path, plugin_config, args.next().as_deref() == Some(RELOAD_ARG)))
+}
+
+/// Entry point of the child process, delivers notifications until the gateway closes the connection
+pub fn run_child(socket_path: PathBuf, plugin_config: String, is_reload: bool) -> anyhow::Result<()> {
+    let (mut plugin, library) = load_plugin(&plugin_config, is_reload)?;
+    tracing::info!(plugin = plugin.name(), config = %plugin_config, is_reload, "loaded sandboxed plugin");
+
+    let stream = UnixStream::connect(&socket_path)
+        .with_context(|| format!("failed to connect to gateway at {}", socket_path.display()))?;
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    write_frame(&mut writer, &Ready {
+        name: plugin.name().to_string(),
+        flags: NotificationFlags::of(&plugin),
+    })?;
+    writer.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let interface: InterfaceVersions = read_frame(&mut reader)?.context("gateway closed the connection")?;
+    while let Some(event) = read_frame::<SandboxEvent>(&mut reader)? {
+        deliver(&plugin, &interface, &event.into());
+    }
+
+    tracing::info!(plugin = plugin.name(), "gateway closed the connection, stopping sandboxed plugin");
+    plugin.on_unload();
+    // the plugin has to be dropped before its library is unloaded
+    drop(plugin);
+    drop(library);
+    Ok(())
+}
+
+/// A freshly started child whose plugin is loaded, but which hasn't received its interface versions yet
+pub struct LaunchedChild {
+    child: Child,
+    stream: UnixStream,
+    ready: Ready,
+}
+
+impl LaunchedChild {
+    pub fn plugin_name(&self) -> &str {
+        &self.ready.name
+    }
+}
+
+/// Starts a child process hosting the plugin and waits until the plugin is loaded. `is_reload` is passed on to the
+/// plugin's `on_load`.
+pub fn launch(config: &SandboxConfig, is_reload: bool) -> anyhow::Result<LaunchedChild> {
+    // removed with the socket in it once the child connected or failed to
+    let socket_dir = tempfile::Builder::new().prefix("ample-sandbox-").tempdir()?;
+    std::fs::set_permissions(socket_dir.path(), std::fs::Permissions::from_mode(0o700))?;
+    let socket_path = socket_dir.path().join("plugin.sock");
+    let listener = bind_private(&socket_path)?;
+    listener.set_nonblocking(true)?;
+
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg(CHILD_ARG)
+        .arg(&socket_path)
+        .arg(&config.plugin_config)
+        .args(is_reload.then_some(RELOAD_ARG))
+        .spawn()?;
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    let accepted: anyhow::Result<UnixStream> = loop {
+        match listener.accept() {
+            Ok((stream, _)) => match peer_pid(&stream) {
+                Ok(pid) if pid == child.id() => break Ok(stream),
+                Ok(pid) => tracing::warn!(pid, config = %config.plugin_config, "rejected sandbox connection of another process"),
+                Err(e) => tracing::warn!(config = %config.plugin_config, "rejected sandbox connection: {}", e),
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => break Err(e.into()),
+        }
+        if let Some(status) = child.try_wait()? {
+            break Err(anyhow::anyhow!("plugin host exited during startup with {}", status));
+        }
+        if Instant::now() > deadline {
+            break Err(anyhow::anyhow!("plugin wasn't loaded within {:?}", STARTUP_TIMEOUT));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    drop(listener);
+    drop(socket_dir);
+
+    let handshake = accepted.and_then(|stream: UnixStream| {
+        stream.set_nonblocking(false)?;
+        let ready = read_frame::<Ready>(&mut &stream)?.context("plugin host closed the connection during startup")?;
+        Ok((stream, ready))
+    });
+    match handshake {
+        Ok((stream, ready)) => Ok(LaunchedChild { child, stream, ready }),
+        Err(e) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(e).with_context(|| format!("failed to start sandboxed plugin {}", config.plugin_config))
+        }
+    }
+}
+
+/// Process id of the other end of the connection, as the kernel recorded it when it connected
+fn peer_pid(stream: &UnixStream) -> std::io::Result<u32> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` are valid for writes and `len` holds the size of `cred`
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(cred.pid as u32)
+}
+
+/// Waits for a child that was asked to exit, killing it once the grace period has passed
+fn wait_or_kill(child: &mut Child) -> std::io::Result<ExitStatus> {
+    let deadline = Instant::now() + STOP_GRACE_PERIOD;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    tracing::warn!(pid = child.id(), "sandboxed plugin didn't exit within {:?}, killing it", STOP_GRACE_PERIOD);
+    let _ = child.kill();
+    child.wait()
+}
+
+/// Owns the child process of a sandboxed plugin, forwarding notifications to it and restarting it when it dies.
+/// Notifications arriving while the child is down are dropped, a restarted plugin starts from a clean state and is
+/// told that startup is over if it already was. Restarts
+/// are launched on a separate thread, so a slow plugin startup never holds up the delivery of notifications.
+pub struct SandboxSupervisor {
+    config: SandboxConfig,
+    name: String,
+    flags: NotificationFlags,
+    interface: InterfaceVersions,
+    child: Option<Child>,
+    connection: Option<BufWriter<UnixStream>>,
+    /// Restart in progress, checked for completion on each delivery
+    launching: Option<JoinHandle<anyhow::Result<LaunchedChild>>>,
+    /// Startup is over, a restarted child is told so right after it's attached
+    end_of_startup_notified: bool,
+    started_at: Instant,
+    backoff: Duration,
+    next_start: Instant,
+    metrics: Option<Arc<TransportMetrics>>,
+}
+
+impl SandboxSupervisor {
+    pub fn new(
+        config: SandboxConfig,
+        launched: LaunchedChild,
+        interface: InterfaceVersions,
+        metrics: Option<Arc<TransportMetrics>>,
+    ) -> anyhow::Result<Self> {
+        let mut supervisor = Self {
+            name: launched.ready.name.clone(),
+            flags: launched.ready.flags,
+            backoff: config.restart_backoff,
+            config,
+            interface,
+            child: None,
+            connection: None,
+            launching: None,
+            end_of_startup_notified: false,
+            started_at: Instant::now(),
+            next_start: Instant::now(),
+            metrics,
+        };
+        supervisor.attach(launched)?;
+        Ok(supervisor)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn flags(&self) -> NotificationFlags {
+        self.flags
+    }
+
+    fn attach(&mut self, launched: LaunchedChild) -> anyhow::Result<()> {
+        let LaunchedChild { child, stream, ready } = launched;
+        if ready.name != self.name {
+            tracing::warn!(plugin = %self.name, "restarted sandboxed plugin now calls itself {}", ready.name);
+        }
+
+        let mut connection = BufWriter::new(stream);
+        write_frame(&mut connection, &self.interface)?;
+        if self.end_of_startup_notified {
+            write_frame(&mut connection, &SandboxEventRef::EndOfStartup)?;
+        }
+        connection.flush()?;
+
+        self.child = Some(child);
+        self.connection = Some(connection);
+        self.started_at = Instant::now();
+        Ok(())
+    }
+
+    /// Returns whether the notification was handed to the child
+    pub fn deliver(&mut self, event: &PluginEvent, flush: bool) -> bool {
+        // remembered even while the child is down, the next child gets it when it's attached
+        if matches!(event, PluginEvent::EndOfStartup) {
+            self.end_of_startup_notified = true;
+        }
+        if self.connection.is_none() && !self.restart() {
+            // dropped while the child is down
+            return false;
+        }
+        let Some(connection) = self.connection.as_mut() else {
+            return false;
+        };
+
+        let written = write_frame(connection, &SandboxEventRef::from(event))
+            .and_then(|_| if flush { Ok(connection.flush()?) } else { Ok(()) });
+        if let Err(e) = written {
+            self.crashed(e);
+            return false;
+        }
+        true
+    }
+
+    fn crashed(&mut self, error: anyhow::Error) {
+        self.connection = None;
+        let status = self.child.take().map(|mut child| {
+            let _ = child.kill();
+            child.wait()
+        });
+        tracing::error!(plugin = %self.name, ?status, "sandboxed plugin died: {:#}", error);
+        StreamMetricHelper::record_plugin_restart(self.metrics.as_ref(), &self.name);
+
+        // a child that stayed up for a while starts over with the initial backoff
+        if self.started_at.elapsed() > self.config.max_restart_backoff {
+            self.backoff = self.config.restart_backoff;
+        }
+        self.schedule_restart();
+    }
+
+    fn schedule_restart(&mut self) {
+        self.next_start = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(self.config.max_restart_backoff);
+    }
+
+    /// Starts a new child once the backoff has passed and attaches it when it's ready, without waiting for either.
+    /// Returns whether a child is attached
+    fn restart(&mut self) -> bool {
+        let Some(launching) = self.launching.take_if(|launching| launching.is_finished()) else {
+            if self.launching.is_none() && Instant::now() >= self.next_start {
+                tracing::info!(plugin = %self.name, "restarting sandboxed plugin");
+                let config = self.config.clone();
+                let spawned = std::thread::Builder::new()
+                    .name(format!("ample-sandbox-{}", self.name))
+                    // a restarted child starts from a clean state, like after a validator restart
+                    .spawn(move || launch(&config, false));
+                match spawned {
+                    Ok(handle) => self.launching = Some(handle),
+                    Err(e) => {
+                        tracing::error!(plugin = %self.name, "failed to spawn sandboxed plugin launcher: {}", e);
+                        self.schedule_restart();
+                    }
+                }
+            }
+            return false;
+        };
+
+        let launched = launching.join().unwrap_or_else(|_| Err(anyhow::anyhow!("sandboxed plugin launcher panicked")));
+        match launched.and_then(|launched| self.attach(launched)) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!(plugin = %self.name, "failed to restart sandboxed plugin: {:#}", e);
+                self.schedule_restart();
+                false
+            }
+        }
+    }
+
+    /// Closes the connection, the child delivers what it has already received and exits. A child that doesn't exit
+    /// within the grace period is killed
+    pub fn stop(mut self) {
+        self.connection = None;
+        // a child started in the meantime never got a notification, it exits once its connection is dropped
+        if let Some(Ok(Ok(mut launched))) = self.launching.take().map(JoinHandle::join) {
+            drop(launched.stream);
+            let _ = wait_or_kill(&mut launched.child);
+        }
+        if let Some(mut child) = self.child.take() {
+            if let Err(e) = wait_or_kill(&mut child) {
+                tracing::error!(plugin = %self.name, "failed to wait for sandboxed plugin: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Optional slot-consistent delivery. Accounts, transactions, entries, block metadata and slot status travel on
+//! independent streams, so block metadata or a `Confirmed` status can overtake the transactions of its slot.
+//!
+//! Block metadata and the `Processed`, `Confirmed` and `Rooted` statuses of a slot are held back until the transactions
+//! and entries announced by the block metadata were delivered, or until the slot's latency budget ran out or block
+//! metadata of a slot `SLOT_BUDGET` slots newer arrived. They are then released in validator order, block metadata
+//! first and the slot statuses after it. A block whose validator doesn't announce the counts (V0_0_1, and V0_0_2 for
+//! entries) can't be recognized as complete, its slot is always released by one of the budgets.
+
+use crate::plugin_worker::PluginEvent;
+use agave_geyser_plugin_interface::geyser_plugin_interface::SlotStatus;
+use solana_clock::Slot;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use transport::metrics::{StreamMetricHelper, TransportMetrics};
+use transport::{Subscription, UniformBlockInfo, UniformSlotInfo};
+
+/// How many slots behind the newest block the state of a slot is kept, to recognize its late notifications
+const TRACKED_SLOTS: Slot = 1024;
+/// How many slots newer block metadata may be than a held slot before the slot is released anyway
+const SLOT_BUDGET: Slot = 32;
+
+#[derive(Default)]
+struct SlotState {
+    transactions: u64,
+    entries: u64,
+    /// Held block metadata
+    block: Option<Arc<UniformBlockInfo>>,
+    /// Held slot statuses, in the order they arrived
+    statuses: Vec<Arc<UniformSlotInfo>>,
+    held_since: Option<Instant>,
+    /// Transactions and entries that arrived while the block metadata was held
+    reorder_depth: u64,
+    /// Set once the block metadata was released, later notifications of the slot pass straight through
+    released: bool,
+}
+
+pub struct SlotOrder {
+    budget: Duration,
+    /// Only streams subscribed upstream can be counted
+    count_transactions: bool,
+    count_entries: bool,
+    slots: BTreeMap<Slot, SlotState>,
+    metrics: Option<Arc<TransportMetrics>>,
+}
+
+impl SlotOrder {
+    pub fn new(budget: Duration, subscription: &Subscription, metrics: Option<Arc<TransportMetrics>>) -> Self {
+        Self {
+            budget,
+            count_transactions: subscription.transactions,
+            count_entries: subscription.entries,
+            slots: BTreeMap::new(),
+            metrics,
+        }
+    }
+
+    pub fn account(&mut self, slot: Slot) {
+        if self.slots.get(&slot).is_some_and(|state| state.released) {
+            StreamMetricHelper::record_slot_order_late_events(self.metrics.as_ref(), 1);
+        }
+    }
+
+    /// Returns the held notifications of the slot that became complete with this transaction
+    pub fn transaction(&mut self, slot: Slot) -> Vec<PluginEvent> {
+        let state = self.slots.entry(slot).or_default();
+        state.transactions += 1;
+        self.data_received(slot)
+    }
+
+    /// Returns the held notifications of the slot that became complete with this entry
+    pub fn entry(&mut self, slot: Slot) -> Vec<PluginEvent> {
+        let state = self.slots.entry(slot).or_default();
+        state.entries += 1;
+        self.data_received(slot)
+    }
+
+    /// Returns the notifications to deliver now, empty while the block metadata is held
+    pub fn block(&mut self, block: Arc<UniformBlockInfo>) -> Vec<PluginEvent> {
+        let slot = block.slot;
+        self.prune(slot);
+        let mut events = self.release_behind(slot);
+
+        let state = self.slots.entry(slot).or_default();
+        if state.released {
+            events.push(PluginEvent::Block(block));
+            return events;
+        }
+        state.block = Some(block);
+        state.held_since.get_or_insert_with(Instant::now);
+        events.extend(self.release_if_complete(slot));
+        events
+    }
+
+    /// Returns the notifications to deliver now, empty while the status is held
+    pub fn slot_status(&mut self, info: Arc<UniformSlotInfo>) -> Vec<PluginEvent> {
+        let holdable = matches!(info.status, SlotStatus::Processed | SlotStatus::Confirmed | SlotStatus::Rooted);
+        let state = self.slots.entry(info.slot).or_default();
+        if !holdable || state.released {
+            return vec![PluginEvent::Slot(info)];
+        }
+        state.statuses.push(info);
+        state.held_since.get_or_insert_with(Instant::now);
+        Vec::new()
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.slots.values()
+            .filter_map(|state| state.held_since)
+            .min()
+            .map(|held_since| held_since + self.budget)
+    }
+
+    /// Releases the slots whose latency budget ran out, incomplete or not
+    pub fn release_expired(&mut self, now: Instant) -> Vec<PluginEvent> {
+        let expired: Vec<Slot> = self.slots.iter()
+            .filter(|(_, state)| state.held_since.is_some_and(|held_since| held_since + self.budget <= now))
+            .map(|(slot, _)| *slot)
+            .collect();
+        expired.into_iter().flat_map(|slot| self.release(slot, true)).collect()
+    }
+
+    /// Releases the held slots whose slot budget ran out with block metadata of `newest`, incomplete or not
+    fn release_behind(&mut self, newest: Slot) -> Vec<PluginEvent> {
+        let cutoff = newest.saturating_sub(SLOT_BUDGET);
+        let expired: Vec<Slot> = self.slots.range(..cutoff)
+            .filter(|(_, state)| state.held_since.is_some())
+            .map(|(slot, _)| *slot)
+            .collect();
+        expired.into_iter().flat_map(|slot| self.release(slot, true)).collect()
+    }
+
+    /// Releases everything held, on shutdown
+    pub fn release_all(&mut self) -> Vec<PluginEvent> {
+        let held: Vec<Slot> = self.slots.iter()
+            .filter(|(_, state)| state.held_since.is_some())
+            .map(|(slot, _)| *slot)
+            .collect();
+        held.into_iter().flat_map(|slot| self.release(slot, true)).collect()
+    }
+
+    fn data_received(&mut self, slot: Slot) -> Vec<PluginEvent> {
+        let state = self.slots.get_mut(&slot).expect("the slot's state was just created");
+        if state.released {
+            StreamMetricHelper::record_slot_order_late_events(self.metrics.as_ref(), 1);
+            return Vec::new();
+        }
+        if state.block.is_none() {
+            return Vec::new();
+        }
+        state.reorder_depth += 1;
+        self.release_if_complete(slot)
+    }
+
+    fn release_if_complete(&mut self, slot: Slot) -> Vec<PluginEvent> {
+        let Some(state) = self.slots.get(&slot) else {
+            return Vec::new();
+        };
+        // counts the validator didn't report leave completeness unknown, the slot waits for its budget
+        let complete = state.block.as_ref().is_some_and(|block| {
+            (!self.count_transactions || block.executed_transaction_count.is_some_and(|count| state.transactions >= count))
+                && (!self.count_entries || block.entry_count.is_some_and(|count| state.entries >= count))
+        });
+        if complete { self.release(slot, false) } else { Vec::new() }
+    }
+
+    fn release(&mut self, slot: Slot, budget_expired: bool) -> Vec<PluginEvent> {
+        let Some(state) = self.slots.get_mut(&slot) else {
+            return Vec::new();
+        };
+        if budget_expired {
+            tracing::debug!(slot, transactions = state.transactions, entries = state.entries, "slot latency budget ran out");
+        }
+        // statuses released without their block metadata still don't hold back a block metadata arriving later
+        state.released = true;
+        state.held_since = None;
+        StreamMetricHelper::record_slot_released(self.metrics.as_ref(), state.reorder_depth, budget_expired);
+
+        let block = state.block.take().map(PluginEvent::Block);
+        let statuses = state.statuses.drain(..).map(PluginEvent::Slot);
+        block.into_iter().chain(statuses).collect()
+    }
+
+    /// Forgets slots far behind the newest block, unless they still hold notifications
+    fn prune(&mut self, newest: Slot) {
+        let cutoff = newest.saturating_sub(TRACKED_SLOTS);
+        while let Some(entry) = self.slots.first_entry() {
+            if *entry.key() >= cutoff || entry.get().held_since.is_some() {
+                break;
+            }
+            entry.remove();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_transaction_status::RewardsAndNumPartitions;
+
+    fn block(slot: Slot, transactions: Option<u64>, entries: Option<u64>) -> Arc<UniformBlockInfo> {
+        Arc::new(UniformBlockInfo {
+            parent_slot: Some(slot - 1),
+            parent_blockhash: None,
+            slot,
+            blockhash: String::new(),
+            rewards: RewardsAndNumPartitions { rewards: Vec::new(), num_partitions: None },
+            block_time: None,
+            block_height: None,
+            executed_transaction_count: transactions,
+            entry_count: entries,
+        })
+    }
+
+    fn status(slot: Slot, status: SlotStatus) -> Arc<UniformSlotInfo> {
+        Arc::new(UniformSlotInfo::from_replica(slot, Some(slot - 1), status))
+    }
+
+    fn released(events: &[PluginEvent]) -> Vec<(&'static str, Slot)> {
+        events.iter()
+            .map(|event| match event {
+                PluginEvent::Block(block) => ("block", block.slot),
+                PluginEvent::Slot(info) => ("slot", info.slot),
+                _ => panic!("slot order only holds block metadata and slot statuses"),
+            })
+            .collect()
+    }
+
+    fn slot_order(budget: Duration) -> SlotOrder {
+        SlotOrder::new(budget, &Subscription::default(), None)
+    }
+
+    #[test]
+    fn holds_block_and_statuses_until_the_slot_is_complete() {
+        let mut order = slot_order(Duration::from_secs(60));
+        assert!(order.block(block(10, Some(2), Some(1))).is_empty());
+        assert!(order.slot_status(status(10, SlotStatus::Confirmed)).is_empty());
+        assert!(order.transaction(10).is_empty());
+        assert!(order.entry(10).is_empty());
+
+        let events = order.transaction(10);
+        assert_eq!(released(&events), [("block", 10), ("slot", 10)]);
+        assert!(order.next_deadline().is_none());
+    }
+
+    #[test]
+    fn data_arriving_before_the_block_counts() {
+        let mut order = slot_order(Duration::from_secs(60));
+        assert!(order.transaction(10).is_empty());
+        assert!(order.entry(10).is_empty());
+        assert_eq!(released(&order.block(block(10, Some(1), Some(1)))), [("block", 10)]);
+    }
+
+    #[test]
+    fn released_slots_pass_through() {
+        let mut order = slot_order(Duration::from_secs(60));
+        assert_eq!(released(&order.block(block(10, Some(0), Some(0)))), [("block", 10)]);
+        assert_eq!(released(&order.slot_status(status(10, SlotStatus::Rooted))), [("slot", 10)]);
+        assert!(order.transaction(10).is_empty());
+    }
+
+    #[test]
+    fn statuses_that_are_never_held_pass_through() {
+        let mut order = slot_order(Duration::from_secs(60));
+        let events = order.slot_status(status(10, SlotStatus::FirstShredReceived));
+        assert_eq!(released(&events), [("slot", 10)]);
+    }
+
+    #[test]
+    fn releases_incomplete_slots_once_the_budget_expires() {
+        let mut order = slot_order(Duration::from_millis(10));
+        assert!(order.block(block(10, Some(5), None)).is_empty());
+        let deadline = order.next_deadline().expect("the block is held");
+
+        assert!(order.release_expired(deadline - Duration::from_millis(1)).is_empty());
+        assert_eq!(released(&order.release_expired(deadline)), [("block", 10)]);
+        assert!(order.next_deadline().is_none());
+    }
+
+    #[test]
+    fn blocks_without_counts_wait_for_the_budget() {
+        let mut order = slot_order(Duration::from_secs(60));
+        assert!(order.block(block(10, None, None)).is_empty());
+        assert!(order.transaction(10).is_empty());
+        assert!(order.entry(10).is_empty());
+        assert_eq!(released(&order.release_all()), [("block", 10)]);
+    }
+
+    #[test]
+    fn releases_slots_behind_a_much_newer_block() {
+        let mut order = slot_order(Duration::from_secs(60));
+        assert!(order.block(block(10, Some(1), None)).is_empty());
+        assert!(order.block(block(10 + SLOT_BUDGET, Some(1), None)).is_empty());
+
+        let events = order.block(block(11 + SLOT_BUDGET, Some(1), None));
+        assert_eq!(released(&events), [("block", 10)]);
+    }
+}
@@ -0,0 +1,254 @@
+//! Reads account state out of a local validator snapshot, so the gateway can bootstrap its plugins without the
+//! validator having to stream startup notifications.
+//!
+//! Only full snapshots using the AppendVec account storage format are supported.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::{bail, Context};
+use solana_clock::Slot;
+use solana_pubkey::Pubkey;
+use tempfile::TempDir;
+use transport::UniformAccountInfo;
+
+// AppendVec entry layout: StoredMeta, AccountMeta, the obsolete account hash and then the account data,
+// with every entry aligned to 8 bytes
+const STORED_META_SIZE: usize = 48;
+const ACCOUNT_META_SIZE: usize = 56;
+const ACCOUNT_HASH_SIZE: usize = 32;
+const STORE_META_OVERHEAD: usize = STORED_META_SIZE + ACCOUNT_META_SIZE + ACCOUNT_HASH_SIZE;
+const MAX_PERMITTED_DATA_LENGTH: u64 = 10 * 1024 * 1024;
+
+pub struct Snapshot {
+    /// The slot the snapshot was taken at, live updates up to and including it are already part of the snapshot
+    pub slot: Slot,
+    /// Account storage files sorted by slot, so later storages supersede earlier ones
+    storages: Vec<AccountStorage>,
+    /// Set when the archive was unpacked into a temporary directory, which is removed once the snapshot is dropped
+    _unpacked_dir: Option<TempDir>,
+}
+
+pub struct AccountStorage {
+    pub slot: Slot,
+    path: PathBuf,
+    /// Write version of the storage's first account, storages of later slots get higher ones
+    first_write_version: u64,
+}
+
+impl Snapshot {
+    /// Opens either an unpacked snapshot directory or a `.tar.zst` / `.tar` snapshot archive, which is unpacked into
+    /// `unpack_dir`. `on_progress` is called regularly while unpacking, which can take a long time, and unpacking stops
+    /// once `shutdown` is set.
+    pub fn open(
+        path: &Path,
+        unpack_dir: Option<&Path>,
+        shutdown: &AtomicBool,
+        mut on_progress: impl FnMut(),
+    ) -> anyhow::Result<Self> {
+        if path.is_dir() {
+            return Self::from_dir(path, None);
+        }
+
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        let reader: Box<dyn Read> = if file_name.ends_with(".tar.zst") {
+            Box::new(zstd::stream::read::Decoder::new(File::open(path)?)?)
+        } else if file_name.ends_with(".tar") {
+            Box::new(File::open(path)?)
+        } else {
+            bail!("unsupported snapshot archive {}, expected a .tar.zst or .tar archive", path.display());
+        };
+
+        // a temporary directory gets an unpredictable name and is only accessible by the gateway's user, it's removed
+        // when dropped, also when unpacking fails halfway
+        let (dir, unpacked_dir) = match unpack_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                (dir.to_path_buf(), None)
+            }
+            None => {
+                let temp = tempfile::Builder::new().prefix("ample-snapshot-").tempdir()?;
+                (temp.path().to_path_buf(), Some(temp))
+            }
+        };
+
+        tracing::info!(archive = %path.display(), dir = %dir.display(), "unpacking snapshot archive");
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            if shutdown.load(Ordering::Relaxed) {
+                bail!("unpacking interrupted by shutdown");
+            }
+            entry?.unpack_in(&dir)?;
+            on_progress();
+        }
+
+        Self::from_dir(&dir, unpacked_dir)
+    }
+
+    fn from_dir(dir: &Path, unpacked_dir: Option<TempDir>) -> anyhow::Result<Self> {
+        // snapshots/<slot>/ holds the bank fields of the snapshot slot
+        let slot = std::fs::read_dir(dir.join("snapshots"))
+            .context("snapshot is missing the snapshots directory")?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<Slot>().ok())
+            .max()
+            .context("snapshot doesn't contain a snapshot slot")?;
+
+        // accounts/<slot>.<id> are the account storages
+        let mut storages = Vec::new();
+        for entry in std::fs::read_dir(dir.join("accounts")).context("snapshot is missing the accounts directory")? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(storage_slot) = file_name.to_str()
+                .and_then(|name| name.split('.').next())
+                .and_then(|slot| slot.parse::<Slot>().ok())
+            else {
+                continue;
+            };
+            storages.push((storage_slot, entry.path(), entry.metadata()?.len()));
+        }
+        storages.sort_by_key(|(slot, _, _)| *slot);
+
+        // every storage gets a range of write versions as large as the accounts it can hold at most, so write versions
+        // ascend with the slot no matter in which order the storages are read
+        let mut next_write_version = 1;
+        let storages = storages.into_iter()
+            .map(|(slot, path, len)| {
+                let first_write_version = next_write_version;
+                next_write_version += len / STORE_META_OVERHEAD as u64 + 1;
+                AccountStorage { slot, path, first_write_version }
+            })
+            .collect();
+
+        Ok(Self {
+            slot,
+            storages,
+            _unpacked_dir: unpacked_dir,
+        })
+    }
+
+    pub fn storages(&self) -> &[AccountStorage] {
+        &self.storages
+    }
+}
+
+/// Keeps only the newest version of every account and drops deleted ones, as the validator does when restoring a
+/// snapshot. Storages have to be passed newest first.
+///
+/// Remembers the pubkey of every account seen until the whole snapshot is loaded, for a mainnet snapshot with its
+/// hundreds of millions of accounts that's tens of GB, so it's only used when asked for.
+#[derive(Default)]
+pub struct NewestAccounts {
+    seen: HashSet<Pubkey>,
+}
+
+impl NewestAccounts {
+    /// Takes the accounts of a storage in write order, returns the ones not superseded by a newer version already seen
+    /// and not deleted by a zero lamport tombstone
+    pub fn filter(&mut self, accounts: Vec<UniformAccountInfo>) -> Vec<UniformAccountInfo> {
+        accounts.into_iter()
+            .rev()
+            .filter(|account| self.seen.insert(account.pubkey) && account.lamports > 0)
+            .collect()
+    }
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Fills `buf`, returns `false` if the file ends before
+fn read_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> anyhow::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl AccountStorage {
+    /// Reads the accounts stored in this storage in write order, one at a time. Snapshots don't keep write versions
+    /// anymore, so they are counted up from the storage's first one.
+    pub fn accounts(&self) -> anyhow::Result<StoredAccounts> {
+        Ok(StoredAccounts {
+            reader: BufReader::new(File::open(&self.path)?),
+            slot: self.slot,
+            write_version: self.first_write_version,
+            finished: false,
+        })
+    }
+
+    /// Reads every account stored in this storage in write order
+    pub fn read_accounts(&self) -> anyhow::Result<Vec<UniformAccountInfo>> {
+        self.accounts()?.collect()
+    }
+}
+
+/// Accounts of a storage file, read without holding more than one account in memory
+pub struct StoredAccounts {
+    reader: BufReader<File>,
+    slot: Slot,
+    write_version: u64,
+    finished: bool,
+}
+
+impl StoredAccounts {
+    fn read_account(&mut self) -> anyhow::Result<Option<UniformAccountInfo>> {
+        let mut header = [0u8; STORE_META_OVERHEAD];
+        if !read_or_eof(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+        let data_len = read_u64(&header, 8);
+        let pubkey = Pubkey::try_from(&header[16..48]).unwrap();
+
+        let meta = STORED_META_SIZE;
+        let lamports = read_u64(&header, meta);
+        let rent_epoch = read_u64(&header, meta + 8);
+        let owner = Pubkey::try_from(&header[meta + 16..meta + 48]).unwrap();
+        let executable = header[meta + 48] != 0;
+
+        // past the written part of the storage, the rest of the file is zeroed
+        if data_len > MAX_PERMITTED_DATA_LENGTH {
+            return Ok(None);
+        }
+        if data_len == 0 && lamports == 0 && pubkey == Pubkey::default() {
+            return Ok(None);
+        }
+        let mut data = vec![0u8; data_len as usize];
+        if !read_or_eof(&mut self.reader, &mut data)? {
+            return Ok(None);
+        }
+        // the header is a multiple of 8 bytes, so only the data is padded, the last entry may be unpadded
+        let mut padding = [0u8; 8];
+        let padding_len = data.len().next_multiple_of(8) - data.len();
+        read_or_eof(&mut self.reader, &mut padding[..padding_len])?;
+
+        let write_version = self.write_version;
+        self.write_version += 1;
+        Ok(Some(UniformAccountInfo {
+            slot: self.slot,
+            pubkey,
+            owner,
+            lamports,
+            data,
+            transaction_ref: None,
+            executable,
+            rent_epoch,
+            write_version,
+        }))
+    }
+}
+
+impl Iterator for StoredAccounts {
+    type Item = anyhow::Result<UniformAccountInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let account = self.read_account().transpose();
+        self.finished = !matches!(account, Some(Ok(_)));
+        account
+    }
+}
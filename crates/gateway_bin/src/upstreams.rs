@@ -0,0 +1,489 @@
+//! Connections to the upstream proxies. With several upstreams, e.g. proxies on redundant validators, their
+//! notifications are merged: whichever upstream delivers a notification first wins and the copies arriving later from
+//! the others are dropped. An upstream whose connection dies is reconnected in the background while the others keep
+//! delivering. A single upstream has nothing to merge, it delivers straight to the merged channels and reconnects the
+//! same way.
+
+use agave_geyser_plugin_interface::geyser_plugin_interface::SlotStatus;
+use anyhow::bail;
+use lru::LruCache;
+use solana_clock::Slot;
+use solana_pubkey::Pubkey;
+use solana_signature::Signature;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::mem::Discriminant;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use transport::client::{ClientConnection, TransportClient};
+use transport::metrics::{StreamMetricHelper, TransportMetrics};
+use transport::{
+    ReplicaChannels, ReplicaReceivers, ReplicaSender, Subscription, TransportOpts, UniformAccountInfo,
+    UniformLifecycleInfo, UniformStartupInfo,
+};
+
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Remembers which notifications were delivered already. A notification is forgotten once every upstream delivered
+/// it, or once it is the least recently delivered one and the capacity is reached.
+struct Dedup<K: Hash + Eq> {
+    seen: LruCache<K, Seen>,
+    /// `None` never forgets delivered notifications, for lifecycle events which are replayed on every reconnect
+    forget_after: Option<usize>,
+}
+
+struct Seen {
+    first: Instant,
+    deliveries: usize,
+}
+
+impl<K: Hash + Eq> Dedup<K> {
+    fn new(capacity: NonZeroUsize, forget_after: Option<usize>) -> Self {
+        Self {
+            seen: LruCache::new(capacity),
+            forget_after,
+        }
+    }
+
+    /// Returns how long after the first delivery this one came, `None` if it is the first
+    fn deliver(&mut self, key: K) -> Option<Duration> {
+        let Some(seen) = self.seen.get_mut(&key) else {
+            self.seen.put(key, Seen { first: Instant::now(), deliveries: 1 });
+            return None;
+        };
+        seen.deliveries += 1;
+        let lag = seen.first.elapsed();
+        if self.forget_after.is_some_and(|upstreams| seen.deliveries >= upstreams) {
+            self.seen.pop(&key);
+        }
+        Some(lag)
+    }
+}
+
+/// The startup stream is only taken from the upstream that starts sending it first, startup accounts of different
+/// validators can't be matched up. If that upstream disconnects before finishing, the next one sending startup
+/// accounts takes over.
+#[derive(Default)]
+struct StartupOwner {
+    upstream: Option<usize>,
+    finished: bool,
+}
+
+impl StartupOwner {
+    fn disconnected(&mut self, index: usize, addr: SocketAddr) {
+        if !self.finished && self.upstream == Some(index) {
+            tracing::warn!(upstream = %addr, "upstream disconnected while streaming startup accounts, taking them from the next upstream");
+            self.upstream = None;
+        }
+    }
+}
+
+pub struct Upstreams {
+    /// Carries the reason to close the connections with once the gateway shuts down
+    shutdown: watch::Sender<Option<String>>,
+    supervisors: Vec<JoinHandle<()>>,
+}
+
+impl Upstreams {
+    /// Connects to every upstream, failing only if none of them can be reached. The notifications are sent on
+    /// `merged`, returns receivers for them.
+    pub async fn connect(
+        addrs: &[SocketAddr],
+        opts: TransportOpts,
+        merged: ReplicaChannels,
+        subscription: Subscription,
+        dedup_capacity: usize,
+        metrics: Option<Arc<TransportMetrics>>,
+    ) -> anyhow::Result<(ReplicaReceivers, Self)> {
+        let receivers = merged.subscribe();
+        let (shutdown, _) = watch::channel(None);
+
+        let merger = (addrs.len() > 1).then(|| Merger::new(&merged, addrs.len(), dedup_capacity, metrics.clone()));
+        let mut supervisors = Vec::with_capacity(addrs.len());
+        let mut connected = 0;
+        for (index, addr) in addrs.iter().enumerate() {
+            let channels = match &merger {
+                Some(merger) => {
+                    let channels = ReplicaChannels::with_defaults();
+                    merger.spawn(index, *addr, channels.subscribe());
+                    channels
+                }
+                None => merged.clone(),
+            };
+
+            let connection = match TransportClient::connect(
+                *addr,
+                opts.clone(),
+                channels.clone(),
+                subscription,
+                metrics.clone(),
+            ).await {
+                Ok((_, connection)) => {
+                    connected += 1;
+                    Some(connection)
+                }
+                Err(e) => {
+                    tracing::warn!(upstream = %addr, "failed to connect to upstream, retrying in the background: {}", e);
+                    None
+                }
+            };
+            let upstream = Upstream {
+                index,
+                addr: *addr,
+                opts: opts.clone(),
+                channels,
+                subscription,
+                startup: merger.as_ref().map(|merger| merger.startup.clone()),
+                metrics: metrics.clone(),
+            };
+            supervisors.push(tokio::spawn(upstream.supervise(connection, shutdown.subscribe())));
+        }
+
+        let upstreams = Self { shutdown, supervisors };
+        if connected == 0 {
+            upstreams.close("no upstream reachable").await;
+            bail!("failed to connect to any of the {} upstreams", addrs.len());
+        }
+        if addrs.len() > 1 {
+            tracing::info!(connected, upstreams = addrs.len(), "merging notifications of multiple upstreams");
+        }
+        Ok((receivers, upstreams))
+    }
+
+    /// Closes every upstream connection, no more notifications are received afterwards
+    pub async fn close(self, reason: &str) {
+        let _ = self.shutdown.send(Some(reason.to_string()));
+        for supervisor in self.supervisors {
+            let _ = supervisor.await;
+        }
+    }
+}
+
+/// Resolves once the gateway shuts down
+async fn shutdown_reason(shutdown: &mut watch::Receiver<Option<String>>) -> String {
+    match shutdown.wait_for(Option::is_some).await {
+        Ok(reason) => reason.clone().unwrap_or_default(),
+        Err(_) => "gateway stopped".to_string(),
+    }
+}
+
+struct Upstream {
+    index: usize,
+    addr: SocketAddr,
+    opts: TransportOpts,
+    /// Outlive the connections, so the merger keeps receiving from them after a reconnect
+    channels: ReplicaChannels,
+    subscription: Subscription,
+    /// Released when the connection dies, `None` without a merger
+    startup: Option<Arc<Mutex<StartupOwner>>>,
+    metrics: Option<Arc<TransportMetrics>>,
+}
+
+impl Upstream {
+    /// Reconnects with a backoff whenever the connection dies, until the gateway shuts down
+    async fn supervise(self, mut connection: Option<ClientConnection>, mut shutdown: watch::Receiver<Option<String>>) {
+        let name = self.addr.to_string();
+        let mut backoff = MIN_RECONNECT_BACKOFF;
+        loop {
+            if let Some(current) = connection.take() {
+                StreamMetricHelper::record_upstream_connected(self.metrics.as_ref(), &name, true);
+                let closing = tokio::select! {
+                    error = current.closed() => {
+                        tracing::warn!(upstream = %name, "upstream connection lost, reconnecting: {}", error);
+                        None
+                    }
+                    reason = shutdown_reason(&mut shutdown) => Some(reason),
+                };
+                if let Some(reason) = closing {
+                    current.close(&reason).await;
+                    return;
+                }
+                if let Some(startup) = &self.startup {
+                    startup.lock().unwrap().disconnected(self.index, self.addr);
+                }
+            }
+            StreamMetricHelper::record_upstream_connected(self.metrics.as_ref(), &name, false);
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = shutdown_reason(&mut shutdown) => return,
+            }
+            let connect = TransportClient::connect(
+                self.addr,
+                self.opts.clone(),
+                self.channels.clone(),
+                self.subscription,
+                self.metrics.clone(),
+            );
+            tokio::select! {
+                result = connect => match result {
+                    Ok((_, reconnected)) => {
+                        tracing::info!(upstream = %name, "reconnected to upstream");
+                        backoff = MIN_RECONNECT_BACKOFF;
+                        connection = Some(reconnected);
+                    }
+                    Err(e) => {
+                        tracing::warn!(upstream = %name, ?backoff, "failed to reconnect to upstream: {}", e);
+                        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                    }
+                },
+                _ = shutdown_reason(&mut shutdown) => return,
+            }
+        }
+    }
+}
+
+/// Pubkey, slot, the transaction that wrote the account and a hash of the written content. The `write_version` is
+/// counted by each validator on its own, so it doesn't match between upstreams.
+type AccountKey = (Pubkey, Slot, Option<Signature>, u64);
+
+fn account_key(account: &UniformAccountInfo) -> AccountKey {
+    let mut hasher = DefaultHasher::new();
+    account.lamports.hash(&mut hasher);
+    account.owner.hash(&mut hasher);
+    account.executable.hash(&mut hasher);
+    account.rent_epoch.hash(&mut hasher);
+    account.data.hash(&mut hasher);
+    (account.pubkey, account.slot, account.transaction_ref, hasher.finish())
+}
+
+/// Forwards the first copy of every notification from the upstreams to the merged channels
+struct Merger {
+    merged: ReplicaChannels,
+    accounts: Arc<Mutex<Dedup<AccountKey>>>,
+    transactions: Arc<Mutex<Dedup<Signature>>>,
+    entries: Arc<Mutex<Dedup<(Slot, usize)>>>,
+    blocks: Arc<Mutex<Dedup<Slot>>>,
+    slots: Arc<Mutex<Dedup<(Slot, Discriminant<SlotStatus>)>>>,
+    lifecycle: Arc<Mutex<Dedup<Discriminant<UniformLifecycleInfo>>>>,
+    startup: Arc<Mutex<StartupOwner>>,
+    metrics: Option<Arc<TransportMetrics>>,
+}
+
+impl Merger {
+    fn new(merged: &ReplicaChannels, upstreams: usize, capacity: usize, metrics: Option<Arc<TransportMetrics>>) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        let dedup = || Arc::new(Mutex::new(Dedup::new(capacity, Some(upstreams))));
+        Self {
+            merged: merged.clone(),
+            accounts: dedup(),
+            transactions: dedup(),
+            entries: dedup(),
+            blocks: dedup(),
+            slots: dedup(),
+            lifecycle: Arc::new(Mutex::new(Dedup::new(capacity, None))),
+            startup: Arc::default(),
+            metrics,
+        }
+    }
+
+    fn spawn(&self, index: usize, addr: SocketAddr, receivers: ReplicaReceivers) {
+        let upstream: Arc<str> = addr.to_string().into();
+        let merged = &self.merged;
+        let metrics = &self.metrics;
+
+        tokio::spawn(forward(upstream.clone(), "account", receivers.account, merged.account.clone(),
+            self.accounts.clone(), account_key, metrics.clone()));
+        tokio::spawn(forward(upstream.clone(), "transaction", receivers.transaction, merged.transaction.clone(),
+            self.transactions.clone(), |txn| txn.signature, metrics.clone()));
+        tokio::spawn(forward(upstream.clone(), "entry", receivers.entry, merged.entry.clone(),
+            self.entries.clone(), |entry| (entry.slot, entry.index), metrics.clone()));
+        tokio::spawn(forward(upstream.clone(), "block", receivers.block, merged.block.clone(),
+            self.blocks.clone(), |block| block.slot, metrics.clone()));
+        tokio::spawn(forward(upstream.clone(), "slot", receivers.slot, merged.slot.clone(),
+            self.slots.clone(), |slot| (slot.slot, std::mem::discriminant(&slot.status)), metrics.clone()));
+        tokio::spawn(forward(upstream.clone(), "lifecycle", receivers.lifecycle, merged.lifecycle.clone(),
+            self.lifecycle.clone(), std::mem::discriminant, metrics.clone()));
+        tokio::spawn(forward_startup(index, upstream, receivers.startup, merged.startup.clone(), self.startup.clone()));
+    }
+}
+
+async fn forward<T, K>(
+    upstream: Arc<str>,
+    stream: &'static str,
+    mut receiver: broadcast::Receiver<T>,
+    merged: impl ReplicaSender<T>,
+    dedup: Arc<Mutex<Dedup<K>>>,
+    key: fn(&T) -> K,
+    metrics: Option<Arc<TransportMetrics>>,
+) where
+    T: Clone,
+    K: Hash + Eq,
+{
+    loop {
+        match receiver.recv().await {
+            Ok(notification) => {
+                let lag = dedup.lock().unwrap().deliver(key(&notification));
+                StreamMetricHelper::record_upstream_delivery(metrics.as_ref(), &upstream, stream, lag);
+                if lag.is_none() {
+                    merged.publish(notification);
+                }
+            }
+            Err(RecvError::Lagged(n)) => {
+                tracing::warn!(upstream = %upstream, stream, skipped = n, "upstream merger lagged, messages dropped");
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn forward_startup(
+    index: usize,
+    upstream: Arc<str>,
+    mut receiver: broadcast::Receiver<UniformStartupInfo>,
+    merged: broadcast::Sender<UniformStartupInfo>,
+    owner: Arc<Mutex<StartupOwner>>,
+) {
+    loop {
+        match receiver.recv().await {
+            Ok(startup) => {
+                let mut owner = owner.lock().unwrap();
+                if owner.finished || *owner.upstream.get_or_insert(index) != index {
+                    continue;
+                }
+                if matches!(startup, UniformStartupInfo::EndOfStartup) {
+                    owner.finished = true;
+                }
+                let _ = merged.send(startup);
+            }
+            Err(RecvError::Lagged(n)) => {
+                tracing::warn!(upstream = %upstream, stream = "startup", skipped = n, "upstream merger lagged, messages dropped");
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::UniformSlotInfo;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn account(data: Vec<u8>, write_version: u64) -> UniformAccountInfo {
+        UniformAccountInfo {
+            slot: 10,
+            pubkey: Pubkey::new_from_array([9; 32]),
+            owner: Pubkey::new_from_array([1; 32]),
+            lamports: 1,
+            data,
+            transaction_ref: None,
+            executable: false,
+            rent_epoch: 0,
+            write_version,
+        }
+    }
+
+    fn slot(slot: Slot, status: SlotStatus) -> UniformSlotInfo {
+        UniformSlotInfo { slot, parent: slot.checked_sub(1), status }
+    }
+
+    fn startup_write_version(received: Result<UniformStartupInfo, RecvError>) -> u64 {
+        match received {
+            Ok(UniformStartupInfo::Account(account)) => account.write_version,
+            _ => panic!("expected a startup account"),
+        }
+    }
+
+    #[test]
+    fn forgets_once_every_upstream_delivered() {
+        let mut dedup = Dedup::new(NonZeroUsize::new(16).unwrap(), Some(2));
+        assert!(dedup.deliver(1).is_none());
+        assert!(dedup.deliver(1).is_some());
+        // a third upstream sending it again, or a resend, goes through as a new notification
+        assert!(dedup.deliver(1).is_none());
+    }
+
+    #[test]
+    fn forgets_least_recently_delivered_at_capacity() {
+        let mut dedup = Dedup::new(NonZeroUsize::new(2).unwrap(), Some(2));
+        assert!(dedup.deliver(1).is_none());
+        assert!(dedup.deliver(2).is_none());
+        assert!(dedup.deliver(3).is_none());
+        assert!(dedup.deliver(1).is_none());
+        assert!(dedup.deliver(3).is_some());
+    }
+
+    #[test]
+    fn lifecycle_never_forgotten() {
+        let mut dedup = Dedup::new(NonZeroUsize::new(16).unwrap(), None);
+        assert!(dedup.deliver(1).is_none());
+        for _ in 0..4 {
+            assert!(dedup.deliver(1).is_some());
+        }
+    }
+
+    #[test]
+    fn account_key_ignores_write_version() {
+        assert_eq!(account_key(&account(vec![1], 5)), account_key(&account(vec![1], 700)));
+        assert_ne!(account_key(&account(vec![1], 5)), account_key(&account(vec![2], 5)));
+    }
+
+    #[test]
+    fn startup_released_only_by_unfinished_owner() {
+        let mut owner = StartupOwner { upstream: Some(0), finished: false };
+        owner.disconnected(1, addr(1));
+        assert_eq!(owner.upstream, Some(0));
+        owner.disconnected(0, addr(0));
+        assert_eq!(owner.upstream, None);
+
+        let mut owner = StartupOwner { upstream: Some(0), finished: true };
+        owner.disconnected(0, addr(0));
+        assert_eq!(owner.upstream, Some(0));
+    }
+
+    #[tokio::test]
+    async fn merges_first_copy_from_any_upstream() {
+        let merged = ReplicaChannels::with_defaults();
+        let mut receivers = merged.subscribe();
+        let merger = Merger::new(&merged, 2, 16, None);
+        let (first, second) = (ReplicaChannels::with_defaults(), ReplicaChannels::with_defaults());
+        merger.spawn(0, addr(0), first.subscribe());
+        merger.spawn(1, addr(1), second.subscribe());
+
+        first.account.send(account(vec![1], 5)).unwrap();
+        assert_eq!(receivers.account.recv().await.unwrap().write_version, 5);
+        // the other validator numbers its writes on its own
+        second.account.send(account(vec![1], 700)).unwrap();
+        second.account.send(account(vec![2], 701)).unwrap();
+        assert_eq!(receivers.account.recv().await.unwrap().write_version, 701);
+
+        second.slot.send(slot(10, SlotStatus::Processed)).unwrap();
+        assert!(matches!(receivers.slot.recv().await.unwrap().status, SlotStatus::Processed));
+        first.slot.send(slot(10, SlotStatus::Processed)).unwrap();
+        first.slot.send(slot(10, SlotStatus::Confirmed)).unwrap();
+        assert!(matches!(receivers.slot.recv().await.unwrap().status, SlotStatus::Confirmed));
+    }
+
+    #[tokio::test]
+    async fn startup_taken_from_one_upstream_until_it_disconnects() {
+        let merged = ReplicaChannels::with_defaults();
+        let mut receivers = merged.subscribe();
+        let merger = Merger::new(&merged, 2, 16, None);
+        let (first, second) = (ReplicaChannels::with_defaults(), ReplicaChannels::with_defaults());
+        merger.spawn(0, addr(0), first.subscribe());
+        merger.spawn(1, addr(1), second.subscribe());
+
+        first.startup.send(UniformStartupInfo::Account(account(vec![1], 1))).unwrap();
+        assert_eq!(startup_write_version(receivers.startup.recv().await), 1);
+
+        second.startup.send(UniformStartupInfo::Account(account(vec![1], 2))).unwrap();
+        while !second.startup.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        merger.startup.lock().unwrap().disconnected(0, addr(0));
+        second.startup.send(UniformStartupInfo::Account(account(vec![1], 3))).unwrap();
+        second.startup.send(UniformStartupInfo::EndOfStartup).unwrap();
+        assert_eq!(startup_write_version(receivers.startup.recv().await), 3);
+        assert!(matches!(receivers.startup.recv().await, Ok(UniformStartupInfo::EndOfStartup)));
+    }
+}
@@ -0,0 +1,231 @@
+//! Verifies that the data received for a slot is complete, using the counts carried by the block metadata and by the
+//! entries. The issues of a slot that fails verification are sent along with its assembled block, so consumers know to
+//! backfill it from another source.
+
+use crate::compat::SharedTransaction;
+use serde::Serialize;
+use std::sync::Arc;
+use transport::{UniformBlockInfo, UniformEntryInfo};
+
+/// Serialized with its [`kind`](Self::kind) as `kind`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VerificationIssue {
+    /// No block metadata arrived, so the counts can't be checked
+    MissingBlockMetadata,
+    /// Fewer transactions than the block metadata announced
+    MissingTransactions { expected: u64, received: u64 },
+    /// Fewer entries than the block metadata announced
+    MissingEntries { expected: u64, received: u64 },
+    /// A transaction index that was received more than once or lies beyond the announced transactions
+    UnexpectedTransaction { index: usize },
+    /// The first entry index not received while a later one was
+    EntryGap { index: usize },
+    /// An entry's first transaction doesn't follow the transactions of the entry before it
+    EntryTransactionIndex { index: usize, expected: usize, received: usize },
+    /// The entries' transaction counts don't add up to the block's
+    EntryTransactionTotal { expected: u64, received: u64 },
+}
+
+impl VerificationIssue {
+    /// Metric attribute of the issue
+    pub fn kind(&self) -> &'static str {
+        match self {
+            VerificationIssue::MissingBlockMetadata => "missing_block_metadata",
+            VerificationIssue::MissingTransactions { .. } => "missing_transactions",
+            VerificationIssue::MissingEntries { .. } => "missing_entries",
+            VerificationIssue::UnexpectedTransaction { .. } => "unexpected_transaction",
+            VerificationIssue::EntryGap { .. } => "entry_gap",
+            VerificationIssue::EntryTransactionIndex { .. } => "entry_transaction_index",
+            VerificationIssue::EntryTransactionTotal { .. } => "entry_transaction_total",
+        }
+    }
+}
+
+/// Result of verifying a slot, only the first issue of each kind is listed
+#[derive(Debug, Clone, Default)]
+pub struct SlotVerification {
+    pub issues: Vec<VerificationIssue>,
+}
+
+impl SlotVerification {
+    /// Transactions and entries must be sorted by their index
+    pub fn verify(
+        block: Option<&UniformBlockInfo>,
+        transactions: &[Arc<SharedTransaction>],
+        entries: &[Arc<UniformEntryInfo>],
+    ) -> Self {
+        let mut issues = Vec::new();
+        let Some(block) = block else {
+            issues.push(VerificationIssue::MissingBlockMetadata);
+            return Self { issues };
+        };
+
+        // counts and indexes the validator didn't report can't be checked
+        let received = transactions.len() as u64;
+        if let Some(expected) = block.executed_transaction_count {
+            if received < expected {
+                issues.push(VerificationIssue::MissingTransactions { expected, received });
+            }
+        }
+        let mut previous = None;
+        for txn in transactions {
+            let Some(index) = txn.replica.index else {
+                break;
+            };
+            if previous == Some(index) || block.executed_transaction_count.is_some_and(|count| index as u64 >= count) {
+                issues.push(VerificationIssue::UnexpectedTransaction { index });
+                break;
+            }
+            previous = Some(index);
+        }
+
+        let received = entries.len() as u64;
+        if let Some(expected) = block.entry_count {
+            if received < expected {
+                issues.push(VerificationIssue::MissingEntries { expected, received });
+            }
+        }
+        if let Some(index) = entries.iter().enumerate().find_map(|(i, entry)| (entry.index != i).then_some(i)) {
+            issues.push(VerificationIssue::EntryGap { index });
+        }
+
+        let mut next_transaction = 0;
+        for entry in entries {
+            let Some(starting_transaction_index) = entry.starting_transaction_index else {
+                break;
+            };
+            if starting_transaction_index != next_transaction {
+                issues.push(VerificationIssue::EntryTransactionIndex {
+                    index: entry.index,
+                    expected: next_transaction,
+                    received: starting_transaction_index,
+                });
+                break;
+            }
+            next_transaction += entry.executed_transaction_count as usize;
+        }
+        // only meaningful once every entry is there
+        if let (Some(entry_count), Some(expected)) = (block.entry_count, block.executed_transaction_count) {
+            if received >= entry_count {
+                let total: u64 = entries.iter().map(|entry| entry.executed_transaction_count).sum();
+                if total != expected {
+                    issues.push(VerificationIssue::EntryTransactionTotal { expected, received: total });
+                }
+            }
+        }
+
+        Self { issues }
+    }
+
+    pub fn needs_backfill(&self) -> bool {
+        !self.issues.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_transaction_status::RewardsAndNumPartitions;
+    use transport::UniformTransactionInfo;
+
+    fn block(transactions: u64, entries: u64) -> UniformBlockInfo {
+        UniformBlockInfo {
+            parent_slot: Some(9),
+            parent_blockhash: None,
+            slot: 10,
+            blockhash: String::new(),
+            rewards: RewardsAndNumPartitions { rewards: Vec::new(), num_partitions: None },
+            block_time: None,
+            block_height: None,
+            executed_transaction_count: Some(transactions),
+            entry_count: Some(entries),
+        }
+    }
+
+    fn transactions(indexes: &[usize]) -> Vec<Arc<SharedTransaction>> {
+        indexes.iter()
+            .map(|index| {
+                let replica = UniformTransactionInfo {
+                    slot: 10,
+                    signature: Default::default(),
+                    message_hash: Default::default(),
+                    is_vote: false,
+                    transaction: Default::default(),
+                    transaction_status_meta: Default::default(),
+                    index: Some(*index),
+                    writable: None,
+                };
+                Arc::new(SharedTransaction::new(replica, None))
+            })
+            .collect()
+    }
+
+    /// `(index, starting transaction index, transactions)` of each entry
+    fn entries(entries: &[(usize, usize, u64)]) -> Vec<Arc<UniformEntryInfo>> {
+        entries.iter()
+            .map(|&(index, starting_transaction_index, executed_transaction_count)| Arc::new(UniformEntryInfo {
+                slot: 10,
+                index,
+                num_hashes: 1,
+                hash: Default::default(),
+                executed_transaction_count,
+                starting_transaction_index: Some(starting_transaction_index),
+            }))
+            .collect()
+    }
+
+    fn issues(block: Option<&UniformBlockInfo>, indexes: &[usize], list: &[(usize, usize, u64)]) -> Vec<VerificationIssue> {
+        SlotVerification::verify(block, &transactions(indexes), &entries(list)).issues
+    }
+
+    #[test]
+    fn a_consistent_block_passes() {
+        let verification = SlotVerification::verify(
+            Some(&block(3, 2)),
+            &transactions(&[0, 1, 2]),
+            &entries(&[(0, 0, 2), (1, 2, 1)]),
+        );
+        assert!(!verification.needs_backfill());
+    }
+
+    #[test]
+    fn a_block_without_metadata_needs_a_backfill() {
+        assert_eq!(issues(None, &[0], &[(0, 0, 1)]), [VerificationIssue::MissingBlockMetadata]);
+    }
+
+    #[test]
+    fn reports_missing_transactions_and_entries() {
+        assert_eq!(issues(Some(&block(3, 2)), &[0, 1], &[(0, 0, 2)]), [
+            VerificationIssue::MissingTransactions { expected: 3, received: 2 },
+            VerificationIssue::MissingEntries { expected: 2, received: 1 },
+        ]);
+    }
+
+    #[test]
+    fn reports_duplicate_and_unannounced_transactions() {
+        assert_eq!(issues(Some(&block(2, 1)), &[0, 0], &[(0, 0, 2)]), [
+            VerificationIssue::UnexpectedTransaction { index: 0 },
+        ]);
+        assert_eq!(issues(Some(&block(2, 1)), &[0, 2], &[(0, 0, 2)]), [
+            VerificationIssue::UnexpectedTransaction { index: 2 },
+        ]);
+    }
+
+    #[test]
+    fn reports_gaps_between_entries() {
+        assert_eq!(issues(Some(&block(2, 2)), &[0, 1], &[(0, 0, 1), (2, 1, 1)]), [
+            VerificationIssue::EntryGap { index: 1 },
+        ]);
+    }
+
+    #[test]
+    fn reports_entries_whose_transactions_dont_line_up() {
+        assert_eq!(issues(Some(&block(3, 2)), &[0, 1, 2], &[(0, 0, 2), (1, 3, 1)]), [
+            VerificationIssue::EntryTransactionIndex { index: 1, expected: 2, received: 3 },
+        ]);
+        assert_eq!(issues(Some(&block(2, 2)), &[0, 1], &[(0, 0, 1), (1, 1, 2)]), [
+            VerificationIssue::EntryTransactionTotal { expected: 2, received: 3 },
+        ]);
+    }
+}
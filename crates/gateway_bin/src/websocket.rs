@@ -0,0 +1,742 @@
+//! Optional WebSocket API, built with the `websocket` cargo feature. Clients send JSON-RPC subscribe requests in the
+//! spirit of Solana's `accountSubscribe`/`slotSubscribe` and receive JSON notifications fed straight from the replica
+//! channels, or the blocks the gateway assembles. Every connection is limited in how many subscriptions it holds and
+//! how many notifications it is sent.
+//!
+//! ```text
+//! -> {"jsonrpc":"2.0","id":1,"method":"accountSubscribe","params":{"owners":["<base58>"],"encoding":"base64"}}
+//! <- {"jsonrpc":"2.0","id":1,"result":0}
+//! <- {"jsonrpc":"2.0","method":"accountNotification","params":{"subscription":0,"result":{...}}}
+//! ```
+
+use crate::block_assembler::CompleteBlock;
+use agave_geyser_plugin_interface::geyser_plugin_interface::SlotStatus;
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use solana_pubkey::Pubkey;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use transport::filters::{AccountFilter, TransactionFilter};
+use transport::metrics::{StreamMetricHelper, TransportMetrics};
+use transport::{
+    ReplicaChannels, UniformAccountInfo, UniformBlockInfo, UniformEntryInfo, UniformRollbackInfo, UniformSlotInfo,
+    UniformTransactionInfo,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Applied to every connection separately
+#[derive(Clone, Copy)]
+pub struct WebSocketLimits {
+    /// Notifications sent per second before further ones are dropped, 0 is unlimited
+    pub max_notifications_per_sec: u32,
+    pub max_subscriptions: usize,
+}
+
+pub struct WebSocketServer {
+    shutdown: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+}
+
+impl WebSocketServer {
+    /// `assembled_blocks` is `None` if the gateway doesn't assemble blocks
+    pub async fn spawn(
+        addr: SocketAddr,
+        replica_channels: ReplicaChannels,
+        assembled_blocks: Option<Sender<Arc<CompleteBlock>>>,
+        limits: WebSocketLimits,
+        metrics: Option<Arc<TransportMetrics>>,
+    ) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let (shutdown, stop) = watch::channel(false);
+        let handle = tokio::spawn(accept(listener, replica_channels, assembled_blocks, limits, metrics, stop));
+        tracing::info!(%addr, "WebSocket API listening");
+        Ok(Self { shutdown, handle })
+    }
+
+    /// Closes every client's connection and stops accepting new clients
+    pub async fn close(self) {
+        let _ = self.shutdown.send(true);
+        let _ = self.handle.await;
+    }
+}
+
+async fn accept(
+    listener: TcpListener,
+    replica_channels: ReplicaChannels,
+    assembled_blocks: Option<Sender<Arc<CompleteBlock>>>,
+    limits: WebSocketLimits,
+    metrics: Option<Arc<TransportMetrics>>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut clients = JoinSet::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = stop.wait_for(|stop| *stop) => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("failed to accept a WebSocket client: {}", e);
+                    continue;
+                }
+            },
+        };
+        while clients.try_join_next().is_some() {}
+
+        let client = Client::new(replica_channels.clone(), assembled_blocks.clone(), limits, metrics.clone());
+        let stop = stop.clone();
+        clients.spawn(async move {
+            let ws = match tokio_tungstenite::accept_async(stream).await {
+                Ok(ws) => ws,
+                Err(e) => {
+                    tracing::debug!(%peer, "WebSocket handshake failed: {}", e);
+                    return;
+                }
+            };
+            tracing::info!(%peer, "WebSocket client connected");
+            StreamMetricHelper::record_websocket_client(client.metrics.as_ref(), true);
+            let metrics = client.metrics.clone();
+            client.serve(ws, stop).await;
+            StreamMetricHelper::record_websocket_client(metrics.as_ref(), false);
+            tracing::info!(%peer, "WebSocket client disconnected");
+        });
+    }
+    // every client sees the stop as well and sends a close frame
+    while clients.join_next().await.is_some() {}
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    /// Slow for large account data
+    Base58,
+    #[default]
+    Base64,
+}
+
+impl Encoding {
+    /// Solana's `[data, encoding]` pair
+    fn encode(self, bytes: &[u8]) -> Value {
+        match self {
+            Encoding::Base58 => json!([bs58::encode(bytes).into_string(), "base58"]),
+            Encoding::Base64 => json!([base64::engine::general_purpose::STANDARD.encode(bytes), "base64"]),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+struct AccountParams {
+    pubkeys: Vec<String>,
+    owners: Vec<String>,
+    encoding: Encoding,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+struct TransactionParams {
+    include_votes: bool,
+    include_failed: bool,
+    accounts: Vec<String>,
+    encoding: Encoding,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+struct AssembledBlockParams {
+    encoding: Encoding,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct UnsubscribeParams {
+    subscription: u64,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+/// Missing params are the defaults, subscribing to everything of a stream
+fn parse_params<T: DeserializeOwned + Default>(params: Value) -> Result<T, RpcError> {
+    if params.is_null() {
+        return Ok(T::default());
+    }
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn parse_pubkeys(keys: &[String]) -> Result<HashSet<Pubkey>, RpcError> {
+    keys.iter()
+        .map(|key| {
+            bs58::decode(key).into_vec().ok()
+                .and_then(|bytes| Pubkey::try_from(bytes.as_slice()).ok())
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("invalid pubkey {}", key)))
+        })
+        .collect()
+}
+
+enum Subscription {
+    Account { filter: AccountFilter, encoding: Encoding },
+    Transaction { filter: TransactionFilter, encoding: Encoding },
+    Slot,
+    Block,
+    Entry,
+    Rollback,
+    AssembledBlock { encoding: Encoding },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Account,
+    Transaction,
+    Slot,
+    Block,
+    Entry,
+    Rollback,
+    AssembledBlock,
+}
+
+impl Subscription {
+    fn stream(&self) -> Stream {
+        match self {
+            Subscription::Account { .. } => Stream::Account,
+            Subscription::Transaction { .. } => Stream::Transaction,
+            Subscription::Slot => Stream::Slot,
+            Subscription::Block => Stream::Block,
+            Subscription::Entry => Stream::Entry,
+            Subscription::Rollback => Stream::Rollback,
+            Subscription::AssembledBlock { .. } => Stream::AssembledBlock,
+        }
+    }
+
+    /// The default for streams without encoded data
+    fn encoding(&self) -> Encoding {
+        match self {
+            Subscription::Account { encoding, .. }
+            | Subscription::Transaction { encoding, .. }
+            | Subscription::AssembledBlock { encoding } => *encoding,
+            _ => Encoding::default(),
+        }
+    }
+}
+
+impl Stream {
+    fn name(self) -> &'static str {
+        match self {
+            Stream::Account => "account",
+            Stream::Transaction => "transaction",
+            Stream::Slot => "slot",
+            Stream::Block => "block",
+            Stream::Entry => "entry",
+            Stream::Rollback => "rollback",
+            Stream::AssembledBlock => "assembledBlock",
+        }
+    }
+}
+
+/// Token bucket holding at most a second's worth of notifications
+struct RateLimit {
+    per_sec: f64,
+    tokens: f64,
+    refilled: Instant,
+    /// Dropped since the client was last told about it
+    dropped: u64,
+}
+
+impl RateLimit {
+    fn new(per_sec: u32) -> Self {
+        Self {
+            per_sec: per_sec as f64,
+            tokens: per_sec as f64,
+            refilled: Instant::now(),
+            dropped: 0,
+        }
+    }
+
+    fn allow(&mut self) -> bool {
+        if self.per_sec == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.per_sec;
+        self.tokens = (self.tokens + refill).min(self.per_sec);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            self.dropped += 1;
+            false
+        }
+    }
+}
+
+/// A stream is only subscribed while the client has a subscription to it, so the others can't make it lag
+#[derive(Default)]
+struct StreamReceivers {
+    account: Option<Receiver<UniformAccountInfo>>,
+    transaction: Option<Receiver<UniformTransactionInfo>>,
+    slot: Option<Receiver<UniformSlotInfo>>,
+    block: Option<Receiver<UniformBlockInfo>>,
+    entry: Option<Receiver<UniformEntryInfo>>,
+    rollback: Option<Receiver<UniformRollbackInfo>>,
+    assembled_block: Option<Receiver<Arc<CompleteBlock>>>,
+}
+
+/// Pending forever for a stream the client has no subscription to
+async fn recv<T: Clone>(receiver: &mut Option<Receiver<T>>) -> Result<T, RecvError> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+enum Input {
+    Stop,
+    Message(Option<Result<Message, tokio_tungstenite::tungstenite::Error>>),
+    Account(Result<UniformAccountInfo, RecvError>),
+    Transaction(Result<UniformTransactionInfo, RecvError>),
+    Slot(Result<UniformSlotInfo, RecvError>),
+    Block(Result<UniformBlockInfo, RecvError>),
+    Entry(Result<UniformEntryInfo, RecvError>),
+    Rollback(Result<UniformRollbackInfo, RecvError>),
+    AssembledBlock(Result<Arc<CompleteBlock>, RecvError>),
+}
+
+struct Client {
+    replica_channels: ReplicaChannels,
+    assembled_blocks: Option<Sender<Arc<CompleteBlock>>>,
+    limits: WebSocketLimits,
+    metrics: Option<Arc<TransportMetrics>>,
+    subscriptions: HashMap<u64, Subscription>,
+    next_subscription: u64,
+    receivers: StreamReceivers,
+    rate_limit: RateLimit,
+}
+
+impl Client {
+    fn new(
+        replica_channels: ReplicaChannels,
+        assembled_blocks: Option<Sender<Arc<CompleteBlock>>>,
+        limits: WebSocketLimits,
+        metrics: Option<Arc<TransportMetrics>>,
+    ) -> Self {
+        Self {
+            replica_channels,
+            assembled_blocks,
+            limits,
+            metrics,
+            subscriptions: HashMap::new(),
+            next_subscription: 0,
+            receivers: StreamReceivers::default(),
+            rate_limit: RateLimit::new(limits.max_notifications_per_sec),
+        }
+    }
+
+    async fn serve(mut self, ws: WebSocketStream<TcpStream>, mut stop: watch::Receiver<bool>) {
+        let (mut sink, mut incoming) = ws.split();
+        loop {
+            let input = tokio::select! {
+                _ = stop.wait_for(|stop| *stop) => Input::Stop,
+                message = incoming.next() => Input::Message(message),
+                account = recv(&mut self.receivers.account) => Input::Account(account),
+                txn = recv(&mut self.receivers.transaction) => Input::Transaction(txn),
+                slot = recv(&mut self.receivers.slot) => Input::Slot(slot),
+                block = recv(&mut self.receivers.block) => Input::Block(block),
+                entry = recv(&mut self.receivers.entry) => Input::Entry(entry),
+                rollback = recv(&mut self.receivers.rollback) => Input::Rollback(rollback),
+                assembled = recv(&mut self.receivers.assembled_block) => Input::AssembledBlock(assembled),
+            };
+
+            let outgoing = match input {
+                Input::Stop => {
+                    let _ = sink.send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "gateway shutting down".into(),
+                    }))).await;
+                    return;
+                }
+                Input::Message(Some(Ok(Message::Text(text)))) => vec![self.request(&text)],
+                Input::Message(Some(Ok(Message::Binary(_)))) => {
+                    vec![error_response(Value::Null, RpcError::new(INVALID_REQUEST, "requests must be text messages"))]
+                }
+                // pings are answered by tungstenite
+                Input::Message(Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_)))) => Vec::new(),
+                Input::Message(Some(Ok(Message::Close(_))) | None) => return,
+                Input::Message(Some(Err(e))) => {
+                    tracing::debug!("WebSocket client failed: {}", e);
+                    return;
+                }
+                Input::Account(account) => self.received(Stream::Account, account, |client, account| {
+                    client.notify(
+                        Stream::Account,
+                        |subscription| matches!(subscription, Subscription::Account { filter, .. } if filter.matches(&account)),
+                        |encoding| Ok(account_json(&account, encoding)),
+                    )
+                }),
+                Input::Transaction(txn) => self.received(Stream::Transaction, txn, |client, txn| {
+                    client.notify(
+                        Stream::Transaction,
+                        |subscription| matches!(subscription, Subscription::Transaction { filter, .. } if filter.matches(&txn)),
+                        |encoding| transaction_json(&txn, encoding),
+                    )
+                }),
+                // built once for every subscription, and only if one of them is within the rate limit
+                Input::Slot(slot) => self.received(Stream::Slot, slot, |client, slot| {
+                    let mut json = None;
+                    client.notify(Stream::Slot, |_| true, |_| Ok(json.get_or_insert_with(|| slot_json(&slot)).clone()))
+                }),
+                Input::Block(block) => self.received(Stream::Block, block, |client, block| {
+                    let mut json = None;
+                    client.notify(Stream::Block, |_| true, |_| {
+                        json.get_or_insert_with(|| block_json(&block)).as_ref()
+                            .cloned()
+                            .map_err(|e| anyhow::anyhow!("{:#}", e))
+                    })
+                }),
+                Input::Entry(entry) => self.received(Stream::Entry, entry, |client, entry| {
+                    let mut json = None;
+                    client.notify(Stream::Entry, |_| true, |_| Ok(json.get_or_insert_with(|| entry_json(&entry)).clone()))
+                }),
+                Input::Rollback(rollback) => self.received(Stream::Rollback, rollback, |client, rollback| {
+                    let mut json = None;
+                    client.notify(Stream::Rollback, |_| true, |_| {
+                        Ok(json.get_or_insert_with(|| rollback_json(&rollback)).clone())
+                    })
+                }),
+                Input::AssembledBlock(assembled) => self.received(Stream::AssembledBlock, assembled, |client, assembled| {
+                    client.notify(
+                        Stream::AssembledBlock,
+                        |_| true,
+                        |encoding| assembled_block_json(&assembled, encoding),
+                    )
+                }),
+            };
+
+            for message in outgoing {
+                if sink.send(Message::text(message.to_string())).await.is_err() {
+                    return;
+                }
+            }
+            if self.receivers_closed() {
+                let _ = sink.send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "upstream feed ended".into(),
+                }))).await;
+                return;
+            }
+        }
+    }
+
+    fn request(&mut self, text: &str) -> Value {
+        let request: Request = match serde_json::from_str(text) {
+            Ok(request) => request,
+            Err(e) => return error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string())),
+        };
+        let id = request.id;
+        match self.handle(&request.method, request.params) {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(e) => error_response(id, e),
+        }
+    }
+
+    fn handle(&mut self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let subscription = match method {
+            "accountSubscribe" => {
+                let params: AccountParams = parse_params(params)?;
+                Subscription::Account {
+                    filter: AccountFilter {
+                        pubkeys: parse_pubkeys(&params.pubkeys)?,
+                        owners: parse_pubkeys(&params.owners)?,
+                    },
+                    encoding: params.encoding,
+                }
+            }
+            "transactionSubscribe" => {
+                let params: TransactionParams = parse_params(params)?;
+                Subscription::Transaction {
+                    filter: TransactionFilter {
+                        include_votes: params.include_votes,
+                        include_failed: params.include_failed,
+                        accounts: parse_pubkeys(&params.accounts)?,
+                    },
+                    encoding: params.encoding,
+                }
+            }
+            "slotSubscribe" => Subscription::Slot,
+            "blockSubscribe" => Subscription::Block,
+            "entrySubscribe" => Subscription::Entry,
+            "rollbackSubscribe" => Subscription::Rollback,
+            "assembledBlockSubscribe" => {
+                if self.assembled_blocks.is_none() {
+                    return Err(RpcError::new(INVALID_REQUEST, "the gateway doesn't assemble blocks"));
+                }
+                let params: AssembledBlockParams = parse_params(params)?;
+                Subscription::AssembledBlock { encoding: params.encoding }
+            }
+            "unsubscribe" => {
+                let params: UnsubscribeParams = parse_params(params)?;
+                return Ok(Value::Bool(self.unsubscribe(params.subscription)));
+            }
+            _ => return Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+        };
+
+        if self.subscriptions.len() >= self.limits.max_subscriptions {
+            return Err(RpcError::new(
+                INVALID_REQUEST,
+                format!("at most {} subscriptions per connection", self.limits.max_subscriptions),
+            ));
+        }
+        let id = self.next_subscription;
+        self.next_subscription += 1;
+        self.subscribe_stream(subscription.stream());
+        self.subscriptions.insert(id, subscription);
+        Ok(Value::from(id))
+    }
+
+    fn subscribe_stream(&mut self, stream: Stream) {
+        let channels = &self.replica_channels;
+        let receivers = &mut self.receivers;
+        match stream {
+            Stream::Account => { receivers.account.get_or_insert_with(|| channels.account.subscribe()); }
+            Stream::Transaction => { receivers.transaction.get_or_insert_with(|| channels.transaction.subscribe()); }
+            Stream::Slot => { receivers.slot.get_or_insert_with(|| channels.slot.subscribe()); }
+            Stream::Block => { receivers.block.get_or_insert_with(|| channels.block.subscribe()); }
+            Stream::Entry => { receivers.entry.get_or_insert_with(|| channels.entry.subscribe()); }
+            Stream::Rollback => { receivers.rollback.get_or_insert_with(|| channels.rollback.subscribe()); }
+            Stream::AssembledBlock => {
+                if let Some(assembled_blocks) = &self.assembled_blocks {
+                    receivers.assembled_block.get_or_insert_with(|| assembled_blocks.subscribe());
+                }
+            }
+        }
+    }
+
+    fn unsubscribe(&mut self, id: u64) -> bool {
+        let Some(subscription) = self.subscriptions.remove(&id) else {
+            return false;
+        };
+        let stream = subscription.stream();
+        if !self.subscriptions.values().any(|subscription| subscription.stream() == stream) {
+            match stream {
+                Stream::Account => self.receivers.account = None,
+                Stream::Transaction => self.receivers.transaction = None,
+                Stream::Slot => self.receivers.slot = None,
+                Stream::Block => self.receivers.block = None,
+                Stream::Entry => self.receivers.entry = None,
+                Stream::Rollback => self.receivers.rollback = None,
+                Stream::AssembledBlock => self.receivers.assembled_block = None,
+            }
+        }
+        true
+    }
+
+    /// A lagging client is told how much it missed and keeps its subscriptions
+    fn received<T>(
+        &mut self,
+        stream: Stream,
+        received: Result<T, RecvError>,
+        notify: impl FnOnce(&mut Self, T) -> Vec<Value>,
+    ) -> Vec<Value> {
+        match received {
+            Ok(value) => notify(self, value),
+            Err(RecvError::Lagged(skipped)) => vec![json!({
+                "jsonrpc": "2.0",
+                "method": "lagged",
+                "params": {"stream": stream.name(), "skipped": skipped},
+            })],
+            Err(RecvError::Closed) => {
+                self.receivers = StreamReceivers::default();
+                Vec::new()
+            }
+        }
+    }
+
+    /// One notification per subscription of the stream that `matches`, as far as the rate limit allows. The result is
+    /// only built for notifications within the rate limit, one that fails to serialize is logged and skipped.
+    fn notify(
+        &mut self,
+        stream: Stream,
+        matches: impl Fn(&Subscription) -> bool,
+        mut result: impl FnMut(Encoding) -> anyhow::Result<Value>,
+    ) -> Vec<Value> {
+        let mut notifications = Vec::new();
+        for (id, subscription) in &self.subscriptions {
+            if subscription.stream() != stream || !matches(subscription) {
+                continue;
+            }
+            if !self.rate_limit.allow() {
+                StreamMetricHelper::record_websocket_rate_limited(self.metrics.as_ref(), 1);
+                continue;
+            }
+            let result = match result(subscription.encoding()) {
+                Ok(result) => result,
+                Err(e) => {
+                    tracing::error!(stream = stream.name(), "failed to serialize notification, skipping it: {:#}", e);
+                    continue;
+                }
+            };
+            if self.rate_limit.dropped > 0 {
+                notifications.push(json!({
+                    "jsonrpc": "2.0",
+                    "method": "rateLimited",
+                    "params": {"dropped": std::mem::take(&mut self.rate_limit.dropped)},
+                }));
+            }
+            notifications.push(json!({
+                "jsonrpc": "2.0",
+                "method": format!("{}Notification", stream.name()),
+                "params": {"subscription": id, "result": result},
+            }));
+        }
+        notifications
+    }
+
+    /// Set once the replica channels closed, the upstream feed won't resume
+    fn receivers_closed(&self) -> bool {
+        !self.subscriptions.is_empty()
+            && self.receivers.account.is_none()
+            && self.receivers.transaction.is_none()
+            && self.receivers.slot.is_none()
+            && self.receivers.block.is_none()
+            && self.receivers.entry.is_none()
+            && self.receivers.rollback.is_none()
+            && self.receivers.assembled_block.is_none()
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": error.code, "message": error.message}})
+}
+
+fn base58(bytes: &[u8]) -> String {
+    bs58::encode(bytes).into_string()
+}
+
+fn account_json(account: &UniformAccountInfo, encoding: Encoding) -> Value {
+    json!({
+        "slot": account.slot,
+        "pubkey": base58(account.pubkey.as_ref()),
+        "owner": base58(account.owner.as_ref()),
+        "lamports": account.lamports,
+        "data": encoding.encode(&account.data),
+        "executable": account.executable,
+        "rentEpoch": account.rent_epoch,
+        "writeVersion": account.write_version,
+        "txnSignature": account.transaction_ref.map(|signature| base58(signature.as_ref())),
+    })
+}
+
+fn transaction_json(txn: &UniformTransactionInfo, encoding: Encoding) -> anyhow::Result<Value> {
+    Ok(json!({
+        "slot": txn.slot,
+        "signature": base58(txn.signature.as_ref()),
+        "isVote": txn.is_vote,
+        "index": txn.index,
+        "failed": txn.transaction_status_meta.status.is_err(),
+        // bincode serialized `VersionedTransaction`
+        "transaction": encoding.encode(&bincode::serialize(&txn.transaction)?),
+        "meta": serde_json::to_value(&txn.transaction_status_meta)?,
+    }))
+}
+
+fn slot_json(info: &UniformSlotInfo) -> Value {
+    let (status, dead_error) = match &info.status {
+        SlotStatus::Processed => ("processed", None),
+        SlotStatus::Rooted => ("rooted", None),
+        SlotStatus::Confirmed => ("confirmed", None),
+        SlotStatus::FirstShredReceived => ("firstShredReceived", None),
+        SlotStatus::Completed => ("completed", None),
+        SlotStatus::CreatedBank => ("createdBank", None),
+        SlotStatus::Dead(error) => ("dead", Some(error)),
+    };
+    json!({
+        "slot": info.slot,
+        "parent": info.parent,
+        "status": status,
+        "deadError": dead_error,
+    })
+}
+
+fn block_json(block: &UniformBlockInfo) -> anyhow::Result<Value> {
+    Ok(json!({
+        "slot": block.slot,
+        "parentSlot": block.parent_slot,
+        "blockhash": block.blockhash,
+        "parentBlockhash": block.parent_blockhash,
+        "blockTime": block.block_time,
+        "blockHeight": block.block_height,
+        "executedTransactionCount": block.executed_transaction_count,
+        "entryCount": block.entry_count,
+        "rewards": serde_json::to_value(&block.rewards.rewards)?,
+        "numPartitions": block.rewards.num_partitions,
+    }))
+}
+
+fn entry_json(entry: &UniformEntryInfo) -> Value {
+    json!({
+        "slot": entry.slot,
+        "index": entry.index,
+        "numHashes": entry.num_hashes,
+        "hash": base58(&entry.hash.to_bytes()),
+        "executedTransactionCount": entry.executed_transaction_count,
+        "startingTransactionIndex": entry.starting_transaction_index,
+    })
+}
+
+fn rollback_json(rollback: &UniformRollbackInfo) -> Value {
+    json!({
+        "root": rollback.root,
+        "slots": rollback.slots,
+        "pubkeys": rollback.pubkeys.iter().map(|pubkey| base58(pubkey.as_ref())).collect::<Vec<_>>(),
+    })
+}
+
+fn assembled_block_json(assembled: &CompleteBlock, encoding: Encoding) -> anyhow::Result<Value> {
+    Ok(json!({
+        "slot": assembled.slot,
+        "block": assembled.block.as_deref().map(block_json).transpose()?,
+        "transactions": assembled.transactions.iter()
+            .map(|txn| transaction_json(&txn.replica, encoding))
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        "entries": assembled.entries.iter().map(|entry| entry_json(entry)).collect::<Vec<_>>(),
+        "transactionsComplete": assembled.transactions_complete,
+        "entriesComplete": assembled.entries_complete,
+        "indexVerified": assembled.index_verified,
+        // empty if the block passed verification, a block with issues should be backfilled from another source
+        "issues": assembled.verification.issues,
+    }))
+}
@@ -1,47 +1,266 @@
-
-use std::sync::atomic::{AtomicU64};
-use std::time::Duration;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use solana_pubkey::Pubkey;
+use transport::metrics::{StreamMetricHelper, TransportMetrics};
 use transport::UniformAccountInfo;
 
+/// Decides which coalescing window (if any) an account update goes into, based on its owner program.
+pub struct CoalescePolicy {
+    /// Window used for owners that aren't listed in `program_durations`, `None` forwards them untouched
+    default_duration: Option<Duration>,
+    /// Per owner program windows, `None` means updates owned by that program are never coalesced
+    program_durations: HashMap<Pubkey, Option<Duration>>,
+}
+
+impl CoalescePolicy {
+    pub fn new(default_duration: Option<Duration>, program_durations: HashMap<Pubkey, Option<Duration>>) -> Self {
+        Self {
+            default_duration,
+            program_durations,
+        }
+    }
+
+    fn duration_for(&self, owner: &Pubkey) -> Option<Duration> {
+        match self.program_durations.get(owner) {
+            Some(duration) => *duration,
+            None => self.default_duration,
+        }
+    }
+
+    fn distinct_durations(&self) -> Vec<Duration> {
+        let mut durations: Vec<Duration> = self.program_durations.values()
+            .chain(std::iter::once(&self.default_duration))
+            .filter_map(|d| *d)
+            .collect();
+        durations.sort();
+        durations.dedup();
+        durations
+    }
+
+    /// The shortest configured window, which is how often the background flusher has to run.
+    pub fn min_duration(&self) -> Option<Duration> {
+        self.distinct_durations().first().copied()
+    }
+}
+
+/// Account coalescer sharded by pubkey, it's called from the validator's replay threads so every shard has its own
+/// lock and flushing schedule, and buffered updates are always emitted after the shard lock has been released.
 pub struct AccountCoalescer {
-    inner: parking_lot::Mutex<AccountCoalescerInner>,
-    coalesce_duration: Duration,
-    coalesced_count: AtomicU64
+    shards: Box<[CoalescerShard]>,
+    shard_mask: usize,
+    policy: CoalescePolicy,
+    metrics: Option<Arc<TransportMetrics>>,
 }
 
-struct AccountCoalescerInner {
-    buffer: std::collections::HashMap<Pubkey, UniformAccountInfo>,
-    last_coalesce_time: std::time::Instant
+// Aligned to avoid false sharing between shards locked from different replay threads
+#[repr(align(128))]
+struct CoalescerShard {
+    windows: parking_lot::Mutex<Vec<CoalesceWindow>>,
+}
+
+struct CoalesceWindow {
+    duration: Duration,
+    buffer: HashMap<Pubkey, UniformAccountInfo>,
+    last_coalesce_time: Instant,
+}
+
+impl CoalescerShard {
+    fn new(durations: &[Duration], now: Instant) -> Self {
+        let windows = durations.iter()
+            .map(|duration| CoalesceWindow {
+                duration: *duration,
+                buffer: HashMap::new(),
+                last_coalesce_time: now,
+            })
+            .collect();
+
+        Self {
+            windows: parking_lot::Mutex::new(windows),
+        }
+    }
+}
+
+/// Moves the contents of every window that is due out of the shard, so they can be emitted without holding the lock.
+fn take_due_windows(windows: &mut [CoalesceWindow], now: Instant, due: &mut Vec<HashMap<Pubkey, UniformAccountInfo>>) {
+    for window in windows.iter_mut() {
+        if now.duration_since(window.last_coalesce_time) >= window.duration {
+            window.last_coalesce_time = now;
+            if !window.buffer.is_empty() {
+                due.push(std::mem::take(&mut window.buffer));
+            }
+        }
+    }
 }
 
 impl AccountCoalescer {
-    pub fn new(coalesce_duration: Duration) -> Self {
+    pub fn new(policy: CoalescePolicy, metrics: Option<Arc<TransportMetrics>>) -> Self {
+        let parallelism = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(16);
+        let shard_count = (parallelism * 4).next_power_of_two();
+
+        let now = Instant::now();
+        let durations = policy.distinct_durations();
+        let shards = (0..shard_count)
+            .map(|_| CoalescerShard::new(&durations, now))
+            .collect();
+
         Self {
-            inner: parking_lot::Mutex::new(AccountCoalescerInner {
-                buffer: std::collections::HashMap::new(),
-                last_coalesce_time: std::time::Instant::now(),
-            }),
-            coalesce_duration,
-            coalesced_count: Default::default(),
+            shards,
+            shard_mask: shard_count - 1,
+            policy,
+            metrics,
+        }
+    }
+
+    fn shard(&self, pubkey: &Pubkey) -> &CoalescerShard {
+        // Pubkeys are uniformly distributed, so their leading bytes are a good enough shard key
+        let bytes = pubkey.as_ref();
+        let key = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+        &self.shards[key & self.shard_mask]
+    }
+
+    /// Buffers the update in its owner's coalescing window, keeping only the highest `write_version` per pubkey.
+    /// Updates from programs that aren't coalesced, and any windows of this shard that are due, are passed to `emit`.
+    ///
+    /// An account is buffered in at most one window, an update still pending in the window of the account's previous
+    /// owner is emitted first, so it's never emitted after the newer update of the new owner.
+    pub fn coalesce(&self, replica: UniformAccountInfo, mut emit: impl FnMut(UniformAccountInfo)) {
+        let duration = self.policy.duration_for(&replica.owner);
+
+        let now = Instant::now();
+        let mut superseded = None;
+        let mut forwarded = None;
+        let mut due = Vec::new();
+        {
+            let mut windows = self.shard(&replica.pubkey).windows.lock();
+
+            for window in windows.iter_mut().filter(|w| Some(w.duration) != duration) {
+                if let Some(pending) = window.buffer.remove(&replica.pubkey) {
+                    superseded = Some(pending);
+                }
+            }
+
+            match windows.iter_mut().find(|w| Some(w.duration) == duration) {
+                Some(window) => match window.buffer.entry(replica.pubkey) {
+                    Entry::Occupied(mut entry) => {
+                        if replica.write_version >= entry.get().write_version {
+                            entry.insert(replica);
+                        }
+                        StreamMetricHelper::record_coalesced_accounts(self.metrics.as_ref(), 1);
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(replica);
+                    }
+                },
+                None => forwarded = Some(replica),
+            }
+
+            take_due_windows(&mut windows, now, &mut due);
         }
+
+        superseded.into_iter().chain(forwarded).for_each(&mut emit);
+        due.into_iter().flatten().for_each(|(_k, v)| emit(v));
     }
-    pub fn coalesce(&self, replica: UniformAccountInfo) -> Option<Vec<UniformAccountInfo>> {
-        let now = std::time::Instant::now();
-        let mut inner = self.inner.lock();
 
-        if inner.buffer.insert(replica.pubkey, replica).is_some() {
-            if self.coalesced_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed) % 1000 == 0 {
-                tracing::debug!("coalesced {} account updates", self.coalesced_count.load(std::sync::atomic::Ordering::Relaxed));
+    /// Flushes every shard window that is due, this is what guarantees that updates to rarely touched shards don't
+    /// sit in the buffer forever. Shards that are currently locked by a replay thread are skipped, they get flushed
+    /// by that thread or on the next tick.
+    pub fn flush_expired(&self, mut emit: impl FnMut(UniformAccountInfo)) {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for shard in self.shards.iter() {
+            if let Some(mut windows) = shard.windows.try_lock() {
+                take_due_windows(&mut windows, now, &mut due);
             }
+            due.drain(..).flatten().for_each(|(_k, v)| emit(v));
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if now.duration_since(inner.last_coalesce_time) >= self.coalesce_duration {
-            let coalesced_accounts: Vec<UniformAccountInfo> = inner.buffer.drain().map(|(_k, v)| v).collect();
-            inner.last_coalesce_time = now;
-            Some(coalesced_accounts)
-        } else {
-            None
+    const COALESCED: Pubkey = Pubkey::new_from_array([1; 32]);
+    const SLOWER: Pubkey = Pubkey::new_from_array([2; 32]);
+    const UNCOALESCED: Pubkey = Pubkey::new_from_array([3; 32]);
+
+    fn coalescer() -> AccountCoalescer {
+        let programs = HashMap::from([
+            (COALESCED, Some(Duration::from_secs(60))),
+            (SLOWER, Some(Duration::from_secs(120))),
+            (UNCOALESCED, None),
+        ]);
+        AccountCoalescer::new(CoalescePolicy::new(None, programs), None)
+    }
+
+    fn account(owner: Pubkey, write_version: u64) -> UniformAccountInfo {
+        UniformAccountInfo {
+            slot: 10,
+            pubkey: Pubkey::new_from_array([9; 32]),
+            owner,
+            lamports: 1,
+            data: Vec::new(),
+            transaction_ref: None,
+            executable: false,
+            rent_epoch: 0,
+            write_version,
+        }
+    }
+
+    /// Write versions of the emitted updates
+    fn coalesce(coalescer: &AccountCoalescer, replica: UniformAccountInfo) -> Vec<u64> {
+        let mut emitted = Vec::new();
+        coalescer.coalesce(replica, |replica| emitted.push(replica.write_version));
+        emitted
+    }
+
+    /// Makes every window due
+    fn flush(coalescer: &AccountCoalescer) -> Vec<u64> {
+        for shard in coalescer.shards.iter() {
+            for window in shard.windows.lock().iter_mut() {
+                if let Some(due) = window.last_coalesce_time.checked_sub(window.duration) {
+                    window.last_coalesce_time = due;
+                }
+            }
         }
+        let mut emitted = Vec::new();
+        coalescer.flush_expired(|replica| emitted.push(replica.write_version));
+        emitted
+    }
+
+    #[test]
+    fn forwards_uncoalesced_owners_right_away() {
+        let coalescer = coalescer();
+        assert_eq!(coalesce(&coalescer, account(UNCOALESCED, 1)), [1]);
+        assert_eq!(coalesce(&coalescer, account(Pubkey::default(), 2)), [2]);
+        assert!(flush(&coalescer).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_newest_update_of_a_window() {
+        let coalescer = coalescer();
+        assert!(coalesce(&coalescer, account(COALESCED, 2)).is_empty());
+        assert!(coalesce(&coalescer, account(COALESCED, 1)).is_empty());
+        assert!(coalesce(&coalescer, account(COALESCED, 3)).is_empty());
+        assert_eq!(flush(&coalescer), [3]);
+        assert!(flush(&coalescer).is_empty());
+    }
+
+    #[test]
+    fn emits_the_pending_update_before_one_of_an_uncoalesced_owner() {
+        let coalescer = coalescer();
+        assert!(coalesce(&coalescer, account(COALESCED, 1)).is_empty());
+        assert_eq!(coalesce(&coalescer, account(UNCOALESCED, 2)), [1, 2]);
+        assert!(flush(&coalescer).is_empty());
+    }
+
+    #[test]
+    fn moves_the_account_to_the_window_of_its_new_owner() {
+        let coalescer = coalescer();
+        assert!(coalesce(&coalescer, account(COALESCED, 1)).is_empty());
+        assert_eq!(coalesce(&coalescer, account(SLOWER, 2)), [1]);
+        assert_eq!(flush(&coalescer), [2]);
     }
 }
@@ -1,20 +1,61 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
 use serde::Deserialize;
+use solana_pubkey::Pubkey;
 use transport::server::{TransportServerConfig};
 use transport::TransportOpts;
+use crate::account_coalescer::CoalescePolicy;
+use crate::startup_forwarder::StartupForwarderConfig;
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct AmpleGeyserProxyConfig {
     #[serde(rename = "libpath")]
     _libpath: String,
     _comment: Option<String>,
-    
+
     pub transport_opts: TransportOpts,
     pub transport_cfg: TransportServerConfig,
     pub bind_addr: SocketAddr,
     pub log_level: String,
     pub use_account_coalescer: bool,
-    pub account_coalescer_duration_us: u64
+    pub account_coalescer_duration_us: u64,
+    /// Coalescing window overrides keyed by owner program id, a window of 0 disables coalescing for that program
+    #[serde(default)]
+    pub account_coalescer_program_durations_us: HashMap<String, u64>,
+    /// Whether owners missing from `account_coalescer_program_durations_us` use the default window or are never coalesced
+    #[serde(default = "default_true")]
+    pub account_coalescer_coalesce_unlisted_programs: bool,
+    pub metrics_otlp_url: Option<String>,
+    /// Stream the accounts restored from the validator's snapshot to gateways on the low priority startup stream
+    #[serde(default)]
+    pub forward_startup_accounts: bool,
+    #[serde(default = "default_startup_accounts_per_second")]
+    pub startup_accounts_per_second: u64,
+    #[serde(default = "default_startup_queue_size")]
+    pub startup_queue_size: usize,
+    /// Only forward startup accounts owned by these programs, forwards everything when empty
+    #[serde(default)]
+    pub startup_account_owners: Vec<String>,
+    #[serde(default = "default_startup_gateway_wait_secs")]
+    pub startup_gateway_wait_secs: u64,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_startup_accounts_per_second() -> u64 {
+    100_000
+}
+
+fn default_startup_queue_size() -> usize {
+    65_536
+}
+
+fn default_startup_gateway_wait_secs() -> u64 {
+    60
 }
 
 impl AmpleGeyserProxyConfig {
@@ -23,4 +64,44 @@ impl AmpleGeyserProxyConfig {
         let json = serde_json::from_str(&contents)?;
         Ok(json)
     }
+
+    pub fn account_coalescer_policy(&self) -> Result<CoalescePolicy, std::io::Error> {
+        let window = |duration_us: u64| (duration_us > 0).then_some(Duration::from_micros(duration_us));
+
+        let default_duration = if self.account_coalescer_coalesce_unlisted_programs {
+            window(self.account_coalescer_duration_us)
+        } else {
+            None
+        };
+
+        let program_durations = self.account_coalescer_program_durations_us
+            .iter()
+            .map(|(program, duration_us)| {
+                let program = Pubkey::from_str(program).map_err(|e| std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid program id {program} in account_coalescer_program_durations_us: {e}"),
+                ))?;
+                Ok((program, window(*duration_us)))
+            })
+            .collect::<Result<_, std::io::Error>>()?;
+
+        Ok(CoalescePolicy::new(default_duration, program_durations))
+    }
+
+    pub fn startup_forwarder_config(&self) -> Result<StartupForwarderConfig, std::io::Error> {
+        let owner_filter = self.startup_account_owners
+            .iter()
+            .map(|program| Pubkey::from_str(program).map_err(|e| std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid program id {program} in startup_account_owners: {e}"),
+            )))
+            .collect::<Result<_, std::io::Error>>()?;
+
+        Ok(StartupForwarderConfig {
+            accounts_per_second: self.startup_accounts_per_second,
+            queue_size: self.startup_queue_size,
+            owner_filter,
+            gateway_wait: Duration::from_secs(self.startup_gateway_wait_secs),
+        })
+    }
 }
@@ -3,6 +3,8 @@ use agave_geyser_plugin_interface::geyser_plugin_interface::GeyserPlugin;
 mod config;
 mod plugin;
 mod account_coalescer;
+mod startup_forwarder;
+mod transaction_writability;
 
 #[unsafe(no_mangle)]
 #[allow(improper_ctypes_definitions)]
@@ -1,23 +1,30 @@
 //! Nothing complex here, just an implementation for the Geyser plugin interface to forward data further down the pipeline.
 
 use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Duration;
 use agave_geyser_plugin_interface::geyser_plugin_interface::{GeyserPluginError, ReplicaAccountInfoVersions, ReplicaBlockInfoVersions, ReplicaEntryInfoVersions, ReplicaTransactionInfoVersions, SlotStatus};
 use tracing::log;
 use log::info;
+use opentelemetry::metrics::MeterProvider;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
 use tokio::runtime::Runtime;
-use transport::{ReplicaChannels, UniformAccountInfo, UniformBlockInfo, UniformEntryInfo, UniformSlotInfo, UniformTransactionInfo};
+use transport::metrics::{init_metrics, StreamMetricHelper, TransportMetrics};
+use transport::{ReplicaChannels, UniformAccountInfo, UniformBlockInfo, UniformEntryInfo, UniformLifecycleInfo, UniformSlotInfo, UniformTransactionInfo};
 use transport::server::{TransportServer};
 use crate::config::AmpleGeyserProxyConfig;
 use rustls::crypto::ring::default_provider;
 use crate::account_coalescer::AccountCoalescer;
+use crate::startup_forwarder::StartupForwarder;
+use crate::transaction_writability::TransactionWritability;
 
 #[derive(Default)]
 pub struct AmpleGeyserPluginOuter {
     inner: Option<AmpleGeyserPluginInner>,
     runtime: Option<Runtime>,
-    use_account_coalescer: bool
+    use_account_coalescer: bool,
+    forward_startup_accounts: bool,
+    unsupported_versions: AtomicU64,
 }
 
 impl Debug for AmpleGeyserPluginOuter {
@@ -34,11 +41,26 @@ impl AmpleGeyserPluginOuter {
     fn channels(&self) -> &ReplicaChannels {
         &self.inner.as_ref().unwrap().channels
     }
+
+    /// Notifications using an interface version newer than the ones this proxy was built against can't be converted,
+    /// count them instead of dropping them silently.
+    fn record_unsupported_version(&self, notification: &'static str) {
+        let count = self.unsupported_versions.fetch_add(1, Ordering::Relaxed);
+        if count % 10_000 == 0 {
+            log::warn!("dropped {} notification with an unsupported interface version, {} dropped so far", notification, count + 1);
+        }
+        StreamMetricHelper::record_unsupported_interface_version(self.inner.as_ref().unwrap().metrics.as_ref(), notification);
+    }
 }
 
 struct AmpleGeyserPluginInner {
     channels: ReplicaChannels,
-    account_coalescer: AccountCoalescer
+    account_coalescer: Arc<AccountCoalescer>,
+    startup_forwarder: Option<StartupForwarder>,
+    transaction_writability: TransactionWritability,
+    metrics: Option<Arc<TransportMetrics>>,
+    // Keep the meter provider alive for the lifetime of the plugin
+    _meter_provider: Option<SdkMeterProvider>,
 }
 
 static THREAD_ID: AtomicU64 = AtomicU64::new(0);
@@ -51,6 +73,7 @@ impl agave_geyser_plugin_interface::geyser_plugin_interface::GeyserPlugin for Am
         let config = AmpleGeyserProxyConfig::load_from_file(config_file)?;
         
         self.use_account_coalescer = config.use_account_coalescer;
+        self.forward_startup_accounts = config.forward_startup_accounts;
 
         solana_logger::setup_with_default(&config.log_level);
 
@@ -63,9 +86,20 @@ impl agave_geyser_plugin_interface::geyser_plugin_interface::GeyserPlugin for Am
                 format!("ample-geyser-proxy-tokio-rt-{id}")
             })
             .build()?;
-        
-        let account_coalescer_duration_us = config.account_coalescer_duration_us;
 
+        let coalesce_policy = config.account_coalescer_policy()?;
+        let startup_forwarder_config = config.startup_forwarder_config()?;
+
+        let (meter_provider, metrics) = if let Some(metrics_otlp_url) = &config.metrics_otlp_url {
+            let _guard = runtime.enter();
+            let meter_provider = init_metrics(metrics_otlp_url);
+            let meter = meter_provider.meter("geyser-proxy");
+            (Some(meter_provider), Some(Arc::new(TransportMetrics::new(&meter))))
+        } else {
+            (None, None)
+        };
+
+        let server_metrics = metrics.clone();
         let channels = runtime.block_on(async move {
             let channels = ReplicaChannels::with_defaults();
 
@@ -74,19 +108,45 @@ impl agave_geyser_plugin_interface::geyser_plugin_interface::GeyserPlugin for Am
                 config.transport_opts,
                 config.transport_cfg,
                 channels.clone(),
-                None
+                server_metrics
             ).map_err(|e| GeyserPluginError::Custom(e.into()))?;
 
             tokio::task::spawn(transport_server.serve());
 
             Ok::<_, GeyserPluginError>(channels)
         })?;
-        
-        let account_coalescer = AccountCoalescer::new(Duration::from_micros(account_coalescer_duration_us));
+
+        let flush_interval = coalesce_policy.min_duration();
+        let account_coalescer = Arc::new(AccountCoalescer::new(coalesce_policy, metrics.clone()));
+
+        if let Some(flush_interval) = flush_interval.filter(|_| self.use_account_coalescer) {
+            let account_coalescer = account_coalescer.clone();
+            let account_channel = channels.account.clone();
+            runtime.spawn(async move {
+                let mut interval = tokio::time::interval(flush_interval);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    interval.tick().await;
+                    account_coalescer.flush_expired(|notif| {
+                        let _ = account_channel.send(notif);
+                    });
+                }
+            });
+        }
+
+        let startup_forwarder = if self.forward_startup_accounts {
+            Some(StartupForwarder::spawn(startup_forwarder_config, channels.startup.clone(), channels.lifecycle.clone())?)
+        } else {
+            None
+        };
 
         self.inner = Some(AmpleGeyserPluginInner {
             channels,
-            account_coalescer
+            account_coalescer,
+            startup_forwarder,
+            transaction_writability: TransactionWritability::default(),
+            metrics,
+            _meter_provider: meter_provider,
         });
         self.runtime = Some(runtime);
 
@@ -104,30 +164,48 @@ impl agave_geyser_plugin_interface::geyser_plugin_interface::GeyserPlugin for Am
     }
 
     fn update_account(&self, account: ReplicaAccountInfoVersions, slot: solana_clock::Slot, is_startup: bool) -> agave_geyser_plugin_interface::geyser_plugin_interface::Result<()> {
-        if !is_startup {
-            match account {
-                ReplicaAccountInfoVersions::V0_0_3(info) => {
-                    let notif = UniformAccountInfo::from_replica(info, slot);
-                    let inner = self.inner.as_ref().unwrap();
-                    
-                    if self.use_account_coalescer {
-                        if let Some(notifs) = self.inner.as_ref().unwrap().account_coalescer.coalesce(notif) {
-                            for notif in notifs {
-                                let _ = inner.channels.account.send(notif);
-                            }
-                        }
-                    } else {
-                        let _ = inner.channels.account.send(notif);
-                    }
+        let inner = self.inner.as_ref().unwrap();
+        let notif = match account {
+            ReplicaAccountInfoVersions::V0_0_1(info) => UniformAccountInfo::from_replica_v1(info, slot),
+            ReplicaAccountInfoVersions::V0_0_2(info) => UniformAccountInfo::from_replica_v2(info, slot),
+            ReplicaAccountInfoVersions::V0_0_3(info) => {
+                if let Some(txn) = info.txn {
+                    inner.transaction_writability.record(txn);
                 }
-                _ => {}
+                UniformAccountInfo::from_replica(info, slot)
+            }
+            #[allow(unreachable_patterns)]
+            _ => {
+                self.record_unsupported_version("account");
+                return Ok(());
             }
+        };
+
+        if is_startup {
+            if let Some(startup_forwarder) = &inner.startup_forwarder {
+                startup_forwarder.forward_account(notif);
+            }
+        } else if self.use_account_coalescer {
+            inner.account_coalescer.coalesce(notif, |notif| {
+                let _ = inner.channels.account.send(notif);
+            });
+        } else {
+            let _ = inner.channels.account.send(notif);
         }
 
         Ok(())
     }
 
     fn notify_end_of_startup(&self) -> agave_geyser_plugin_interface::geyser_plugin_interface::Result<()> {
+        let inner = self.inner.as_ref().unwrap();
+        match &inner.startup_forwarder {
+            // the forwarder announces the end of startup once it has drained its queue
+            Some(startup_forwarder) => startup_forwarder.end_of_startup(),
+            None => {
+                let _ = inner.channels.lifecycle.send(UniformLifecycleInfo::EndOfStartup { startup_accounts_forwarded: false });
+            }
+        }
+
         Ok(())
     }
 
@@ -138,34 +216,53 @@ impl agave_geyser_plugin_interface::geyser_plugin_interface::GeyserPlugin for Am
     }
 
     fn notify_transaction(&self, transaction: ReplicaTransactionInfoVersions, slot: solana_clock::Slot) -> agave_geyser_plugin_interface::geyser_plugin_interface::Result<()> {
-        match transaction {
+        let notif = match transaction {
+            ReplicaTransactionInfoVersions::V0_0_1(info) => UniformTransactionInfo::from_replica_v1(info, slot),
+            ReplicaTransactionInfoVersions::V0_0_2(info) => UniformTransactionInfo::from_replica_v2(info, slot),
             ReplicaTransactionInfoVersions::V0_0_3(info) => {
-                let _ = self.channels().transaction.send(UniformTransactionInfo::from_replica(info, slot));
-            },
-            _ => {}
-        }
+                let mut notif = UniformTransactionInfo::from_replica(info, slot);
+                notif.writable = self.inner.as_ref().unwrap().transaction_writability.take(info.signature);
+                notif
+            }
+            #[allow(unreachable_patterns)]
+            _ => {
+                self.record_unsupported_version("transaction");
+                return Ok(());
+            }
+        };
+        let _ = self.channels().transaction.send(notif);
 
         Ok(())
     }
 
     fn notify_entry(&self, entry: ReplicaEntryInfoVersions) -> agave_geyser_plugin_interface::geyser_plugin_interface::Result<()> {
-        match entry {
-            ReplicaEntryInfoVersions::V0_0_2(info) => {
-                let _ = self.channels().entry.send(UniformEntryInfo::from_replica(info));
-            },
-            _ => {}
-        }
+        let notif = match entry {
+            ReplicaEntryInfoVersions::V0_0_1(info) => UniformEntryInfo::from_replica_v1(info),
+            ReplicaEntryInfoVersions::V0_0_2(info) => UniformEntryInfo::from_replica(info),
+            #[allow(unreachable_patterns)]
+            _ => {
+                self.record_unsupported_version("entry");
+                return Ok(());
+            }
+        };
+        let _ = self.channels().entry.send(notif);
 
         Ok(())
     }
 
     fn notify_block_metadata(&self, blockinfo: ReplicaBlockInfoVersions) -> agave_geyser_plugin_interface::geyser_plugin_interface::Result<()> {
-        match blockinfo {
-            ReplicaBlockInfoVersions::V0_0_4(info) => {
-                let _ = self.channels().block.send(UniformBlockInfo::from_replica(info));
-            },
-            _ => {}
-        }
+        let notif = match blockinfo {
+            ReplicaBlockInfoVersions::V0_0_1(info) => UniformBlockInfo::from_replica_v1(info),
+            ReplicaBlockInfoVersions::V0_0_2(info) => UniformBlockInfo::from_replica_v2(info),
+            ReplicaBlockInfoVersions::V0_0_3(info) => UniformBlockInfo::from_replica_v3(info),
+            ReplicaBlockInfoVersions::V0_0_4(info) => UniformBlockInfo::from_replica(info),
+            #[allow(unreachable_patterns)]
+            _ => {
+                self.record_unsupported_version("block");
+                return Ok(());
+            }
+        };
+        let _ = self.channels().block.send(notif);
 
         Ok(())
     }
@@ -174,10 +271,11 @@ impl agave_geyser_plugin_interface::geyser_plugin_interface::GeyserPlugin for Am
         true
     }
 
-    // Startup is currently unsupported due to large data volumes
+    // Startup accounts are a large volume of data, so they are only forwarded when explicitly enabled and then at a
+    // bounded rate on their own low priority stream
     // TBD: in the future the ample gateway should download snapshots on it's own and sync to the geyser proxy
     fn account_data_snapshot_notifications_enabled(&self) -> bool {
-        false
+        self.forward_startup_accounts
     }
 
     fn transaction_notifications_enabled(&self) -> bool {
@@ -0,0 +1,132 @@
+use std::collections::HashSet;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::time::{Duration, Instant};
+use solana_pubkey::Pubkey;
+use tracing::log;
+use transport::{LifecycleSender, UniformAccountInfo, UniformLifecycleInfo, UniformStartupInfo, buffer_defaults};
+
+/// Accounts queued on the startup channel for the slowest gateway before forwarding holds back. The startup stream has
+/// the lowest priority on the connection, so under live traffic it drains slower than any rate limit
+const CONSUMER_BACKLOG_LIMIT: usize = buffer_defaults::STARTUP / 2;
+
+/// How long forwarding holds back for a gateway that doesn't drain its backlog at all, before accounts are risked to be
+/// dropped for it
+const MAX_CONSUMER_STALL: Duration = Duration::from_secs(60);
+
+/// Forwards the accounts the validator restores from its snapshot to the startup stream at a bounded rate.
+///
+/// Updates are queued from the validator's startup threads and drained by a dedicated pacing thread, when the queue
+/// is full the validator is slowed down instead of accounts being dropped.
+pub struct StartupForwarder {
+    queue: SyncSender<UniformStartupInfo>,
+    owner_filter: HashSet<Pubkey>,
+}
+
+pub struct StartupForwarderConfig {
+    pub accounts_per_second: u64,
+    pub queue_size: usize,
+    /// Only accounts owned by these programs are forwarded, an empty set forwards everything
+    pub owner_filter: HashSet<Pubkey>,
+    /// How long to hold startup accounts back while no gateway is connected, before dropping them
+    pub gateway_wait: Duration,
+}
+
+impl StartupForwarder {
+    pub fn spawn(
+        config: StartupForwarderConfig,
+        channel: tokio::sync::broadcast::Sender<UniformStartupInfo>,
+        lifecycle: LifecycleSender,
+    ) -> std::io::Result<Self> {
+        let (queue, rx) = std::sync::mpsc::sync_channel(config.queue_size);
+
+        std::thread::Builder::new()
+            .name("ample-geyser-proxy-startup".to_string())
+            .spawn(move || {
+                let complete = Self::pace(rx, channel, config.accounts_per_second, config.gateway_wait);
+                // only announce the end of startup once the whole startup stream has been written, gateways must not
+                // take the startup accounts for the full state if some of them were dropped
+                let _ = lifecycle.send(UniformLifecycleInfo::EndOfStartup { startup_accounts_forwarded: complete });
+            })?;
+
+        Ok(Self {
+            queue,
+            owner_filter: config.owner_filter,
+        })
+    }
+
+    pub fn forward_account(&self, account: UniformAccountInfo) {
+        if !self.owner_filter.is_empty() && !self.owner_filter.contains(&account.owner) {
+            return;
+        }
+        let _ = self.queue.send(UniformStartupInfo::Account(account));
+    }
+
+    pub fn end_of_startup(&self) {
+        let _ = self.queue.send(UniformStartupInfo::EndOfStartup);
+    }
+
+    /// Returns whether every account reached the startup channel without a gateway risking to lag behind
+    fn pace(
+        rx: Receiver<UniformStartupInfo>,
+        channel: tokio::sync::broadcast::Sender<UniformStartupInfo>,
+        accounts_per_second: u64,
+        gateway_wait: Duration,
+    ) -> bool {
+        let wait_deadline = Instant::now() + gateway_wait;
+        while channel.receiver_count() == 0 && Instant::now() < wait_deadline {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        if channel.receiver_count() == 0 {
+            log::warn!("no gateway connected after {:?}, startup accounts will only reach gateways connected later", gateway_wait);
+        }
+
+        let started = Instant::now();
+        let mut sent: u64 = 0;
+        let mut dropped: u64 = 0;
+        let mut stalled = false;
+
+        while let Ok(info) = rx.recv() {
+            let end_of_startup = matches!(info, UniformStartupInfo::EndOfStartup);
+
+            if !end_of_startup && accounts_per_second > 0 {
+                let scheduled = started + Duration::from_secs_f64(sent as f64 / accounts_per_second as f64);
+                let now = Instant::now();
+                if scheduled > now {
+                    std::thread::sleep(scheduled - now);
+                }
+            }
+
+            if !end_of_startup && !stalled {
+                stalled = !Self::wait_for_consumers(&channel);
+            }
+
+            if channel.send(info).is_err() && !end_of_startup {
+                dropped += 1;
+            }
+
+            if end_of_startup {
+                break;
+            }
+            sent += 1;
+        }
+
+        log::info!("startup account forwarding finished, forwarded {} accounts ({} without a connected gateway) in {:?}", sent, dropped, started.elapsed());
+        if stalled {
+            log::warn!("a gateway stopped draining the startup stream, it may have missed startup accounts");
+        }
+        dropped == 0 && !stalled
+    }
+
+    /// Holds back while the slowest gateway's backlog is close to the channel capacity, so it never lags and misses
+    /// accounts. Returns false if the backlog didn't drain in time
+    fn wait_for_consumers(channel: &tokio::sync::broadcast::Sender<UniformStartupInfo>) -> bool {
+        let deadline = Instant::now() + MAX_CONSUMER_STALL;
+        while channel.len() >= CONSUMER_BACKLOG_LIMIT {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
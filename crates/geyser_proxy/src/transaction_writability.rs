@@ -0,0 +1,78 @@
+use std::collections::{HashMap, VecDeque};
+use solana_signature::Signature;
+use solana_transaction::sanitized::SanitizedTransaction;
+use transport::UniformTransactionInfo;
+
+/// Recent transactions remembered, transactions whose notification doesn't follow in time are forgotten
+const MAX_TRANSACTIONS: usize = 50_000;
+
+/// Remembers how the validator sanitized recent transactions. Transaction notifications of the current interface
+/// version only carry the `VersionedTransaction`, the `SanitizedTransaction` with the validator's writability comes
+/// along with the transaction's account updates, which the validator notifies first.
+///
+/// Recorded from the validator's replay threads on every account update, so it's sharded by signature the same way
+/// the account coalescer is sharded by pubkey.
+pub struct TransactionWritability {
+    shards: Box<[WritabilityShard]>,
+    shard_mask: usize,
+    /// Transactions remembered per shard
+    shard_capacity: usize,
+}
+
+// Aligned to avoid false sharing between shards locked from different replay threads
+#[repr(align(128))]
+#[derive(Default)]
+struct WritabilityShard {
+    recent: parking_lot::Mutex<RecentTransactions>,
+}
+
+#[derive(Default)]
+struct RecentTransactions {
+    writable: HashMap<Signature, Vec<bool>>,
+    /// In recording order, may still list transactions that were taken already
+    order: VecDeque<Signature>,
+}
+
+impl Default for TransactionWritability {
+    fn default() -> Self {
+        let parallelism = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(16);
+        let shard_count = (parallelism * 4).next_power_of_two();
+
+        Self {
+            shards: (0..shard_count).map(|_| WritabilityShard::default()).collect(),
+            shard_mask: shard_count - 1,
+            shard_capacity: MAX_TRANSACTIONS.div_ceil(shard_count),
+        }
+    }
+}
+
+impl TransactionWritability {
+    fn shard(&self, signature: &Signature) -> &WritabilityShard {
+        // Signatures are uniformly distributed, so their leading bytes are a good enough shard key
+        let bytes = signature.as_ref();
+        let key = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+        &self.shards[key & self.shard_mask]
+    }
+
+    /// Called for every account update a transaction caused, only the first one is recorded
+    pub fn record(&self, transaction: &SanitizedTransaction) {
+        let signature = *transaction.signature();
+        // checked and inserted under the same lock, so concurrent updates of one transaction record it once
+        let mut recent = self.shard(&signature).recent.lock();
+        if recent.writable.contains_key(&signature) {
+            return;
+        }
+        if recent.order.len() >= self.shard_capacity {
+            if let Some(oldest) = recent.order.pop_front() {
+                recent.writable.remove(&oldest);
+            }
+        }
+        recent.order.push_back(signature);
+        recent.writable.insert(signature, UniformTransactionInfo::writability(transaction));
+    }
+
+    /// `None` if no account update carried the transaction
+    pub fn take(&self, signature: &Signature) -> Option<Vec<bool>> {
+        self.shard(signature).recent.lock().writable.remove(signature)
+    }
+}
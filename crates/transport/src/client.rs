@@ -1,6 +1,6 @@
-use crate::{NUM_EXPECTED_REPLICA_CHANNELS, ReplicaChannels, StreamOp, TransportOpts, TransportResult, error, metrics::TransportMetrics, ReplicaReceivers};
+use crate::{ReplicaChannels, ReplicaSender, StreamOp, Subscription, TransportOpts, TransportResult, error, metrics::TransportMetrics, ReplicaReceivers, CLOSE_CODE_SHUTDOWN};
 use quinn::crypto::rustls::QuicClientConfig;
-use quinn::{Endpoint, RecvStream};
+use quinn::{Endpoint, RecvStream, VarInt};
 use rustls::pki_types::CertificateDer;
 use serde::Deserialize;
 use std::fs;
@@ -9,7 +9,7 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::task::{Context, Poll};
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, BufReader, ReadBuf};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf};
 use tracing::log;
 use crate::metrics::StreamMetricHelper;
 
@@ -61,6 +61,26 @@ impl<R: AsyncBufRead + Unpin> AsyncBufRead for CompressedBytesWrapper<R> {
     }
 }
 
+/// The upstream connection of a client, kept to close it cleanly on shutdown
+pub struct ClientConnection {
+    endpoint: Endpoint,
+    connection: quinn::Connection,
+}
+
+impl ClientConnection {
+    /// Closes the connection with [`CLOSE_CODE_SHUTDOWN`], no more notifications are received afterwards. Waits until
+    /// the server was told, so it doesn't have to time the connection out.
+    pub async fn close(self, reason: &str) {
+        self.connection.close(VarInt::from_u32(CLOSE_CODE_SHUTDOWN), reason.as_bytes());
+        self.endpoint.wait_idle().await;
+    }
+
+    /// Resolves once the connection is lost, with the reason it was
+    pub async fn closed(&self) -> quinn::ConnectionError {
+        self.connection.closed().await
+    }
+}
+
 pub struct TransportClient {
     replica_channels: ReplicaChannels,
     metrics: Option<Arc<TransportMetrics>>,
@@ -71,8 +91,9 @@ impl TransportClient {
         addr: SocketAddr,
         opts: TransportOpts,
         replica_channels: ReplicaChannels,
+        subscription: Subscription,
         metrics: Option<Arc<TransportMetrics>>,
-    ) -> TransportResult<ReplicaReceivers> {
+    ) -> TransportResult<(ReplicaReceivers, ClientConnection)> {
         let mut endpoint = Endpoint::client(SocketAddr::from(([0, 0, 0, 0], 0)))?;
 
         let mut roots = rustls::RootCertStore::empty();
@@ -91,20 +112,27 @@ impl TransportClient {
         endpoint.set_default_client_config(client_config);
 
         let connection = endpoint.connect(addr, &opts.fqdn)?.await?;
+        Self::send_subscription(&connection, subscription).await?;
         let client = Self { replica_channels: replica_channels.clone(), metrics };
-        client.spawn_replica_channel_tasks(connection).await?;
-
-        Ok(ReplicaReceivers {
-            account: replica_channels.account.subscribe(),
-            transaction: replica_channels.transaction.subscribe(),
-            entry: replica_channels.entry.subscribe(),
-            block: replica_channels.block.subscribe(),
-            slot: replica_channels.slot.subscribe(),
-        })
+        client.spawn_replica_channel_tasks(connection.clone(), subscription).await?;
+
+        Ok((replica_channels.subscribe(), ClientConnection { endpoint, connection }))
+    }
+
+    async fn send_subscription(connection: &quinn::Connection, subscription: Subscription) -> TransportResult<()> {
+        let serialized = bincode::serialize(&subscription)?;
+        let mut send = connection.open_uni().await?;
+        send.write_u8(StreamOp::Subscribe as u8).await?;
+        send.write_u32_le(serialized.len() as u32).await?;
+        AsyncWriteExt::write_all(&mut send, &serialized).await?;
+        let _ = send.finish();
+
+        log::debug!("sent subscription {:?}", subscription);
+        Ok(())
     }
 
     async fn handle_explicit_stream_type<T>(
-        sender: tokio::sync::broadcast::Sender<T>,
+        sender: impl ReplicaSender<T>,
         mut stream: Box<dyn AsyncRead + Send + Unpin>,
         stream_op: StreamOp,
         metrics: Option<Arc<TransportMetrics>>,
@@ -134,7 +162,7 @@ impl TransportClient {
              */
             match bincode::deserialize::<T>(buf.as_slice()) {
                 Ok(data) => {
-                    let _ = sender.send(data);
+                    sender.publish(data);
                     // Record metrics for successfully received message
                     StreamMetricHelper::record_message(
                         metrics.as_ref(),
@@ -195,6 +223,15 @@ impl TransportClient {
             StreamOp::SlotStatus => {
                 Self::handle_explicit_stream_type(replica_channels.slot, rx, stream_type, metrics, exit.clone()).await
             }
+            StreamOp::Startup => {
+                Self::handle_explicit_stream_type(replica_channels.startup, rx, stream_type, metrics, exit.clone()).await
+            }
+            StreamOp::Lifecycle => {
+                Self::handle_explicit_stream_type(replica_channels.lifecycle, rx, stream_type, metrics, exit.clone()).await
+            }
+            StreamOp::Rollback => {
+                Self::handle_explicit_stream_type(replica_channels.rollback, rx, stream_type, metrics, exit.clone()).await
+            }
             _ => {}
         }
         log::debug!("replica channel {:?} opened", stream_type);
@@ -203,12 +240,13 @@ impl TransportClient {
     async fn spawn_replica_channel_tasks(
         &self,
         connection: quinn::Connection,
+        subscription: Subscription,
     ) -> TransportResult<()> {
         let replica_channels = self.replica_channels.clone();
         let metrics = self.metrics.clone();
         let exit = Arc::new(AtomicBool::new(false));
 
-        for i in 0..NUM_EXPECTED_REPLICA_CHANNELS {
+        for i in 0..subscription.stream_count() {
             let mut recv = connection.accept_uni().await?;
 
             let first_op =
@@ -24,4 +24,10 @@ pub enum TransportError {
 
     #[error("invalid stream opcode: {0}")]
     InvalidStreamOp(u8),
+
+    #[error("client didn't send its subscription in time")]
+    SubscriptionTimeout,
+
+    #[error("subscription of {0} bytes exceeds the limit")]
+    SubscriptionTooLarge(u32),
 }
\ No newline at end of file
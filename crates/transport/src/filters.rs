@@ -0,0 +1,47 @@
+//! Filters on notifications, applied by the gateway's gRPC and WebSocket APIs and by the client SDK
+
+use solana_pubkey::Pubkey;
+use std::collections::HashSet;
+use crate::{UniformAccountInfo, UniformTransactionInfo};
+
+/// Matches an account update if its pubkey or its owner is listed, every update if both are empty
+#[derive(Debug, Clone, Default)]
+pub struct AccountFilter {
+    pub pubkeys: HashSet<Pubkey>,
+    pub owners: HashSet<Pubkey>,
+}
+
+impl AccountFilter {
+    pub fn matches(&self, account: &UniformAccountInfo) -> bool {
+        (self.pubkeys.is_empty() && self.owners.is_empty())
+            || self.pubkeys.contains(&account.pubkey)
+            || self.owners.contains(&account.owner)
+    }
+}
+
+/// Matches a transaction if it references one of the accounts, every transaction if there are none
+#[derive(Debug, Clone, Default)]
+pub struct TransactionFilter {
+    pub include_votes: bool,
+    pub include_failed: bool,
+    pub accounts: HashSet<Pubkey>,
+}
+
+impl TransactionFilter {
+    pub fn matches(&self, txn: &UniformTransactionInfo) -> bool {
+        if txn.is_vote && !self.include_votes {
+            return false;
+        }
+        if txn.transaction_status_meta.status.is_err() && !self.include_failed {
+            return false;
+        }
+        if self.accounts.is_empty() {
+            return true;
+        }
+        let loaded = &txn.transaction_status_meta.loaded_addresses;
+        txn.transaction.message.static_account_keys().iter()
+            .chain(&loaded.writable)
+            .chain(&loaded.readonly)
+            .any(|key| self.accounts.contains(key))
+    }
+}
@@ -1,22 +1,34 @@
 pub mod server;
 pub mod client;
 pub mod error;
+pub mod filters;
 pub mod metrics;
 
 use std::path::PathBuf;
-use agave_geyser_plugin_interface::geyser_plugin_interface::{ReplicaAccountInfoV3, ReplicaBlockInfoV4, ReplicaEntryInfoV2, ReplicaTransactionInfoV3, SlotStatus};
+use std::sync::{Arc, Mutex};
+use agave_geyser_plugin_interface::geyser_plugin_interface::{
+    ReplicaAccountInfo, ReplicaAccountInfoV2, ReplicaAccountInfoV3, ReplicaBlockInfo, ReplicaBlockInfoV2,
+    ReplicaBlockInfoV3, ReplicaBlockInfoV4, ReplicaEntryInfo, ReplicaEntryInfoV2, ReplicaTransactionInfo,
+    ReplicaTransactionInfoV2, ReplicaTransactionInfoV3, SlotStatus,
+};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use serde::{Deserialize, Serialize};
 use solana_clock::Slot;
 use solana_pubkey::Pubkey;
 use solana_signature::Signature;
+use solana_transaction::sanitized::SanitizedTransaction;
 use solana_transaction::versioned::VersionedTransaction;
-use solana_transaction_status::RewardsAndNumPartitions;
+use solana_transaction_status::{Reward, RewardsAndNumPartitions};
 use solana_transaction_status_client_types::TransactionStatusMeta;
 
 pub type TransportResult<T> = Result<T, error::TransportError>;
 
-pub const ALPN_QUIC_AMPLE: &[&[u8]] = &[b"ample/0.1"];
+/// Bumped whenever the wire format changes, so mismatched proxies and gateways fail the handshake instead of
+/// misreading each other's frames
+pub const ALPN_QUIC_AMPLE: &[&[u8]] = &[b"ample/0.2"];
+
+/// QUIC application close code of a client disconnecting because it shuts down
+pub const CLOSE_CODE_SHUTDOWN: u32 = 1;
 
 /// Default buffer sizes for replica channels
 pub mod buffer_defaults {
@@ -25,6 +37,9 @@ pub mod buffer_defaults {
     pub const ENTRY: usize = 4000;
     pub const BLOCK: usize = 1024;
     pub const SLOT: usize = 1024;
+    pub const STARTUP: usize = 65_535;
+    pub const LIFECYCLE: usize = 16;
+    pub const ROLLBACK: usize = 1024;
 }
 
 #[derive(Debug, IntoPrimitive, TryFromPrimitive, Copy, Clone)]
@@ -38,6 +53,10 @@ pub enum StreamOp {
     UseLz4Compression = 5,
     UseZstdCompression = 6,
     UseNoCompression = 7,
+    Startup = 8,
+    Lifecycle = 9,
+    Subscribe = 10,
+    Rollback = 11,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -47,7 +66,40 @@ pub struct TransportOpts {
     pub fqdn: String,
 }
 
-pub const NUM_EXPECTED_REPLICA_CHANNELS: usize = 5;
+/// Streams a client wants the server to open, sent by the client right after connecting so the server doesn't spend
+/// bandwidth on notifications nobody consumes. Block, slot status and lifecycle streams are always opened.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Subscription {
+    pub accounts: bool,
+    pub transactions: bool,
+    pub entries: bool,
+    pub startup: bool,
+    /// Rollbacks are detected by gateways, a proxy never sends any. Gateways track forks themselves and don't
+    /// subscribe to them upstream
+    pub rollbacks: bool,
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Self {
+            accounts: true,
+            transactions: true,
+            entries: true,
+            startup: true,
+            rollbacks: true,
+        }
+    }
+}
+
+impl Subscription {
+    /// Number of uni streams the server opens for this subscription
+    pub fn stream_count(&self) -> usize {
+        3 + [self.accounts, self.transactions, self.entries, self.startup, self.rollbacks]
+            .into_iter()
+            .filter(|subscribed| *subscribed)
+            .count()
+    }
+}
 
 // Cloning is cheap and clones the pointers to the inner broadcast channels
 #[derive(Clone)]
@@ -57,6 +109,72 @@ pub struct ReplicaChannels {
     pub entry: tokio::sync::broadcast::Sender<UniformEntryInfo>,
     pub block: tokio::sync::broadcast::Sender<UniformBlockInfo>,
     pub slot: tokio::sync::broadcast::Sender<UniformSlotInfo>,
+    pub startup: tokio::sync::broadcast::Sender<UniformStartupInfo>,
+    pub lifecycle: LifecycleSender,
+    pub rollback: tokio::sync::broadcast::Sender<UniformRollbackInfo>,
+}
+
+/// Publishes the notifications of one stream of [`ReplicaChannels`]
+pub trait ReplicaSender<T>: Send + Sync + 'static {
+    /// Returns false if there was no receiver
+    fn publish(&self, notification: T) -> bool;
+
+    fn subscribe(&self) -> tokio::sync::broadcast::Receiver<T>;
+}
+
+impl<T: Clone + Send + 'static> ReplicaSender<T> for tokio::sync::broadcast::Sender<T> {
+    fn publish(&self, notification: T) -> bool {
+        self.send(notification).is_ok()
+    }
+
+    fn subscribe(&self) -> tokio::sync::broadcast::Receiver<T> {
+        tokio::sync::broadcast::Sender::subscribe(self)
+    }
+}
+
+/// Lifecycle events describe state rather than updates, so servers replay every past event to a client when it
+/// connects. Events are added to the history under the same lock that snapshots it, so a client subscribing with
+/// [`LifecycleSender::subscribe_with_history`] gets every event exactly once.
+#[derive(Clone)]
+pub struct LifecycleSender {
+    sender: tokio::sync::broadcast::Sender<UniformLifecycleInfo>,
+    history: Arc<Mutex<Vec<UniformLifecycleInfo>>>,
+}
+
+impl LifecycleSender {
+    fn new(buffer_size: usize) -> Self {
+        Self {
+            sender: tokio::sync::broadcast::channel(buffer_size).0,
+            history: Arc::default(),
+        }
+    }
+
+    pub fn send(&self, event: UniformLifecycleInfo) -> bool {
+        let mut history = self.history.lock().unwrap();
+        history.push(event.clone());
+        self.sender.send(event).is_ok()
+    }
+
+    /// Receives the events sent from now on
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<UniformLifecycleInfo> {
+        self.sender.subscribe()
+    }
+
+    /// The events sent so far, and a receiver for the ones sent afterwards
+    pub fn subscribe_with_history(&self) -> (Vec<UniformLifecycleInfo>, tokio::sync::broadcast::Receiver<UniformLifecycleInfo>) {
+        let history = self.history.lock().unwrap();
+        (history.clone(), self.sender.subscribe())
+    }
+}
+
+impl ReplicaSender<UniformLifecycleInfo> for LifecycleSender {
+    fn publish(&self, event: UniformLifecycleInfo) -> bool {
+        self.send(event)
+    }
+
+    fn subscribe(&self) -> tokio::sync::broadcast::Receiver<UniformLifecycleInfo> {
+        LifecycleSender::subscribe(self)
+    }
 }
 
 pub struct ReplicaReceivers {
@@ -65,15 +183,22 @@ pub struct ReplicaReceivers {
     pub entry: tokio::sync::broadcast::Receiver<UniformEntryInfo>,
     pub block: tokio::sync::broadcast::Receiver<UniformBlockInfo>,
     pub slot: tokio::sync::broadcast::Receiver<UniformSlotInfo>,
+    pub startup: tokio::sync::broadcast::Receiver<UniformStartupInfo>,
+    pub lifecycle: tokio::sync::broadcast::Receiver<UniformLifecycleInfo>,
+    pub rollback: tokio::sync::broadcast::Receiver<UniformRollbackInfo>,
 }
 
 impl ReplicaChannels {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         account_buffer_size: usize,
         transaction_buffer_size: usize,
         entry_buffer_size: usize,
         block_buffer_size: usize,
         slot_buffer_size: usize,
+        startup_buffer_size: usize,
+        lifecycle_buffer_size: usize,
+        rollback_buffer_size: usize,
     ) -> Self {
         create_replica_channels(
             account_buffer_size,
@@ -81,9 +206,26 @@ impl ReplicaChannels {
             entry_buffer_size,
             block_buffer_size,
             slot_buffer_size,
+            startup_buffer_size,
+            lifecycle_buffer_size,
+            rollback_buffer_size,
         )
     }
 
+    /// Receivers for everything sent on the channels from now on
+    pub fn subscribe(&self) -> ReplicaReceivers {
+        ReplicaReceivers {
+            account: self.account.subscribe(),
+            transaction: self.transaction.subscribe(),
+            entry: self.entry.subscribe(),
+            block: self.block.subscribe(),
+            slot: self.slot.subscribe(),
+            startup: self.startup.subscribe(),
+            lifecycle: self.lifecycle.subscribe(),
+            rollback: self.rollback.subscribe(),
+        }
+    }
+
     pub fn with_defaults() -> Self {
         Self::new(
             buffer_defaults::ACCOUNT,
@@ -91,30 +233,42 @@ impl ReplicaChannels {
             buffer_defaults::ENTRY,
             buffer_defaults::BLOCK,
             buffer_defaults::SLOT,
+            buffer_defaults::STARTUP,
+            buffer_defaults::LIFECYCLE,
+            buffer_defaults::ROLLBACK,
         )
     }
 }
 
 
+#[allow(clippy::too_many_arguments)]
 fn create_replica_channels(
     account_buffer_size: usize,
     transaction_buffer_size: usize,
     entry_buffer_size: usize,
     block_buffer_size: usize,
     slot_buffer_size: usize,
+    startup_buffer_size: usize,
+    lifecycle_buffer_size: usize,
+    rollback_buffer_size: usize,
 ) -> ReplicaChannels {
     let (account_sender, _) = tokio::sync::broadcast::channel(account_buffer_size);
     let (transaction_sender, _) = tokio::sync::broadcast::channel(transaction_buffer_size);
     let (entry_sender, _) = tokio::sync::broadcast::channel(entry_buffer_size);
     let (block_sender, _) = tokio::sync::broadcast::channel(block_buffer_size);
     let (slot_sender, _) = tokio::sync::broadcast::channel(slot_buffer_size);
-    
+    let (startup_sender, _) = tokio::sync::broadcast::channel(startup_buffer_size);
+    let (rollback_sender, _) = tokio::sync::broadcast::channel(rollback_buffer_size);
+
     ReplicaChannels {
         account: account_sender,
         transaction: transaction_sender,
         entry: entry_sender,
         block: block_sender,
         slot: slot_sender,
+        startup: startup_sender,
+        lifecycle: LifecycleSender::new(lifecycle_buffer_size),
+        rollback: rollback_sender,
     }
 }
 
@@ -150,6 +304,34 @@ impl UniformAccountInfo {
             write_version: v.write_version
         }
     }
+
+    pub fn from_replica_v2(v: &ReplicaAccountInfoV2, slot: Slot) -> Self {
+        Self {
+            slot,
+            pubkey: Pubkey::try_from(v.pubkey).unwrap(),
+            owner: Pubkey::try_from(v.owner).unwrap(),
+            lamports: v.lamports,
+            data: v.data.to_vec(),
+            transaction_ref: v.txn_signature.copied(),
+            executable: v.executable,
+            rent_epoch: v.rent_epoch,
+            write_version: v.write_version
+        }
+    }
+
+    pub fn from_replica_v1(v: &ReplicaAccountInfo, slot: Slot) -> Self {
+        Self {
+            slot,
+            pubkey: Pubkey::try_from(v.pubkey).unwrap(),
+            owner: Pubkey::try_from(v.owner).unwrap(),
+            lamports: v.lamports,
+            data: v.data.to_vec(),
+            transaction_ref: None,
+            executable: v.executable,
+            rent_epoch: v.rent_epoch,
+            write_version: v.write_version
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -160,7 +342,11 @@ pub struct UniformTransactionInfo {
     pub is_vote: bool,
     pub transaction: VersionedTransaction,
     pub transaction_status_meta: TransactionStatusMeta,
-    pub index: usize
+    /// The transaction's index in the block, `None` if the validator didn't report it
+    pub index: Option<usize>,
+    /// Writability of each account key as the validator sanitized the transaction, `None` if the proxy didn't get the
+    /// `SanitizedTransaction`. Consumers then derive it from the reserved account keys they know of
+    pub writable: Option<Vec<bool>>,
 }
 
 impl UniformTransactionInfo {
@@ -172,9 +358,45 @@ impl UniformTransactionInfo {
             is_vote: v.is_vote,
             transaction: v.transaction.clone(),
             transaction_status_meta: v.transaction_status_meta.clone(),
-            index: v.index,
+            index: Some(v.index),
+            writable: None,
+        }
+    }
+
+    pub fn from_replica_v2(v: &ReplicaTransactionInfoV2<'_>, slot: Slot) -> Self {
+        Self::from_sanitized(v.signature, v.is_vote, v.transaction, v.transaction_status_meta, Some(v.index), slot)
+    }
+
+    /// V0_0_1 notifications don't carry the transaction's index in the block.
+    pub fn from_replica_v1(v: &ReplicaTransactionInfo<'_>, slot: Slot) -> Self {
+        Self::from_sanitized(v.signature, v.is_vote, v.transaction, v.transaction_status_meta, None, slot)
+    }
+
+    fn from_sanitized(
+        signature: &Signature,
+        is_vote: bool,
+        transaction: &SanitizedTransaction,
+        transaction_status_meta: &TransactionStatusMeta,
+        index: Option<usize>,
+        slot: Slot,
+    ) -> Self {
+        Self {
+            slot,
+            signature: *signature,
+            message_hash: solana_hash::Hash::new_from_array(transaction.message_hash().to_bytes()),
+            is_vote,
+            transaction: transaction.to_versioned_transaction(),
+            transaction_status_meta: transaction_status_meta.clone(),
+            index,
+            writable: Some(Self::writability(transaction)),
         }
     }
+
+    /// Writability of each account key of the sanitized transaction
+    pub fn writability(transaction: &SanitizedTransaction) -> Vec<bool> {
+        let message = transaction.message();
+        (0..message.account_keys().len()).map(|index| message.is_writable(index)).collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -190,8 +412,8 @@ pub struct UniformEntryInfo {
     pub hash: solana_hash::Hash,
     /// The number of executed transactions in the Entry
     pub executed_transaction_count: u64,
-    /// The index-in-block of the first executed transaction in this Entry
-    pub starting_transaction_index: usize,
+    /// The index-in-block of the first executed transaction in this Entry, `None` if the validator didn't report it
+    pub starting_transaction_index: Option<usize>,
 }
 
 impl UniformEntryInfo {
@@ -204,38 +426,104 @@ impl UniformEntryInfo {
             num_hashes: v.num_hashes,
             hash: solana_hash::Hash::new_from_array(hash),
             executed_transaction_count: v.executed_transaction_count,
-            starting_transaction_index: v.starting_transaction_index,
+            starting_transaction_index: Some(v.starting_transaction_index),
+        }
+    }
+
+    /// V0_0_1 notifications don't carry the starting transaction index.
+    pub fn from_replica_v1(v: &ReplicaEntryInfo<'_>) -> Self {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(v.hash);
+        Self {
+            slot: v.slot,
+            index: v.index,
+            num_hashes: v.num_hashes,
+            hash: solana_hash::Hash::new_from_array(hash),
+            executed_transaction_count: v.executed_transaction_count,
+            starting_transaction_index: None,
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct UniformBlockInfo {
-    pub parent_slot: u64,
-    pub parent_blockhash: String,
+    /// `None` where the validator's interface version doesn't report it, as are the other optional counts
+    pub parent_slot: Option<u64>,
+    pub parent_blockhash: Option<String>,
     pub slot: u64,
     pub blockhash: String,
     pub rewards: RewardsAndNumPartitions,
     pub block_time: Option<i64>,
     pub block_height: Option<u64>,
-    pub executed_transaction_count: u64,
-    pub entry_count: u64
+    pub executed_transaction_count: Option<u64>,
+    pub entry_count: Option<u64>
 }
 
 impl UniformBlockInfo {
     pub fn from_replica(v: &ReplicaBlockInfoV4<'_>) -> Self {
         Self {
-            parent_slot: v.parent_slot,
-            parent_blockhash: v.parent_blockhash.to_string(),
+            parent_slot: Some(v.parent_slot),
+            parent_blockhash: Some(v.parent_blockhash.to_string()),
             slot: v.slot,
             blockhash: v.blockhash.to_string(),
             rewards: v.rewards.clone(),
             block_time: v.block_time,
             block_height: v.block_height,
-            executed_transaction_count: v.executed_transaction_count,
-            entry_count: v.entry_count
+            executed_transaction_count: Some(v.executed_transaction_count),
+            entry_count: Some(v.entry_count)
         }
     }
+
+    pub fn from_replica_v3(v: &ReplicaBlockInfoV3<'_>) -> Self {
+        Self {
+            parent_slot: Some(v.parent_slot),
+            parent_blockhash: Some(v.parent_blockhash.to_string()),
+            slot: v.slot,
+            blockhash: v.blockhash.to_string(),
+            rewards: rewards_without_partitions(v.rewards),
+            block_time: v.block_time,
+            block_height: v.block_height,
+            executed_transaction_count: Some(v.executed_transaction_count),
+            entry_count: Some(v.entry_count)
+        }
+    }
+
+    /// V0_0_2 notifications don't carry the entry count.
+    pub fn from_replica_v2(v: &ReplicaBlockInfoV2<'_>) -> Self {
+        Self {
+            parent_slot: Some(v.parent_slot),
+            parent_blockhash: Some(v.parent_blockhash.to_string()),
+            slot: v.slot,
+            blockhash: v.blockhash.to_string(),
+            rewards: rewards_without_partitions(v.rewards),
+            block_time: v.block_time,
+            block_height: v.block_height,
+            executed_transaction_count: Some(v.executed_transaction_count),
+            entry_count: None
+        }
+    }
+
+    /// V0_0_1 notifications don't carry the parent, transaction or entry counts.
+    pub fn from_replica_v1(v: &ReplicaBlockInfo<'_>) -> Self {
+        Self {
+            parent_slot: None,
+            parent_blockhash: None,
+            slot: v.slot,
+            blockhash: v.blockhash.to_string(),
+            rewards: rewards_without_partitions(v.rewards),
+            block_time: v.block_time,
+            block_height: v.block_height,
+            executed_transaction_count: None,
+            entry_count: None
+        }
+    }
+}
+
+fn rewards_without_partitions(rewards: &[Reward]) -> RewardsAndNumPartitions {
+    RewardsAndNumPartitions {
+        rewards: rewards.to_vec(),
+        num_partitions: None,
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -255,6 +543,36 @@ impl UniformSlotInfo {
     }
 }
 
+/// Slots whose processed data must be undone, because they died or were on a fork abandoned when another fork was
+/// rooted. Lists every account updated in those slots, so consumers storing processed data know what to restore.
+/// Detected by gateways tracking forks and sent on their own stream.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UniformRollbackInfo {
+    /// The root that abandoned the fork, `None` for a slot the validator marked dead
+    pub root: Option<Slot>,
+    /// Rolled back slots in ascending order
+    pub slots: Vec<Slot>,
+    pub pubkeys: Vec<Pubkey>,
+}
+
+/// Account state sent by the validator while it loads its snapshot, forwarded on its own low priority stream.
+/// The stream is terminated by `EndOfStartup` once the validator calls `notify_end_of_startup`.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum UniformStartupInfo {
+    Account(UniformAccountInfo),
+    EndOfStartup,
+}
+
+/// Plugin lifecycle events of the validator. Servers replay past lifecycle events to every newly connected client,
+/// so a gateway connecting after startup still learns that startup has ended.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum UniformLifecycleInfo {
+    /// The validator called `notify_end_of_startup`. When startup accounts were forwarded this is only sent once
+    /// the startup stream has been fully written, after its own `UniformStartupInfo::EndOfStartup` terminator.
+    /// `startup_accounts_forwarded` is false if no startup accounts were forwarded or some of them were dropped.
+    EndOfStartup { startup_accounts_forwarded: bool },
+}
+
 impl TransportOpts {
     // Possible structures:
     // cert_path:key_path@fqdn
@@ -1,7 +1,8 @@
 use opentelemetry::KeyValue;
-use opentelemetry::metrics::{Counter, Gauge, Meter};
+use opentelemetry::metrics::{Counter, Gauge, Histogram, Meter, UpDownCounter};
 use opentelemetry_otlp::{WithExportConfig};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub struct TransportMetrics {
     // General
@@ -13,6 +14,7 @@ pub struct TransportMetrics {
     pub account_total_bytes: Counter<u64>,
     pub account_compressed_bytes: Counter<u64>,
     pub account_packets_dropped: Counter<u64>,
+    pub account_coalesced_updates: Counter<u64>,
 
     // Transaction channel
     pub transaction_buffered_messages: Gauge<u64>,
@@ -42,8 +44,61 @@ pub struct TransportMetrics {
     pub slot_compressed_bytes: Counter<u64>,
     pub slot_packets_dropped: Counter<u64>,
 
+    // Startup account channel
+    pub startup_buffered_messages: Gauge<u64>,
+    pub startup_total_messages: Counter<u64>,
+    pub startup_total_bytes: Counter<u64>,
+    pub startup_compressed_bytes: Counter<u64>,
+    pub startup_packets_dropped: Counter<u64>,
+
     // Loaded plugins
     pub loaded_plugins: Counter<u64>,
+
+    // Gateway replicator
+    pub replicator_busy_time_us: Counter<u64>,
+    pub replicator_idle_time_us: Counter<u64>,
+    pub replicator_busy_ratio: Gauge<f64>,
+    pub account_txn_fallbacks: Counter<u64>,
+    pub transaction_reconstruction_failures: Counter<u64>,
+
+    // Gateway slot ordering
+    pub slot_order_reorder_depth: Histogram<u64>,
+    pub slot_order_budget_expired: Counter<u64>,
+    pub slot_order_late_events: Counter<u64>,
+
+    // Gateway commitment gating
+    pub commitment_buffered_slots: Gauge<u64>,
+    pub commitment_discarded_events: Counter<u64>,
+
+    // Gateway fork tracking
+    pub fork_rollbacks: Counter<u64>,
+    pub fork_rolled_back_slots: Counter<u64>,
+    pub fork_rolled_back_accounts: Counter<u64>,
+
+    // Gateway block assembly
+    pub blocks_assembled: Counter<u64>,
+    pub block_verification_failures: Counter<u64>,
+
+    // Gateway upstreams
+    pub upstream_connected: Gauge<u64>,
+    pub upstream_first_deliveries: Counter<u64>,
+    pub upstream_lag_us: Histogram<u64>,
+
+    // Gateway WebSocket API
+    pub websocket_clients: UpDownCounter<i64>,
+    pub websocket_rate_limited: Counter<u64>,
+
+    // Gateway plugin workers
+    pub plugin_queue_depth: Gauge<u64>,
+    pub plugin_max_delivery_lag_us: Gauge<u64>,
+    pub plugin_events_dropped: Counter<u64>,
+    pub plugin_sandbox_restarts: Counter<u64>,
+
+    // Gateway snapshot bootstrap
+    pub snapshot_live_gap_slots: Counter<u64>,
+
+    // Geyser proxy
+    pub unsupported_interface_versions: Counter<u64>,
 }
 
 impl TransportMetrics {
@@ -75,6 +130,10 @@ impl TransportMetrics {
                 .u64_counter("transport.channel.account.packets_dropped_total")
                 .with_description("Account update packets dropped due to buffer overflow")
                 .build(),
+            account_coalesced_updates: meter
+                .u64_counter("transport.channel.account.coalesced_total")
+                .with_description("Account updates superseded by a newer write within the same coalescing window")
+                .build(),
 
             // Transaction metrics
             transaction_buffered_messages: meter
@@ -163,10 +222,136 @@ impl TransportMetrics {
                 .u64_counter("transport.channel.slot.packets_dropped_total")
                 .with_description("Slot packets dropped due to buffer overflow")
                 .build(),
+
+            // Startup account metrics
+            startup_buffered_messages: meter
+                .u64_gauge("transport.channel.startup.buffered_messages")
+                .with_description("Number of buffered startup account messages")
+                .build(),
+            startup_total_messages: meter
+                .u64_counter("transport.channel.startup.messages_total")
+                .with_description("Total startup account messages sent")
+                .build(),
+            startup_total_bytes: meter
+                .u64_counter("transport.channel.startup.bytes_total")
+                .with_description("Total bytes of startup accounts sent")
+                .build(),
+            startup_compressed_bytes: meter
+                .u64_counter("transport.channel.startup.compressed_bytes_total")
+                .with_description("Total compressed bytes of startup accounts received")
+                .build(),
+            startup_packets_dropped: meter
+                .u64_counter("transport.channel.startup.packets_dropped_total")
+                .with_description("Startup account packets dropped due to buffer overflow")
+                .build(),
             loaded_plugins: meter
                 .u64_counter("gateway.loaded_plugins")
                 .with_description("Plugins loaded by the geyser gateway")
                 .build(),
+            replicator_busy_time_us: meter
+                .u64_counter("gateway.replicator.busy_time_us")
+                .with_description("Time the replicator spent delivering notifications to plugins")
+                .build(),
+            replicator_idle_time_us: meter
+                .u64_counter("gateway.replicator.idle_time_us")
+                .with_description("Time the replicator spent spinning or parked waiting for notifications")
+                .build(),
+            replicator_busy_ratio: meter
+                .f64_gauge("gateway.replicator.busy_ratio")
+                .with_description("Share of the last reporting interval the replicator was busy delivering notifications")
+                .build(),
+            account_txn_fallbacks: meter
+                .u64_counter("gateway.replicator.account_txn_fallbacks_total")
+                .with_description("Held account updates delivered without their transaction because it didn't arrive in time")
+                .build(),
+            transaction_reconstruction_failures: meter
+                .u64_counter("gateway.transaction_reconstruction_failures_total")
+                .with_description("Transactions the gateway failed to rebuild a SanitizedTransaction for")
+                .build(),
+            slot_order_reorder_depth: meter
+                .u64_histogram("gateway.slot_order.reorder_depth")
+                .with_description("Transactions and entries of a slot that arrived after its block metadata, which was held back for them")
+                .build(),
+            slot_order_budget_expired: meter
+                .u64_counter("gateway.slot_order.budget_expired_total")
+                .with_description("Slots whose block metadata and status were released incomplete because the latency budget ran out")
+                .build(),
+            slot_order_late_events: meter
+                .u64_counter("gateway.slot_order.late_events_total")
+                .with_description("Accounts, transactions and entries that arrived after their slot's block metadata was delivered")
+                .build(),
+            commitment_buffered_slots: meter
+                .u64_gauge("gateway.commitment.buffered_slots")
+                .with_description("Slots whose data is buffered until they reach the commitment of the plugins waiting for them")
+                .build(),
+            commitment_discarded_events: meter
+                .u64_counter("gateway.commitment.discarded_events_total")
+                .with_description("Buffered notifications discarded because their slot died or was skipped by fork choice")
+                .build(),
+            fork_rollbacks: meter
+                .u64_counter("gateway.forks.rollbacks_total")
+                .with_description("Rollbacks of slots that died or were on a fork abandoned by a new root")
+                .build(),
+            fork_rolled_back_slots: meter
+                .u64_counter("gateway.forks.rolled_back_slots_total")
+                .with_description("Slots rolled back")
+                .build(),
+            fork_rolled_back_accounts: meter
+                .u64_counter("gateway.forks.rolled_back_accounts_total")
+                .with_description("Accounts updated in rolled back slots, counted once per slot")
+                .build(),
+            blocks_assembled: meter
+                .u64_counter("gateway.blocks.assembled_total")
+                .with_description("Blocks assembled from their transactions, entries and metadata, by completeness")
+                .build(),
+            block_verification_failures: meter
+                .u64_counter("gateway.blocks.verification_failures_total")
+                .with_description("Slots whose data failed verification against the block metadata and entry counts, by issue")
+                .build(),
+            upstream_connected: meter
+                .u64_gauge("gateway.upstream.connected")
+                .with_description("1 while the gateway is connected to the upstream proxy, 0 while it reconnects")
+                .build(),
+            upstream_first_deliveries: meter
+                .u64_counter("gateway.upstream.first_deliveries_total")
+                .with_description("Notifications an upstream delivered before any other upstream, per upstream and stream")
+                .build(),
+            upstream_lag_us: meter
+                .u64_histogram("gateway.upstream.lag_us")
+                .with_description("How long after the first upstream an upstream delivered the same notification, per upstream and stream")
+                .build(),
+            websocket_clients: meter
+                .i64_up_down_counter("gateway.websocket.clients")
+                .with_description("WebSocket clients currently connected")
+                .build(),
+            websocket_rate_limited: meter
+                .u64_counter("gateway.websocket.rate_limited_total")
+                .with_description("Notifications dropped because a WebSocket client exceeded its rate limit")
+                .build(),
+            plugin_queue_depth: meter
+                .u64_gauge("gateway.plugin.queue_depth")
+                .with_description("Notifications queued for a plugin's worker thread")
+                .build(),
+            plugin_max_delivery_lag_us: meter
+                .u64_gauge("gateway.plugin.max_delivery_lag_us")
+                .with_description("Longest time a notification waited in a plugin's queue during the last reporting interval")
+                .build(),
+            plugin_events_dropped: meter
+                .u64_counter("gateway.plugin.events_dropped_total")
+                .with_description("Notifications dropped by a plugin's overflow policy or while its sandbox was down")
+                .build(),
+            plugin_sandbox_restarts: meter
+                .u64_counter("gateway.plugin.sandbox_restarts_total")
+                .with_description("Times a sandboxed plugin's child process died and was restarted")
+                .build(),
+            snapshot_live_gap_slots: meter
+                .u64_counter("gateway.snapshot.live_gap_slots_total")
+                .with_description("Slots missing between a bootstrap snapshot and the earliest live update buffered while loading it")
+                .build(),
+            unsupported_interface_versions: meter
+                .u64_counter("proxy.unsupported_interface_versions")
+                .with_description("Notifications dropped by the geyser proxy because of an unsupported interface version")
+                .build(),
         }
     }
 }
@@ -177,7 +362,8 @@ macro_rules! dispatch_stream_metric {
         transaction: $tx:ident,
         entry: $entry:ident,
         block: $block:ident,
-        slot: $slot:ident
+        slot: $slot:ident,
+        startup: $startup:ident
     }) => {
         match $stream_op {
             crate::StreamOp::Account => $metrics.$acc.$method($value, &[]),
@@ -185,6 +371,7 @@ macro_rules! dispatch_stream_metric {
             crate::StreamOp::Entry => $metrics.$entry.$method($value, &[]),
             crate::StreamOp::Block => $metrics.$block.$method($value, &[]),
             crate::StreamOp::SlotStatus => $metrics.$slot.$method($value, &[]),
+            crate::StreamOp::Startup => $metrics.$startup.$method($value, &[]),
             _ => {}
         }
     };
@@ -204,14 +391,16 @@ impl StreamMetricHelper {
                 transaction: transaction_total_messages,
                 entry: entry_total_messages,
                 block: block_total_messages,
-                slot: slot_total_messages
+                slot: slot_total_messages,
+                startup: startup_total_messages
             });
             dispatch_stream_metric!(m, stream_op, counter add(bytes_count) => {
                 account: account_total_bytes,
                 transaction: transaction_total_bytes,
                 entry: entry_total_bytes,
                 block: block_total_bytes,
-                slot: slot_total_bytes
+                slot: slot_total_bytes,
+                startup: startup_total_bytes
             });
         }
     }
@@ -227,7 +416,8 @@ impl StreamMetricHelper {
                 transaction: transaction_packets_dropped,
                 entry: entry_packets_dropped,
                 block: block_packets_dropped,
-                slot: slot_packets_dropped
+                slot: slot_packets_dropped,
+                startup: startup_packets_dropped
             });
         }
     }
@@ -243,7 +433,8 @@ impl StreamMetricHelper {
                 transaction: transaction_buffered_messages,
                 entry: entry_buffered_messages,
                 block: block_buffered_messages,
-                slot: slot_buffered_messages
+                slot: slot_buffered_messages,
+                startup: startup_buffered_messages
             });
         }
     }
@@ -259,11 +450,21 @@ impl StreamMetricHelper {
                 transaction: transaction_compressed_bytes,
                 entry: entry_compressed_bytes,
                 block: block_compressed_bytes,
-                slot: slot_compressed_bytes
+                slot: slot_compressed_bytes,
+                startup: startup_compressed_bytes
             });
         }
     }
 
+    pub fn record_coalesced_accounts(
+        metrics: Option<&Arc<TransportMetrics>>,
+        count: u64,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.account_coalesced_updates.add(count, &[]);
+        }
+    }
+
     pub fn record_network_bytes_transferred(
         metrics: Option<&Arc<TransportMetrics>>,
         bytes_count: u64,
@@ -288,6 +489,208 @@ impl StreamMetricHelper {
             );
         }
     }
+
+    pub fn record_replicator_utilization(
+        metrics: Option<&Arc<TransportMetrics>>,
+        busy: Duration,
+        idle: Duration,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.replicator_busy_time_us.add(busy.as_micros() as u64, &[]);
+            metrics.replicator_idle_time_us.add(idle.as_micros() as u64, &[]);
+            let total = busy + idle;
+            if !total.is_zero() {
+                metrics.replicator_busy_ratio.record(busy.as_secs_f64() / total.as_secs_f64(), &[]);
+            }
+        }
+    }
+
+    pub fn record_account_txn_fallbacks(
+        metrics: Option<&Arc<TransportMetrics>>,
+        count: u64,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.account_txn_fallbacks.add(count, &[]);
+        }
+    }
+
+    pub fn record_transaction_reconstruction_failure(
+        metrics: Option<&Arc<TransportMetrics>>,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.transaction_reconstruction_failures.add(1, &[]);
+        }
+    }
+
+    pub fn record_slot_released(
+        metrics: Option<&Arc<TransportMetrics>>,
+        reorder_depth: u64,
+        budget_expired: bool,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.slot_order_reorder_depth.record(reorder_depth, &[]);
+            if budget_expired {
+                metrics.slot_order_budget_expired.add(1, &[]);
+            }
+        }
+    }
+
+    pub fn record_slot_order_late_events(
+        metrics: Option<&Arc<TransportMetrics>>,
+        count: u64,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.slot_order_late_events.add(count, &[]);
+        }
+    }
+
+    pub fn record_commitment_buffer(
+        metrics: Option<&Arc<TransportMetrics>>,
+        buffered_slots: u64,
+        discarded_events: u64,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.commitment_buffered_slots.record(buffered_slots, &[]);
+            if discarded_events > 0 {
+                metrics.commitment_discarded_events.add(discarded_events, &[]);
+            }
+        }
+    }
+
+    pub fn record_rollback(
+        metrics: Option<&Arc<TransportMetrics>>,
+        slots: u64,
+        accounts: u64,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.fork_rollbacks.add(1, &[]);
+            metrics.fork_rolled_back_slots.add(slots, &[]);
+            metrics.fork_rolled_back_accounts.add(accounts, &[]);
+        }
+    }
+
+    pub fn record_block_assembled(
+        metrics: Option<&Arc<TransportMetrics>>,
+        complete: bool,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.blocks_assembled.add(1, &[KeyValue::new("complete", complete)]);
+        }
+    }
+
+    pub fn record_block_verification_failed(
+        metrics: Option<&Arc<TransportMetrics>>,
+        issues: &[&'static str],
+    ) {
+        if let Some(metrics) = metrics {
+            for issue in issues {
+                metrics.block_verification_failures.add(1, &[KeyValue::new("issue", *issue)]);
+            }
+        }
+    }
+
+    pub fn record_upstream_connected(
+        metrics: Option<&Arc<TransportMetrics>>,
+        upstream: &str,
+        connected: bool,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.upstream_connected.record(
+                connected as u64,
+                &[KeyValue::new("upstream", upstream.to_string())],
+            );
+        }
+    }
+
+    /// `lag` is `None` when the upstream delivered the notification first
+    pub fn record_upstream_delivery(
+        metrics: Option<&Arc<TransportMetrics>>,
+        upstream: &str,
+        stream: &'static str,
+        lag: Option<Duration>,
+    ) {
+        if let Some(metrics) = metrics {
+            let attributes = [
+                KeyValue::new("upstream", upstream.to_string()),
+                KeyValue::new("stream", stream),
+            ];
+            match lag {
+                Some(lag) => metrics.upstream_lag_us.record(lag.as_micros() as u64, &attributes),
+                None => metrics.upstream_first_deliveries.add(1, &attributes),
+            }
+        }
+    }
+
+    pub fn record_websocket_client(metrics: Option<&Arc<TransportMetrics>>, connected: bool) {
+        if let Some(metrics) = metrics {
+            metrics.websocket_clients.add(if connected { 1 } else { -1 }, &[]);
+        }
+    }
+
+    pub fn record_websocket_rate_limited(metrics: Option<&Arc<TransportMetrics>>, dropped: u64) {
+        if let Some(metrics) = metrics {
+            metrics.websocket_rate_limited.add(dropped, &[]);
+        }
+    }
+
+    pub fn record_plugin_lag(
+        metrics: Option<&Arc<TransportMetrics>>,
+        plugin_name: &str,
+        queue_depth: u64,
+        max_lag: Duration,
+    ) {
+        if let Some(metrics) = metrics {
+            let attributes = [KeyValue::new("plugin_name", plugin_name.to_string())];
+            metrics.plugin_queue_depth.record(queue_depth, &attributes);
+            metrics.plugin_max_delivery_lag_us.record(max_lag.as_micros() as u64, &attributes);
+        }
+    }
+
+    pub fn record_plugin_events_dropped(
+        metrics: Option<&Arc<TransportMetrics>>,
+        plugin_name: &str,
+        count: u64,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.plugin_events_dropped.add(
+                count,
+                &[KeyValue::new("plugin_name", plugin_name.to_string())],
+            );
+        }
+    }
+
+    pub fn record_plugin_restart(
+        metrics: Option<&Arc<TransportMetrics>>,
+        plugin_name: &str,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.plugin_sandbox_restarts.add(
+                1,
+                &[KeyValue::new("plugin_name", plugin_name.to_string())],
+            );
+        }
+    }
+
+    pub fn record_snapshot_live_gap(
+        metrics: Option<&Arc<TransportMetrics>>,
+        slots: u64,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.snapshot_live_gap_slots.add(slots, &[]);
+        }
+    }
+
+    pub fn record_unsupported_interface_version(
+        metrics: Option<&Arc<TransportMetrics>>,
+        notification: &'static str,
+    ) {
+        if let Some(metrics) = metrics {
+            metrics.unsupported_interface_versions.add(
+                1,
+                &[KeyValue::new("notification", notification)],
+            );
+        }
+    }
 }
 
 /// Initialize OpenTelemetry metrics with stdout exporter
@@ -1,11 +1,12 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
 use quinn::crypto::rustls::QuicServerConfig;
+use quinn::VarInt;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::log;
-use crate::{TransportOpts, TransportResult, error::TransportError, ReplicaChannels, StreamOp, metrics::TransportMetrics};
+use crate::{TransportOpts, TransportResult, error::TransportError, ReplicaChannels, StreamOp, Subscription, metrics::TransportMetrics, CLOSE_CODE_SHUTDOWN};
 
 pub struct TransportServer {
     endpoint: quinn::Endpoint,
@@ -14,6 +15,26 @@ pub struct TransportServer {
     metrics: Option<Arc<TransportMetrics>>,
 }
 
+/// How long a new connection gets to send its subscription
+const SUBSCRIPTION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Upper bound of a serialized subscription, checked before allocating since clients aren't authenticated
+const MAX_SUBSCRIPTION_LEN: u32 = 64;
+
+/// Closes a server's connections once it shuts down, so clients can tell a shutdown from a lost connection
+pub struct ServerShutdown {
+    endpoint: quinn::Endpoint,
+}
+
+impl ServerShutdown {
+    /// Closes every connection with [`CLOSE_CODE_SHUTDOWN`] and stops accepting new ones, waits until the clients
+    /// were told
+    pub async fn close(self, reason: &str) {
+        self.endpoint.close(VarInt::from_u32(CLOSE_CODE_SHUTDOWN), reason.as_bytes());
+        self.endpoint.wait_idle().await;
+    }
+}
+
 #[derive(Clone, Copy, Deserialize, Debug)]
 pub struct TransportServerConfig {
     pub use_lz4_compression: bool,
@@ -55,76 +76,169 @@ impl TransportServer {
         })
     }
 
+    pub fn shutdown_handle(&self) -> ServerShutdown {
+        ServerShutdown { endpoint: self.endpoint.clone() }
+    }
+
     #[tracing::instrument(skip_all, fields(peer_addr = %incoming.remote_address()))]
     async fn accept_connection(incoming: quinn::Incoming, replica_channels: ReplicaChannels, config: TransportServerConfig, metrics: Option<Arc<TransportMetrics>>) -> TransportResult<quinn::Connection> {
         let connection = incoming.await?;
 
-        log::info!("accepted connection, opening replica channels");
+        let subscription = tokio::time::timeout(SUBSCRIPTION_TIMEOUT, Self::read_subscription(&connection))
+            .await
+            .map_err(|_| TransportError::SubscriptionTimeout)??;
+
+        log::info!("accepted connection, opening replica channels for {:?}", subscription);
 
         // account channel
-        let send = connection.open_uni().await?;
-        tokio::spawn(Self::handle_channel(
-            send,
-            replica_channels.account.clone(),
-            StreamOp::Account,
-            config,
-            connection.remote_address(),
-            metrics.clone(),
-        ));
+        if subscription.accounts {
+            let send = connection.open_uni().await?;
+            tokio::spawn(Self::handle_channel(
+                send,
+                replica_channels.account.subscribe(),
+                Vec::new(),
+                StreamOp::Account,
+                config,
+                connection.remote_address(),
+                metrics.clone(),
+            ));
+        }
 
         // transaction channel
-        let send = connection.open_uni().await?;
-        let _ = send.set_priority(5);
-        tokio::spawn(Self::handle_channel(
-            send,
-            replica_channels.transaction.clone(),
-            StreamOp::Transaction,
-            config,
-            connection.remote_address(),
-            metrics.clone(),
-        ));
+        if subscription.transactions {
+            let send = connection.open_uni().await?;
+            let _ = send.set_priority(5);
+            tokio::spawn(Self::handle_channel(
+                send,
+                replica_channels.transaction.subscribe(),
+                Vec::new(),
+                StreamOp::Transaction,
+                config,
+                connection.remote_address(),
+                metrics.clone(),
+            ));
+        }
 
         // entry channel
+        if subscription.entries {
+            let send = connection.open_uni().await?;
+            tokio::spawn(Self::handle_channel(
+                send,
+                replica_channels.entry.subscribe(),
+                Vec::new(),
+                StreamOp::Entry,
+                config,
+                connection.remote_address(),
+                metrics.clone(),
+            ));
+        }
+
+        // block channel
         let send = connection.open_uni().await?;
         tokio::spawn(Self::handle_channel(
             send,
-            replica_channels.entry.clone(),
-            StreamOp::Entry,
+            replica_channels.block.subscribe(),
+            Vec::new(),
+            StreamOp::Block,
             config,
             connection.remote_address(),
             metrics.clone(),
         ));
 
-        // block channel
+        // slot status channel
         let send = connection.open_uni().await?;
+        let _ = send.set_priority(4);
         tokio::spawn(Self::handle_channel(
             send,
-            replica_channels.block.clone(),
-            StreamOp::Block,
+            replica_channels.slot.subscribe(),
+            Vec::new(),
+            StreamOp::SlotStatus,
             config,
             connection.remote_address(),
             metrics.clone(),
         ));
 
-        // slot status channel
+        // lifecycle channel, past events are replayed first
         let send = connection.open_uni().await?;
         let _ = send.set_priority(4);
+        let (lifecycle_replay, lifecycle_rx) = replica_channels.lifecycle.subscribe_with_history();
         tokio::spawn(Self::handle_channel(
             send,
-            replica_channels.slot.clone(),
-            StreamOp::SlotStatus,
+            lifecycle_rx,
+            lifecycle_replay,
+            StreamOp::Lifecycle,
             config,
             connection.remote_address(),
             metrics.clone(),
         ));
 
+        // rollback channel
+        if subscription.rollbacks {
+            let send = connection.open_uni().await?;
+            let _ = send.set_priority(4);
+            tokio::spawn(Self::handle_channel(
+                send,
+                replica_channels.rollback.subscribe(),
+                Vec::new(),
+                StreamOp::Rollback,
+                config,
+                connection.remote_address(),
+                metrics.clone(),
+            ));
+        }
+
+        // startup account channel, bulk snapshot data must never get in the way of live updates
+        if subscription.startup {
+            let send = connection.open_uni().await?;
+            let _ = send.set_priority(-1);
+            tokio::spawn(Self::handle_channel(
+                send,
+                replica_channels.startup.subscribe(),
+                Vec::new(),
+                StreamOp::Startup,
+                config,
+                connection.remote_address(),
+                metrics.clone(),
+            ));
+        }
+
+        let peer_addr = connection.remote_address();
+        let closing = connection.clone();
+        tokio::spawn(async move {
+            match closing.closed().await {
+                quinn::ConnectionError::ApplicationClosed(close) if close.error_code == VarInt::from_u32(CLOSE_CODE_SHUTDOWN) => {
+                    log::info!("client {} shut down: {}", peer_addr, String::from_utf8_lossy(&close.reason));
+                }
+                reason => log::warn!("connection to {} lost: {}", peer_addr, reason),
+            }
+        });
+
         Ok(connection)
     }
 
+    async fn read_subscription(connection: &quinn::Connection) -> TransportResult<Subscription> {
+        let mut recv = connection.accept_uni().await?;
+
+        let op_u8 = recv.read_u8().await?;
+        if !matches!(StreamOp::try_from(op_u8), Ok(StreamOp::Subscribe)) {
+            return Err(TransportError::InvalidStreamOp(op_u8));
+        }
+
+        let len = recv.read_u32_le().await?;
+        if len > MAX_SUBSCRIPTION_LEN {
+            return Err(TransportError::SubscriptionTooLarge(len));
+        }
+        let mut buf = vec![0u8; len as usize];
+        AsyncReadExt::read_exact(&mut recv, &mut buf).await?;
+
+        Ok(bincode::deserialize(&buf)?)
+    }
+
     #[tracing::instrument(skip_all, fields(remote_peer_addr = %_remote_peer_addr))]
     async fn handle_channel<T>(
         mut send: quinn::SendStream,
-        channel: tokio::sync::broadcast::Sender<T>,
+        mut notif_rx: tokio::sync::broadcast::Receiver<T>,
+        replay: Vec<T>,
         op: StreamOp,
         config: TransportServerConfig,
         _remote_peer_addr: SocketAddr,
@@ -148,9 +262,13 @@ impl TransportServer {
             tx = Box::new(send);
         };
 
-        let mut notif_rx = channel.subscribe();
+        let mut replay = replay.into_iter();
         loop {
-            match notif_rx.recv().await {
+            let next = match replay.next() {
+                Some(data) => Ok(data),
+                None => notif_rx.recv().await,
+            };
+            match next {
                 Ok(data) => {
                     let serialized = bincode::serialize(&data)?;
                     let data_len = serialized.len() as u32;